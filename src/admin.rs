@@ -0,0 +1,560 @@
+//! A registry of every connection a server is handling, and an optional admin port for
+//! inspecting and controlling it with a line-based protocol.
+//!
+//! A session starts with `auth <token>`, then takes one command per line:
+//!
+//! - `conns` lists each connection's ID, peer, listener, start time and byte counts
+//! - `kick <id>` closes a connection
+//! - `shutdown` shuts down every server, giving connections a short time to finish
+//! - `drain` stops accepting connections and shuts down once the open ones have finished
+//! - `loglevel <level>` changes the log level, for example to `debug`
+//!
+//! Every command's response ends with a line starting `ok` or `error:`.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::as_display;
+
+use crate::{
+    metrics::ConnectionCounters,
+    server::{ForceCloser, PeerAddress, ReadBefore, ShutdownSignal},
+};
+
+/// How long a client gets to authenticate before we hang up
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longer than any command, including its newline; a longer line ends the session
+const MAX_LINE: u64 = 1024;
+/// Sessions open at once, authenticated or not; clients beyond this are turned away
+const MAX_SESSIONS: usize = 4;
+
+/// Every connection being handled by the servers sharing a context, by ID.
+pub(crate) struct ConnectionRegistry {
+    next_id: AtomicU64,
+    connections: Mutex<BTreeMap<u64, RegisteredConnection>>,
+    /// The shutdown signal of every server registering connections here, indexed by server ID
+    servers: Mutex<Vec<ShutdownSignal>>,
+}
+
+struct RegisteredConnection {
+    server: usize,
+    peer: PeerAddress,
+    listener: PeerAddress,
+    started: SystemTime,
+    counters: Arc<ConnectionCounters>,
+    /// Taken when the connection is closed, so it's only closed once
+    force_closer: Option<ForceCloser>,
+}
+
+/// A snapshot of one registered connection.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ConnectionInfo {
+    pub(crate) id: u64,
+    pub(crate) peer: PeerAddress,
+    pub(crate) listener: PeerAddress,
+    pub(crate) started: SystemTime,
+    pub(crate) bytes_read: u64,
+    pub(crate) bytes_written: u64,
+}
+
+impl Default for ConnectionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionRegistry {
+    pub(crate) fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            connections: Mutex::new(BTreeMap::new()),
+            servers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Track a server's shutdown signal, so the admin port can shut it down. Returns the server ID
+    /// to register its connections under.
+    pub(crate) fn add_server(&self, shutdown_signal: &ShutdownSignal) -> usize {
+        let mut servers = self.servers();
+        servers.push(shutdown_signal.clone());
+        servers.len() - 1
+    }
+
+    /// Returns the new connection's ID, which is unique for the life of the registry.
+    pub(crate) fn register(
+        &self,
+        server: usize,
+        peer: PeerAddress,
+        listener: PeerAddress,
+        counters: Arc<ConnectionCounters>,
+        force_closer: Option<ForceCloser>,
+    ) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.connections().insert(
+            id,
+            RegisteredConnection {
+                server,
+                peer,
+                listener,
+                started: SystemTime::now(),
+                counters,
+                force_closer,
+            },
+        );
+        id
+    }
+
+    /// Record who a connection is really from, once we know; see [`crate::proxy_protocol`].
+    pub(crate) fn set_peer(&self, id: u64, peer: PeerAddress) {
+        if let Some(connection) = self.connections().get_mut(&id) {
+            connection.peer = peer;
+        }
+    }
+
+    pub(crate) fn remove(&self, id: u64) {
+        self.connections().remove(&id);
+    }
+
+    pub(crate) fn list(&self) -> Vec<ConnectionInfo> {
+        self.connections()
+            .iter()
+            .map(|(&id, connection)| ConnectionInfo {
+                id,
+                peer: connection.peer.clone(),
+                listener: connection.listener.clone(),
+                started: connection.started,
+                bytes_read: connection.counters.bytes_read(),
+                bytes_written: connection.counters.bytes_written(),
+            })
+            .collect()
+    }
+
+    /// Close a connection, so its handler returns. It stays registered until then.
+    pub(crate) fn kick(&self, id: u64) -> Result<(), String> {
+        let force_close = match self.connections().get_mut(&id) {
+            None => return Err(format!("no connection {}", id)),
+            Some(connection) => connection
+                .force_closer
+                .take()
+                .ok_or_else(|| format!("connection {} can't be closed", id))?,
+        };
+        force_close();
+        Ok(())
+    }
+
+    /// Close every connection `server` is handling, returning how many there were.
+    pub(crate) fn close_all(&self, server: usize) -> usize {
+        let force_closers = self
+            .connections()
+            .values_mut()
+            .filter(|connection| connection.server == server)
+            .filter_map(|connection| connection.force_closer.take())
+            .collect::<Vec<_>>();
+        let count = force_closers.len();
+        for force_close in force_closers {
+            force_close();
+        }
+        count
+    }
+
+    /// Shut down every server, cutting short any drain. Returns false if there was nothing to do.
+    pub(crate) fn shutdown(&self) -> bool {
+        let mut any = false;
+        for shutdown_signal in self.servers().iter_mut() {
+            any |= shutdown_signal.start_shutdown() || shutdown_signal.stop_draining();
+        }
+        any
+    }
+
+    /// Drain every server. Returns false if they were all already shutting down.
+    pub(crate) fn drain(&self) -> bool {
+        let mut any = false;
+        for shutdown_signal in self.servers().iter_mut() {
+            any |= shutdown_signal.start_drain();
+        }
+        any
+    }
+
+    fn connections(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, RegisteredConnection>> {
+        self.connections
+            .lock()
+            .expect("Connection registry should not be poisoned")
+    }
+
+    fn servers(&self) -> std::sync::MutexGuard<'_, Vec<ShutdownSignal>> {
+        self.servers
+            .lock()
+            .expect("Server list should not be poisoned")
+    }
+}
+
+/// Serve the admin protocol on `listener` from a background thread, with a thread per session.
+/// Clients must give `token` before anything else. Returns the address we're listening on.
+pub(crate) fn serve(
+    listener: TcpListener,
+    token: String,
+    registry: Arc<ConnectionRegistry>,
+) -> io::Result<SocketAddr> {
+    let local_address = listener.local_addr()?;
+    log::info!(address = as_display!(local_address); "Serving admin port");
+    let token = Arc::new(token);
+    thread::Builder::new()
+        .name("admin-listener".into())
+        .spawn(move || {
+            let sessions = Arc::new(AtomicUsize::new(0));
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::debug!(error = as_display!(e); "Error accepting admin connection");
+                        continue;
+                    }
+                };
+                let Some(slot) = SessionSlot::take(&sessions) else {
+                    log::warn!("Too many admin sessions, turning a client away");
+                    let _ = (&stream).write_all(b"error: too many sessions\n");
+                    continue;
+                };
+                let token = token.clone();
+                let registry = registry.clone();
+                // If the thread doesn't start, dropping the closure gives the slot back
+                let spawned =
+                    thread::Builder::new()
+                        .name("admin-session".into())
+                        .spawn(move || {
+                            let _slot = slot;
+                            if let Err(e) = session(&stream, &token, &registry) {
+                                log::debug!(error = as_display!(e); "Error in admin session");
+                            }
+                        });
+                if let Err(e) = spawned {
+                    log::error!(error = as_display!(e); "Unable to spawn admin session thread");
+                }
+            }
+        })?;
+    Ok(local_address)
+}
+
+/// One of the [`MAX_SESSIONS`] places for a session, given back when dropped, however the
+/// session ends.
+struct SessionSlot(Arc<AtomicUsize>);
+
+impl SessionSlot {
+    /// Take a slot from `sessions`, the count of those taken, unless they're all taken already.
+    fn take(sessions: &Arc<AtomicUsize>) -> Option<Self> {
+        if sessions.fetch_add(1, Ordering::SeqCst) >= MAX_SESSIONS {
+            sessions.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Self(sessions.clone()))
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn session(stream: &TcpStream, token: &str, registry: &ConnectionRegistry) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    // However slowly they send it, the whole auth line has to arrive in time
+    let mut reader = BufReader::new(ReadBefore::new(stream, AUTH_TIMEOUT));
+    let mut writer = stream;
+    let mut line = String::new();
+
+    if !read_line(&mut reader, &mut line)? {
+        return writer.write_all(b"error: line too long\n");
+    }
+    if !authenticates(line.trim_end(), token) {
+        log::warn!(peer = as_display!(peer); "Admin client failed to authenticate");
+        return writer.write_all(b"error: expected auth <token>\n");
+    }
+    reader.get_mut().clear_deadline()?;
+    log::info!(peer = as_display!(peer); "Admin client authenticated");
+    writer.write_all(b"ok\n")?;
+
+    loop {
+        line.clear();
+        if !read_line(&mut reader, &mut line)? {
+            return writer.write_all(b"error: line too long\n");
+        }
+        if line.is_empty() {
+            return Ok(());
+        }
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+        log::info!(peer = as_display!(peer), command = command; "Admin command");
+        writer.write_all(run_command(command, registry).as_bytes())?;
+    }
+}
+
+/// Read a line of at most MAX_LINE bytes into `line`, returning false if it was longer. Nothing
+/// past the limit is read, so a client can't make us buffer an endless line.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<bool> {
+    let read = reader.by_ref().take(MAX_LINE).read_line(line)?;
+    Ok(read < MAX_LINE as usize || line.ends_with('\n'))
+}
+
+/// Whether `line` is `auth <token>`, compared in constant time so the token can't be guessed a
+/// byte at a time.
+fn authenticates(line: &str, token: &str) -> bool {
+    let Some(given) = line.strip_prefix("auth ") else {
+        return false;
+    };
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Run one command, returning the whole response.
+fn run_command(command: &str, registry: &ConnectionRegistry) -> String {
+    let (name, argument) = match command.split_once(' ') {
+        Some((name, argument)) => (name, Some(argument.trim())),
+        None => (command, None),
+    };
+    match (name, argument) {
+        ("conns", None) => {
+            let connections = registry.list();
+            let mut response = String::new();
+            for connection in &connections {
+                let started = connection
+                    .started
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let _ = writeln!(
+                    response,
+                    "{} peer={} listener={} started={}.{:03} bytes_read={} bytes_written={}",
+                    connection.id,
+                    connection.peer,
+                    connection.listener,
+                    started.as_secs(),
+                    started.subsec_millis(),
+                    connection.bytes_read,
+                    connection.bytes_written
+                );
+            }
+            let _ = writeln!(response, "ok {} connections", connections.len());
+            response
+        }
+        ("kick", Some(id)) => match id.parse() {
+            Ok(id) => match registry.kick(id) {
+                Ok(()) => format!("ok kicked {}\n", id),
+                Err(e) => format!("error: {}\n", e),
+            },
+            Err(_) => format!("error: invalid connection ID '{}'\n", id),
+        },
+        ("shutdown", None) => match registry.shutdown() {
+            true => String::from("ok shutting down\n"),
+            false => String::from("error: already shutting down\n"),
+        },
+        ("drain", None) => match registry.drain() {
+            true => String::from("ok draining\n"),
+            false => String::from("error: already shutting down\n"),
+        },
+        ("loglevel", Some(level)) => match level.parse::<log::LevelFilter>() {
+            Ok(level) => {
+                log::set_max_level(level);
+                format!("ok log level is {}\n", level)
+            }
+            Err(_) => format!("error: unknown log level '{}'\n", level),
+        },
+        _ => format!(
+            "error: unknown command '{}', expected conns, kick <id>, shutdown, drain or loglevel <level>\n",
+            command
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use std::sync::atomic::AtomicBool;
+
+    fn counters() -> Arc<ConnectionCounters> {
+        Arc::new(ConnectionCounters::new(Arc::new(Metrics::new("test"))))
+    }
+
+    fn peer(address: &str) -> PeerAddress {
+        PeerAddress::Inet(address.parse().unwrap())
+    }
+
+    #[test]
+    fn lists_and_kicks_registered_connections() {
+        let registry = ConnectionRegistry::new();
+        let closed = Arc::new(AtomicBool::new(false));
+        let closed_clone = closed.clone();
+        let first_counters = counters();
+        let first = registry.register(
+            0,
+            peer("127.0.0.1:5000"),
+            peer("127.0.0.1:9000"),
+            first_counters.clone(),
+            Some(Box::new(move || closed_clone.store(true, Ordering::SeqCst))),
+        );
+        let second = registry.register(
+            0,
+            peer("127.0.0.1:5001"),
+            peer("127.0.0.1:9000"),
+            counters(),
+            None,
+        );
+        first_counters.read(10);
+        first_counters.written(4);
+        registry.set_peer(first, peer("192.0.2.1:1234"));
+
+        let response = run_command("conns", &registry);
+        let lines = response.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3, "{}", response);
+        assert!(lines[0].starts_with(&format!(
+            "{} peer=192.0.2.1:1234 listener=127.0.0.1:9000 started=",
+            first
+        )));
+        assert!(lines[0].ends_with(" bytes_read=10 bytes_written=4"));
+        assert!(lines[1].starts_with(&format!("{} peer=127.0.0.1:5001 ", second)));
+        assert_eq!(lines[2], "ok 2 connections");
+
+        assert_eq!(
+            run_command(&format!("kick {}", first), &registry),
+            format!("ok kicked {}\n", first)
+        );
+        assert!(closed.load(Ordering::SeqCst));
+        assert!(run_command(&format!("kick {}", second), &registry).starts_with("error: "));
+        assert!(run_command("kick 99", &registry).starts_with("error: no connection"));
+        assert!(run_command("kick me", &registry).starts_with("error: invalid"));
+
+        registry.remove(first);
+        registry.remove(second);
+        assert_eq!(run_command("conns", &registry), "ok 0 connections\n");
+    }
+
+    #[test]
+    fn only_closes_the_given_servers_connections() {
+        let registry = ConnectionRegistry::new();
+        let closed = Arc::new(AtomicU64::new(0));
+        for server in [0, 0, 1] {
+            let closed = closed.clone();
+            registry.register(
+                server,
+                peer("127.0.0.1:5000"),
+                peer("127.0.0.1:9000"),
+                counters(),
+                Some(Box::new(move || {
+                    closed.fetch_add(1, Ordering::SeqCst);
+                })),
+            );
+        }
+        assert_eq!(registry.close_all(0), 2);
+        assert_eq!(registry.close_all(0), 0);
+        assert_eq!(closed.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn rejects_bad_tokens() {
+        assert!(authenticates("auth s3cret", "s3cret"));
+        assert!(!authenticates("auth s3cre", "s3cret"));
+        assert!(!authenticates("auth s3cret!", "s3cret"));
+        assert!(!authenticates("s3cret", "s3cret"));
+        assert!(!authenticates("conns", "s3cret"));
+    }
+
+    #[test]
+    fn serves_commands_after_authenticating() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = serve(listener, String::from("s3cret"), registry.clone()).unwrap();
+        let read_line = |reader: &mut BufReader<TcpStream>| {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line
+        };
+
+        let unauthenticated = TcpStream::connect(address).unwrap();
+        (&unauthenticated).write_all(b"conns\n").unwrap();
+        let mut reader = BufReader::new(unauthenticated);
+        assert!(read_line(&mut reader).starts_with("error: "));
+        assert_eq!(read_line(&mut reader), "", "Should hang up");
+
+        let client = TcpStream::connect(address).unwrap();
+        (&client)
+            .write_all(b"auth s3cret\nconns\nloglevel nonsense\nfrobnicate\n")
+            .unwrap();
+        let mut reader = BufReader::new(client);
+        assert_eq!(read_line(&mut reader), "ok\n");
+        assert_eq!(read_line(&mut reader), "ok 0 connections\n");
+        assert!(read_line(&mut reader).starts_with("error: unknown log level"));
+        assert!(read_line(&mut reader).starts_with("error: unknown command"));
+
+        let long_winded = TcpStream::connect(address).unwrap();
+        (&long_winded)
+            .write_all(&[b'a'; MAX_LINE as usize + 1])
+            .unwrap();
+        let mut reader = BufReader::new(long_winded);
+        assert_eq!(read_line(&mut reader), "error: line too long\n");
+        assert_eq!(read_line(&mut reader), "", "Should hang up");
+    }
+
+    #[test]
+    fn turns_away_clients_beyond_the_session_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = serve(
+            listener,
+            String::from("s3cret"),
+            Arc::new(ConnectionRegistry::new()),
+        )
+        .unwrap();
+        let connect = || {
+            let client = TcpStream::connect(address).unwrap();
+            (&client).write_all(b"auth s3cret\n").unwrap();
+            let mut reader = BufReader::new(client);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            (reader, line)
+        };
+
+        let sessions: Vec<_> = (0..MAX_SESSIONS).map(|_| connect()).collect();
+        assert!(sessions.iter().all(|(_, line)| line == "ok\n"));
+        assert_eq!(connect().1, "error: too many sessions\n");
+    }
+
+    #[test]
+    fn hangs_up_on_clients_slow_to_authenticate() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = serve(
+            listener,
+            String::from("s3cret"),
+            Arc::new(ConnectionRegistry::new()),
+        )
+        .unwrap();
+
+        // Each byte well within the timeout, but the line as a whole far beyond it
+        let client = TcpStream::connect(address).unwrap();
+        client.set_nonblocking(true).unwrap();
+        let start = std::time::Instant::now();
+        while (&client).write_all(b"a").is_ok() && start.elapsed() < AUTH_TIMEOUT * 2 {
+            if client.peek(&mut [0u8; 1]).is_ok_and(|peeked| peeked == 0) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(500));
+        }
+        let hung_up = start.elapsed();
+        assert!(hung_up >= AUTH_TIMEOUT);
+        assert!(hung_up < AUTH_TIMEOUT + Duration::from_secs(2));
+    }
+}
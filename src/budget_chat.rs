@@ -1,21 +1,25 @@
+use crate::check::{Scenario, Step, Transport};
 use crate::scaffolding::Context;
-use crate::server::{Server as _, TcpServer};
+use crate::server::{
+    ConnectionHandler, PeerAddress, Server as _, ShutdownSignal, Stream, StreamServer,
+};
 use log::{as_debug, as_display};
-use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::error::Error;
 use std::io::{BufRead, BufReader, Write};
-use std::net::{SocketAddr, TcpStream};
+use std::net::Shutdown;
 
 use std::ops::Deref;
 use std::sync::{
     mpsc::{channel, Sender},
-    Arc, RwLock,
+    Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
 };
 use std::thread;
+use std::time::Duration;
 
-static CHATROOM: Lazy<RwLock<HashMap<Arc<String>, Sender<Message>>>> =
-    Lazy::new(|| RwLock::new(HashMap::new()));
+/// How long a client gets to give their name. Once they've joined they can idle for as long as
+/// they like.
+const NAME_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Clone, Debug)]
 enum MessageContent {
@@ -23,6 +27,7 @@ enum MessageContent {
     Joined,
     Left,
     Message(Arc<String>),
+    ServerShuttingDown,
 }
 
 #[derive(Clone, Debug)]
@@ -31,148 +36,366 @@ struct Message {
     content: MessageContent,
 }
 
-pub(crate) fn run(ctx: &Context) -> Result<(), Box<dyn Error>> {
-    let shutdown_signal = TcpServer::new().serve(ctx, handle)?;
-    shutdown_signal.set_as_ctrl_c_handler()?;
-    shutdown_signal.sleep_until_shutdown();
-    Ok(())
+/// A chat room, shared by every connection to one server
+#[derive(Default)]
+pub(crate) struct Chatroom {
+    members: RwLock<HashMap<Arc<String>, Sender<Message>>>,
 }
 
-fn send_to_room(message: Message) -> Result<(), Box<dyn Error>> {
-    let user_sinks = CHATROOM.read().expect("Chatroom should not be poisoned");
-    for (target, sink) in user_sinks.iter() {
-        // Never send a message to the sender
-        if target == &message.from {
-            continue;
-        }
-        match sink.send(message.clone()) {
-            Ok(()) => {}
-            Err(e) => {
-                log::warn!(
-                    from = as_display!(message.from),
-                    to = as_display!(target),
-                    message = as_debug!(message),
-                    error = as_debug!(e);
-                    "Failed to forward message to client"
-                );
+pub(crate) fn serve(ctx: &Context) -> Result<ShutdownSignal, Box<dyn Error>> {
+    StreamServer::new().serve(ctx, Chatroom::default())
+}
+
+impl Chatroom {
+    // Every change to the members is a single insert or remove, so they're never left half-done
+    // by a handler panicking, and a poisoned lock is safe to carry on with
+    fn members(&self) -> RwLockReadGuard<'_, HashMap<Arc<String>, Sender<Message>>> {
+        self.members.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn members_mut(&self) -> RwLockWriteGuard<'_, HashMap<Arc<String>, Sender<Message>>> {
+        self.members.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn send_to_room(&self, message: Message) -> Result<(), Box<dyn Error>> {
+        let user_sinks = self.members();
+        for (target, sink) in user_sinks.iter() {
+            // Never send a message to the sender
+            if target == &message.from {
+                continue;
+            }
+            match sink.send(message.clone()) {
+                Ok(()) => {}
+                Err(e) => {
+                    log::warn!(
+                        from = as_display!(message.from),
+                        to = as_display!(target),
+                        message = as_debug!(message),
+                        error = as_debug!(e);
+                        "Failed to forward message to client"
+                    );
+                }
             }
         }
+        Ok(())
     }
-    Ok(())
 }
 
-fn handle(stream: &mut TcpStream, _remote_address: &SocketAddr) -> Result<(), Box<dyn Error>> {
-    stream.set_read_timeout(Some(std::time::Duration::from_millis(10000)))?;
-    let reader = BufReader::new(stream.try_clone()?);
-    let mut lines = reader.lines();
-
-    stream.write_all("Name pls:\n".as_bytes())?;
-    stream.flush()?;
-    let next_line = if let Some(r) = lines.next() {
-        r
-    } else {
-        // This means no more lines from the client, so time to pack up and head home
-        log::warn!("No name from client {} before timeout", _remote_address);
-        return Ok(());
-    };
-    let name = Arc::new(if next_line.is_ok() {
-        let inner_name = next_line?;
-        if inner_name
-            .matches(|c| char::is_ascii_alphanumeric(&c))
-            .count()
-            != inner_name.len()
-        {
-            stream.write_all("Name must be alphanumeric.".as_bytes())?;
-            stream.flush()?;
+impl ConnectionHandler<Stream> for Chatroom {
+    fn handle(
+        &self,
+        stream: &mut Stream,
+        _remote_address: &PeerAddress,
+        shutdown_signal: &ShutdownSignal,
+    ) -> Result<(), Box<dyn Error>> {
+        stream.set_read_timeout(Some(NAME_TIMEOUT))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        let mut lines = reader.lines();
+
+        // Until they've joined there's nobody to tell, so just hang up on shutdown
+        let closer = stream.try_clone()?;
+        let shutdown_guard = shutdown_signal.on_shutdown(move || {
+            let _ = closer.shutdown(Shutdown::Both);
+        });
+
+        stream.write_all("Name pls:\n".as_bytes())?;
+        stream.flush()?;
+        let next_line = if let Some(r) = lines.next() {
+            r
+        } else {
+            // This means no more lines from the client, so time to pack up and head home
+            log::warn!("No name from client {} before timeout", _remote_address);
             return Ok(());
-        }
-        if CHATROOM
-            .read()
-            .expect("Chatroom should not be poisoned")
-            .contains_key(&inner_name)
-        {
-            stream.write_all("Name already taken.".as_bytes())?;
+        };
+        let name = Arc::new(if next_line.is_ok() {
+            let inner_name = next_line?;
+            if inner_name
+                .matches(|c| char::is_ascii_alphanumeric(&c))
+                .count()
+                != inner_name.len()
+            {
+                stream.write_all("Name must be alphanumeric.".as_bytes())?;
+                stream.flush()?;
+                return Ok(());
+            }
+            if self.members().contains_key(&inner_name) {
+                stream.write_all("Name already taken.".as_bytes())?;
+                stream.flush()?;
+                return Ok(());
+            }
+            inner_name
+        } else {
+            log::warn!("No name provided");
+            stream.write_all("Shoulda said a name.".as_bytes())?;
             stream.flush()?;
             return Ok(());
-        }
-        inner_name
-    } else {
-        log::warn!("No name provided");
-        stream.write_all("Shoulda said a name.".as_bytes())?;
-        stream.flush()?;
-        return Ok(());
-    });
-
-    let (tx, rx) = channel::<Message>();
-
-    let mut locked_chatroom = CHATROOM.write().expect("Chatroom should not be poisoned");
-    let user_list: Vec<Arc<String>> = locked_chatroom.keys().cloned().collect();
-    tx.send(Message {
-        from: name.clone(),
-        content: MessageContent::UserList(user_list),
-    })?;
-    locked_chatroom.insert(name.clone(), tx.clone());
-    drop(locked_chatroom);
-
-    send_to_room(Message {
-        from: name.clone(),
-        content: MessageContent::Joined,
-    })?;
-
-    let mut writer = stream.try_clone()?;
-    let name_for_rx: Arc<String> = name.clone();
-    thread::spawn(move || {
-        for message in rx {
-            log::debug!(to = as_display!(name_for_rx), message = as_debug!(message); "Got message");
-            let response = match message.content {
-                MessageContent::UserList(users) => format!(
-                    "* The room contains: {}\n",
-                    users
-                        .iter()
-                        .map(|s| s.deref().as_str())
-                        .collect::<Vec<&str>>()
-                        .join(", ")
-                ),
-                MessageContent::Joined => format!("* {} joined\n", message.from),
-                MessageContent::Left => format!("* {} left\n", message.from),
-                MessageContent::Message(content) => {
-                    format!("[{}] {}\n", message.from, content)
+        });
+
+        stream.set_read_timeout(None)?;
+
+        let (tx, rx) = channel::<Message>();
+        drop(shutdown_guard);
+        let tx_for_shutdown = tx.clone();
+        let name_for_shutdown = name.clone();
+        let _shutdown_guard = shutdown_signal.on_shutdown(move || {
+            let _ = tx_for_shutdown.send(Message {
+                from: name_for_shutdown,
+                content: MessageContent::ServerShuttingDown,
+            });
+        });
+
+        let mut locked_chatroom = self.members_mut();
+        let user_list: Vec<Arc<String>> = locked_chatroom.keys().cloned().collect();
+        tx.send(Message {
+            from: name.clone(),
+            content: MessageContent::UserList(user_list),
+        })?;
+        locked_chatroom.insert(name.clone(), tx.clone());
+        drop(locked_chatroom);
+        let _membership = Membership {
+            chatroom: self,
+            name: name.clone(),
+            shutdown_signal,
+        };
+
+        self.send_to_room(Message {
+            from: name.clone(),
+            content: MessageContent::Joined,
+        })?;
+
+        let mut writer = stream.try_clone()?;
+        let name_for_rx: Arc<String> = name.clone();
+        thread::spawn(move || {
+            for message in rx {
+                log::debug!(to = as_display!(name_for_rx), message = as_debug!(message); "Got message");
+                let response = match message.content {
+                    MessageContent::UserList(users) => format!(
+                        "* The room contains: {}\n",
+                        users
+                            .iter()
+                            .map(|s| s.deref().as_str())
+                            .collect::<Vec<&str>>()
+                            .join(", ")
+                    ),
+                    MessageContent::Joined => format!("* {} joined\n", message.from),
+                    MessageContent::Left => format!("* {} left\n", message.from),
+                    MessageContent::Message(content) => {
+                        format!("[{}] {}\n", message.from, content)
+                    }
+                    MessageContent::ServerShuttingDown => {
+                        // Hanging up ends the handler's read loop too
+                        let _ = writer.write_all(b"* server shutting down\n");
+                        let _ = writer.shutdown(Shutdown::Both);
+                        break;
+                    }
+                };
+                if let Err(e) = writer.write_all(response.as_bytes()) {
+                    log::error!("Error writing message to client {}: {}", name_for_rx, e);
+                    break;
+                }
+                if let Err(e) = writer.flush() {
+                    log::error!("Error flushing message to client {}: {}", name_for_rx, e);
+                    break;
                 }
-            };
-            if let Err(e) = writer.write_all(response.as_bytes()) {
-                log::error!("Error writing message to client {}: {}", name_for_rx, e);
-                break;
             }
-            if let Err(e) = writer.flush() {
-                log::error!("Error flushing message to client {}: {}", name_for_rx, e);
-                break;
+        });
+
+        for line in lines {
+            let line = line?;
+            if line.is_empty() {
+                continue;
             }
+            log::debug!(from = as_display!(name), message = as_display!(line); "Forwarding message");
+            self.send_to_room(Message {
+                from: name.clone(),
+                content: MessageContent::Message(line.into()),
+            })?;
         }
-    });
+        Ok(())
+    }
+}
+
+/// Someone in the room, who leaves it when this is dropped, however their connection ended.
+/// Leaving drops the room's sender for them, so their writer thread finishes too.
+struct Membership<'a> {
+    chatroom: &'a Chatroom,
+    name: Arc<String>,
+    shutdown_signal: &'a ShutdownSignal,
+}
 
-    for line in lines {
-        let line = line?;
-        if line.is_empty() {
-            continue;
+impl Drop for Membership<'_> {
+    fn drop(&mut self) {
+        self.chatroom.members_mut().remove(&self.name);
+        if self.shutdown_signal.is_shutdown_initiated() {
+            // Everyone else is being hung up on too
+            return;
+        }
+        if let Err(e) = self.chatroom.send_to_room(Message {
+            from: self.name.clone(),
+            content: MessageContent::Left,
+        }) {
+            log::error!(from = as_display!(self.name), error = as_debug!(e); "Failed to announce leaving");
         }
-        log::debug!(from = as_display!(name), message = as_display!(line); "Forwarding message");
-        send_to_room(Message {
-            from: name.clone(),
-            content: MessageContent::Message(line.into()),
-        })?;
     }
-
-    CHATROOM
-        .write()
-        .expect("Chatroom should not be poisoned")
-        .remove(&name);
-    send_to_room(Message {
-        from: name.clone(),
-        content: MessageContent::Left,
-    })
 }
 
 pub(crate) fn help(ctx: &Context) -> Result<(), Box<dyn Error>> {
     println!("Usage: {} budget_chat", ctx.program_name);
     Ok(())
 }
+
+pub(crate) fn scenarios() -> Vec<Scenario> {
+    // Names must be unique in the room, and the room outlives the checker's connections
+    let run_id = crate::check::run_id();
+    let alice = format!("alice{}", run_id);
+    let bob = format!("bob{}", run_id);
+    let carol = format!("carol{}", run_id);
+    vec![Scenario {
+        name: "join and leave notifications are ordered with messages",
+        transport: Transport::Tcp,
+        steps: vec![
+            // The greeting can say anything
+            Step::expect_matching(0, "", ""),
+            Step::send(0, format!("{}\n", alice)),
+            Step::expect_matching(0, "*", ""),
+            Step::expect_matching(1, "", ""),
+            Step::send(1, format!("{}\n", bob)),
+            Step::expect_matching(1, "*", &alice),
+            Step::expect_matching(0, "*", &bob),
+            Step::send(0, "hi bob\n"),
+            Step::expect(1, format!("[{}] hi bob\n", alice)),
+            Step::send(1, "hi alice\n"),
+            Step::send(1, "bye alice\n"),
+            Step::Disconnect(1),
+            Step::expect(0, format!("[{}] hi alice\n", bob)),
+            Step::expect(0, format!("[{}] bye alice\n", bob)),
+            Step::expect_matching(0, "*", &bob),
+            Step::expect_matching(2, "", ""),
+            Step::send(2, format!("{}\n", carol)),
+            Step::expect_matching(2, "*", &alice),
+            Step::expect_matching(0, "*", &carol),
+        ],
+    }]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scaffolding::test_context;
+    use std::io::Read;
+    use std::net::{SocketAddr, TcpStream};
+    use std::os::fd::AsRawFd;
+
+    fn serve_chatroom() -> (SocketAddr, ShutdownSignal) {
+        let (ctx, address) = test_context("budget_chat");
+        let shutdown_signal = StreamServer::new()
+            .serve(&ctx, Chatroom::default())
+            .expect("Server should start");
+        (address, shutdown_signal)
+    }
+
+    fn join(address: SocketAddr, name: &str) -> (BufReader<TcpStream>, String) {
+        let mut stream = TcpStream::connect(address).expect("Should be able to connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+        stream.write_all(format!("{}\n", name).as_bytes()).unwrap();
+        let mut room_list = String::new();
+        reader.read_line(&mut room_list).unwrap();
+        (reader, room_list)
+    }
+
+    #[test]
+    fn servers_have_separate_rooms() {
+        let (first, _) = serve_chatroom();
+        let (second, _) = serve_chatroom();
+
+        let (_alice_in_first, room_list) = join(first, "alice");
+        assert_eq!(room_list, "* The room contains: \n");
+        // The same name is free in the other room, which is also empty
+        let (_alice_in_second, room_list) = join(second, "alice");
+        assert_eq!(room_list, "* The room contains: \n");
+
+        let (_bob_in_second, room_list) = join(second, "bob");
+        assert_eq!(room_list, "* The room contains: alice\n");
+    }
+
+    #[test]
+    fn members_leave_however_their_connection_ends() {
+        let (address, _shutdown_signal) = serve_chatroom();
+        let (mut alice, _) = join(address, "alice");
+        let (bob, _) = join(address, "bob");
+        let mut line = String::new();
+        alice.read_line(&mut line).unwrap();
+        assert_eq!(line, "* bob joined\n");
+
+        // Resetting the connection makes bob's handler fail to read, rather than see the end
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        // SAFETY: linger is a valid SO_LINGER option value, and outlives the call
+        let result = unsafe {
+            libc::setsockopt(
+                bob.get_ref().as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_LINGER,
+                &linger as *const libc::linger as *const libc::c_void,
+                std::mem::size_of::<libc::linger>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(result, 0);
+        drop(bob);
+
+        line.clear();
+        alice.read_line(&mut line).unwrap();
+        assert_eq!(line, "* bob left\n");
+        let (_bob_again, room_list) = join(address, "bob");
+        assert_eq!(room_list, "* The room contains: alice\n");
+    }
+
+    #[test]
+    fn carries_on_after_a_panic_while_holding_the_room() {
+        let chatroom = Chatroom::default();
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _members = chatroom.members.write().unwrap();
+            panic!("Deliberate panic while holding the room");
+        }));
+        assert!(panicked.is_err());
+        assert!(chatroom.members.is_poisoned());
+
+        let (ctx, address) = test_context("budget_chat");
+        let _shutdown_signal = StreamServer::new().serve(&ctx, chatroom).unwrap();
+        let (mut alice, _) = join(address, "alice");
+        let (mut bob, room_list) = join(address, "bob");
+        assert_eq!(room_list, "* The room contains: alice\n");
+        let mut line = String::new();
+        alice.read_line(&mut line).unwrap();
+        assert_eq!(line, "* bob joined\n");
+        bob.get_mut().write_all(b"hi\n").unwrap();
+        line.clear();
+        alice.read_line(&mut line).unwrap();
+        assert_eq!(line, "[bob] hi\n");
+    }
+
+    #[test]
+    fn tells_everyone_when_shutting_down() {
+        let (address, mut shutdown_signal) = serve_chatroom();
+        let (mut alice, _) = join(address, "alice");
+        let (mut bob, _) = join(address, "bob");
+        let mut bob_joined = String::new();
+        alice.read_line(&mut bob_joined).unwrap();
+
+        shutdown_signal.start_shutdown();
+        for reader in [&mut alice, &mut bob] {
+            let mut rest = String::new();
+            reader.read_to_string(&mut rest).unwrap();
+            assert_eq!(rest, "* server shutting down\n");
+        }
+        // Every handler returned, so there was no need to wait for the shutdown timeout
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(2)));
+    }
+}
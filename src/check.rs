@@ -0,0 +1,406 @@
+//! A conformance checker: scripted protocol scenarios that can be run against any server.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{ToSocketAddrs, UdpSocket},
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::server::{Stream, UNIX_PREFIX};
+
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Transport {
+    Tcp,
+    Udp,
+}
+
+/// One step of a scenario. Every step names a client by index; each client gets its own
+/// connection (or, for UDP, its own socket), opened the first time the client is used.
+#[derive(Debug)]
+pub(crate) enum Step {
+    /// Send these bytes (for UDP, as a single datagram)
+    Send(usize, Vec<u8>),
+    /// Expect exactly these bytes next (for UDP, exactly this datagram)
+    Expect(usize, Vec<u8>),
+    /// Expect a line (for UDP, a datagram) which starts with one string and contains another
+    ExpectMatching {
+        client: usize,
+        starts_with: String,
+        contains: String,
+    },
+    /// Expect exactly these datagrams, in any order (UDP only)
+    ExpectUnordered(usize, Vec<Vec<u8>>),
+    /// Expect a line of JSON equal to this value, regardless of formatting or key order
+    ExpectJsonLine(usize, serde_json::Value),
+    /// Expect the server to close the connection without sending anything more
+    ExpectClosed(usize),
+    /// Close the client's connection
+    Disconnect(usize),
+}
+
+impl Step {
+    pub(crate) fn send(client: usize, bytes: impl AsRef<[u8]>) -> Self {
+        Step::Send(client, bytes.as_ref().to_vec())
+    }
+
+    pub(crate) fn expect(client: usize, bytes: impl AsRef<[u8]>) -> Self {
+        Step::Expect(client, bytes.as_ref().to_vec())
+    }
+
+    pub(crate) fn expect_matching(client: usize, starts_with: &str, contains: &str) -> Self {
+        Step::ExpectMatching {
+            client,
+            starts_with: String::from(starts_with),
+            contains: String::from(contains),
+        }
+    }
+}
+
+/// A number which is very likely different every time the checker runs, for scenarios that
+/// need names or ids the server hasn't seen before (servers keep state between connections).
+pub(crate) fn run_id() -> u32 {
+    static RUN_ID: OnceLock<u32> = OnceLock::new();
+    *RUN_ID.get_or_init(|| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        (now.as_secs() as u32 ^ now.subsec_nanos() ^ std::process::id()) % 1_000_000_000
+    })
+}
+
+pub(crate) struct Scenario {
+    pub(crate) name: &'static str,
+    pub(crate) transport: Transport,
+    pub(crate) steps: Vec<Step>,
+}
+
+enum Client {
+    Tcp(BufReader<Stream>),
+    Udp(UdpSocket),
+}
+
+struct Failure {
+    step: usize,
+    message: String,
+}
+
+/// Show bytes as an escaped string, which is readable for text protocols and still exact for binary ones.
+fn describe(bytes: &[u8]) -> String {
+    format!("\"{}\"", bytes.escape_ascii())
+}
+
+fn describe_difference(expected: &[u8], actual: &[u8]) -> String {
+    let offset = expected
+        .iter()
+        .zip(actual.iter())
+        .position(|(e, a)| e != a)
+        .unwrap_or(expected.len().min(actual.len()));
+    format!(
+        "first difference at byte {}\n      expected: {}\n      actual:   {}",
+        offset,
+        describe(expected),
+        describe(actual)
+    )
+}
+
+/// Connect to `address`, which may be `unix:/path/to/socket` for stream transports.
+fn connect(transport: Transport, address: &str) -> io::Result<Client> {
+    match transport {
+        Transport::Tcp => {
+            let stream = Stream::connect(address)?;
+            stream.set_read_timeout(Some(READ_TIMEOUT))?;
+            Ok(Client::Tcp(BufReader::new(stream)))
+        }
+        Transport::Udp if address.starts_with(UNIX_PREFIX) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "UDP scenarios can't be run over Unix domain sockets",
+        )),
+        Transport::Udp => {
+            let remote_address = address.to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+            })?;
+            let socket = UdpSocket::bind(if remote_address.is_ipv6() {
+                "[::]:0"
+            } else {
+                "0.0.0.0:0"
+            })?;
+            socket.set_read_timeout(Some(READ_TIMEOUT))?;
+            socket.connect(remote_address)?;
+            Ok(Client::Udp(socket))
+        }
+    }
+}
+
+fn receive(socket: &UdpSocket) -> Result<Vec<u8>, String> {
+    let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+    let length = socket
+        .recv(&mut buffer)
+        .map_err(|e| format!("expected a datagram, got error: {}", e))?;
+    buffer.truncate(length);
+    Ok(buffer)
+}
+
+fn run_step(step: &Step, client: &mut Client) -> Result<(), String> {
+    match (step, client) {
+        (Step::Send(_, bytes), Client::Tcp(reader)) => {
+            reader.get_mut().write_all(bytes).map_err(|e| e.to_string())
+        }
+        (Step::Send(_, bytes), Client::Udp(socket)) => {
+            socket.send(bytes).map(|_| ()).map_err(|e| e.to_string())
+        }
+        (Step::Expect(_, expected), Client::Tcp(reader)) => {
+            let mut actual = Vec::with_capacity(expected.len());
+            let result = reader.take(expected.len() as u64).read_to_end(&mut actual);
+            if actual != *expected {
+                let mut message = describe_difference(expected, &actual);
+                if let Err(e) = result {
+                    message.push_str(&format!("\n      then: {}", e));
+                }
+                return Err(message);
+            }
+            Ok(())
+        }
+        (Step::Expect(_, expected), Client::Udp(socket)) => {
+            let actual = receive(socket)?;
+            if actual != *expected {
+                return Err(describe_difference(expected, &actual));
+            }
+            Ok(())
+        }
+        (
+            Step::ExpectMatching {
+                starts_with,
+                contains,
+                ..
+            },
+            client,
+        ) => {
+            let actual = match client {
+                Client::Tcp(reader) => {
+                    let mut line = Vec::new();
+                    reader
+                        .read_until(b'\n', &mut line)
+                        .map_err(|e| format!("expected a line, got error: {}", e))?;
+                    if !line.ends_with(b"\n") {
+                        return Err(format!("expected a complete line, got {}", describe(&line)));
+                    }
+                    line
+                }
+                Client::Udp(socket) => receive(socket)?,
+            };
+            if !actual.starts_with(starts_with.as_bytes())
+                || !String::from_utf8_lossy(&actual).contains(contains.as_str())
+            {
+                return Err(format!(
+                    "expected something starting with {} and containing {}\n      actual: {}",
+                    describe(starts_with.as_bytes()),
+                    describe(contains.as_bytes()),
+                    describe(&actual)
+                ));
+            }
+            Ok(())
+        }
+        (Step::ExpectUnordered(_, expected), Client::Udp(socket)) => {
+            let mut remaining = expected.clone();
+            while !remaining.is_empty() {
+                let actual = receive(socket)?;
+                match remaining.iter().position(|datagram| *datagram == actual) {
+                    Some(index) => {
+                        remaining.remove(index);
+                    }
+                    None => {
+                        return Err(format!(
+                            "expected one of {}\n      actual: {}",
+                            remaining
+                                .iter()
+                                .map(|datagram| describe(datagram))
+                                .collect::<Vec<String>>()
+                                .join(", "),
+                            describe(&actual)
+                        ))
+                    }
+                }
+            }
+            Ok(())
+        }
+        (Step::ExpectJsonLine(_, expected), Client::Tcp(reader)) => {
+            let mut line = Vec::new();
+            reader
+                .read_until(b'\n', &mut line)
+                .map_err(|e| format!("expected a line, got error: {}", e))?;
+            match serde_json::from_slice::<serde_json::Value>(&line) {
+                Ok(actual) if line.ends_with(b"\n") && actual == *expected => Ok(()),
+                _ => Err(format!(
+                    "expected JSON equivalent to {}\n      actual: {}",
+                    describe(expected.to_string().as_bytes()),
+                    describe(&line)
+                )),
+            }
+        }
+        (Step::ExpectClosed(_), Client::Tcp(reader)) => {
+            let mut rest = Vec::new();
+            match reader.read_to_end(&mut rest) {
+                Ok(_) if rest.is_empty() => Ok(()),
+                Ok(_) => Err(format!(
+                    "expected the connection to close, got {}",
+                    describe(&rest)
+                )),
+                // A reset is as closed as it gets
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => Ok(()),
+                Err(e) => Err(format!(
+                    "expected the connection to close, got error: {}",
+                    e
+                )),
+            }
+        }
+        (step @ Step::ExpectUnordered(..), Client::Tcp(_)) => {
+            Err(format!("{:?} is only supported over UDP", step))
+        }
+        (step, Client::Udp(_)) => Err(format!("{:?} is not supported over UDP", step)),
+        (Step::Disconnect(_), _) => {
+            unreachable!("Handled without a client")
+        }
+    }
+}
+
+fn run_scenario(scenario: &Scenario, address: &str) -> Result<(), Failure> {
+    let mut clients: HashMap<usize, Client> = HashMap::new();
+    for (index, step) in scenario.steps.iter().enumerate() {
+        let fail = |message: String| Failure {
+            step: index + 1,
+            message,
+        };
+        let client_index = match step {
+            Step::Disconnect(client) => {
+                clients.remove(client);
+                continue;
+            }
+            Step::Send(client, _)
+            | Step::Expect(client, _)
+            | Step::ExpectMatching { client, .. }
+            | Step::ExpectUnordered(client, _)
+            | Step::ExpectJsonLine(client, _)
+            | Step::ExpectClosed(client) => *client,
+        };
+        let client = match clients.entry(client_index) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(connect(scenario.transport, address).map_err(|e| {
+                    fail(format!("unable to connect client {}: {}", client_index, e))
+                })?)
+            }
+        };
+        run_step(step, client)
+            .map_err(|message| fail(format!("client {}: {}", client_index, message)))?;
+    }
+    Ok(())
+}
+
+/// Run every scenario against `address`, printing the result of each. Returns the number that failed.
+pub(crate) fn run_scenarios(scenarios: &[Scenario], address: &str) -> usize {
+    let mut failed = 0;
+    for scenario in scenarios {
+        match run_scenario(scenario, address) {
+            Ok(()) => println!("PASS {}", scenario.name),
+            Err(failure) => {
+                failed += 1;
+                println!(
+                    "FAIL {} (step {} of {})\n      {}",
+                    scenario.name,
+                    failure.step,
+                    scenario.steps.len(),
+                    failure.message
+                );
+            }
+        }
+    }
+    failed
+}
+
+pub(crate) fn check(
+    problem: &str,
+    scenarios: &[Scenario],
+    address: &str,
+) -> Result<(), Box<dyn Error>> {
+    if scenarios.is_empty() {
+        println!("No scenarios for {}", problem);
+        return Ok(());
+    }
+    let failed = run_scenarios(scenarios, address);
+    println!(
+        "{}: {} passed, {} failed",
+        problem,
+        scenarios.len() - failed,
+        failed
+    );
+    if failed > 0 {
+        Err(format!("{} of {} scenarios failed", failed, scenarios.len()).into())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn echo_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().map_while(Result::ok) {
+                thread::spawn(move || {
+                    let mut reader = stream.try_clone().unwrap();
+                    let _ = io::copy(&mut reader, &mut stream);
+                });
+            }
+        });
+        address.to_string()
+    }
+
+    #[test]
+    fn passes_matching_scenarios() {
+        let address = echo_server();
+        let scenarios = [Scenario {
+            name: "echo",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, "hello\n"),
+                Step::send(1, "{\"b\": 2, \"a\": 1}\n"),
+                Step::expect(0, "hello\n"),
+                Step::ExpectJsonLine(1, serde_json::json!({"a": 1, "b": 2})),
+                Step::send(0, "* bob joined\n"),
+                Step::expect_matching(0, "*", "bob"),
+            ],
+        }];
+        assert_eq!(run_scenarios(&scenarios, &address), 0);
+    }
+
+    #[test]
+    fn reports_the_first_differing_byte() {
+        let address = echo_server();
+        let scenario = Scenario {
+            name: "mismatch",
+            transport: Transport::Tcp,
+            steps: vec![Step::send(0, "hello"), Step::expect(0, "help!")],
+        };
+        let failure = run_scenario(&scenario, &address).err().unwrap();
+        assert_eq!(failure.step, 2);
+        assert!(failure.message.contains("first difference at byte 3"));
+        assert!(failure.message.contains("\"help!\""));
+        assert!(failure.message.contains("\"hello\""));
+    }
+
+    #[test]
+    fn describes_binary_bytes_exactly() {
+        assert_eq!(describe(&[0x41, 0x00, 0xff, b'\n']), "\"A\\x00\\xff\\n\"");
+    }
+}
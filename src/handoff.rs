@@ -0,0 +1,268 @@
+//! Restarting into a new build without closing the port or dropping connections. On SIGUSR2 we
+//! start our binary again, passing it our listening sockets the way socket activation would
+//! (see [`crate::socket_activation`]), and drain once it has taken over. If any server can't
+//! hand over without dropping something, such as a datagram server, we don't restart at all.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error,
+    fs::File,
+    io::{self, Read, Write},
+    iter,
+    os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    process::{Child, Command, ExitStatus},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{as_debug, as_display};
+
+use crate::{admin::ConnectionRegistry, pass_fds::pass_fds, scaffolding::Context, signals};
+
+const RESTART_SIGNAL: libc::c_int = libc::SIGUSR2;
+/// If the new process isn't serving within this long, it couldn't take over, so we carry on as
+/// we were
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// Names the descriptor the new process writes a byte to once it's serving
+const READY_FD_VARIABLE: &str = "HANDOFF_READY_FD";
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The listening sockets to pass on when we restart.
+#[derive(Default)]
+pub(crate) struct Handoff {
+    listeners: Mutex<Listeners>,
+    /// Set once a new process has taken over our listeners
+    handed_off: AtomicBool,
+    /// If we're the new process, where to tell the old one we're serving
+    ready_pipe: Mutex<Option<File>>,
+}
+
+#[derive(Default)]
+struct Listeners {
+    next_id: u64,
+    /// Each listener's socket, and the name the new process will know it by
+    sockets: BTreeMap<u64, (OwnedFd, String)>,
+    /// Why we can't restart, from servers whose listeners can't be handed over
+    refusals: Vec<String>,
+}
+
+/// Stops a listener being handed over when dropped.
+#[must_use = "the listener is no longer handed over once this is dropped"]
+pub(crate) struct HandoffGuard {
+    handoff: Arc<Handoff>,
+    id: u64,
+}
+
+impl Drop for HandoffGuard {
+    fn drop(&mut self) {
+        self.handoff.listeners().sockets.remove(&self.id);
+    }
+}
+
+impl Handoff {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pass `listener` on to the new process as `name` when we restart, until the guard is
+    /// dropped. The name is how the new process tells which listener is for what.
+    pub(crate) fn add_listener(
+        self: &Arc<Self>,
+        listener: BorrowedFd<'_>,
+        name: &str,
+    ) -> std::io::Result<HandoffGuard> {
+        let socket = listener.try_clone_to_owned()?;
+        let mut listeners = self.listeners();
+        let id = listeners.next_id;
+        listeners.next_id += 1;
+        listeners.sockets.insert(id, (socket, String::from(name)));
+        Ok(HandoffGuard {
+            handoff: self.clone(),
+            id,
+        })
+    }
+
+    /// Refuse to restart, for a server which can't hand its listener over without dropping
+    /// anything. `reason` is logged when a restart is asked for.
+    pub(crate) fn refuse(&self, reason: String) {
+        self.listeners().refusals.push(reason);
+    }
+
+    /// Tell the process we're taking over from, if any, once we're serving.
+    pub(crate) fn set_ready_pipe(&self, ready_pipe: File) {
+        *self.ready_pipe() = Some(ready_pipe);
+    }
+
+    /// Let the process we're taking over from, if any, know we're serving, so it can drain.
+    pub(crate) fn notify_ready(&self) {
+        let Some(mut ready_pipe) = self.ready_pipe().take() else {
+            return;
+        };
+        if let Err(e) = ready_pipe.write_all(b"!") {
+            log::warn!(error = as_display!(e); "Unable to tell the old process we're ready");
+        }
+    }
+
+    /// Whether a new process has taken over our listeners, so we should leave them as they are.
+    pub(crate) fn is_handed_off(&self) -> bool {
+        self.handed_off.load(Ordering::SeqCst)
+    }
+
+    fn listeners(&self) -> std::sync::MutexGuard<'_, Listeners> {
+        self.listeners
+            .lock()
+            .expect("Handoff listeners should not be poisoned")
+    }
+
+    fn ready_pipe(&self) -> std::sync::MutexGuard<'_, Option<File>> {
+        self.ready_pipe
+            .lock()
+            .expect("Handoff ready pipe should not be poisoned")
+    }
+
+    /// Start `argv` with our listeners as file descriptors 3 onwards, and wait for it to say
+    /// it's serving.
+    fn hand_off(&self, argv: &[String]) -> Result<Child, Box<dyn Error>> {
+        let listeners = self.listeners();
+        if let Some(reason) = listeners.refusals.first() {
+            return Err(reason.clone().into());
+        }
+        if listeners.sockets.is_empty() {
+            return Err(String::from("There are no listening sockets to hand over").into());
+        }
+        let sockets: Vec<RawFd> = listeners
+            .sockets
+            .values()
+            .map(|(socket, _)| socket.as_raw_fd())
+            .collect();
+        let names: Vec<&str> = listeners
+            .sockets
+            .values()
+            .map(|(_, name)| name.as_str())
+            .collect();
+        let mut command = Command::new("sh");
+        // LISTEN_PID has to be the new process's pid, which exec keeps the same as the shell's
+        command
+            .args(["-c", r#"LISTEN_PID=$$ exec "$0" "$@""#])
+            .args(argv)
+            .env("LISTEN_FDS", sockets.len().to_string())
+            .env("LISTEN_FDNAMES", names.join(":"));
+        let (mut ready, ready_writer) = signals::pipe()?;
+        signals::add_flags(
+            ready.as_raw_fd(),
+            libc::F_GETFL,
+            libc::F_SETFL,
+            libc::O_NONBLOCK,
+        )?;
+        // Passed after the listeners, so it isn't mistaken for one
+        command.env(READY_FD_VARIABLE, (3 + sockets.len()).to_string());
+        let mut fds = sockets;
+        fds.push(ready_writer.as_raw_fd());
+        pass_fds(&mut command, fds);
+        let child = command.spawn()?;
+        drop(listeners);
+        // Only the new process can write now, so if it exits we see the end of the pipe
+        drop(ready_writer);
+
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        loop {
+            match ready.read(&mut [0u8; 1]) {
+                Ok(0) => {
+                    let status = stop(child)?;
+                    return Err(format!("The new process exited before serving: {}", status).into());
+                }
+                Ok(_) => return Ok(child),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    stop(child)?;
+                    return Err(e.into());
+                }
+            }
+            if Instant::now() >= deadline {
+                stop(child)?;
+                return Err(format!(
+                    "The new process wasn't serving within {:?}",
+                    STARTUP_TIMEOUT
+                )
+                .into());
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Stop a new process which couldn't take over, as it would otherwise share our listeners.
+fn stop(mut child: Child) -> io::Result<ExitStatus> {
+    // It may have exited already
+    let _ = child.kill();
+    child.wait()
+}
+
+/// Take the pipe the process we're taking over from waits on, if it started us.
+pub(crate) fn ready_pipe_from_env() -> Result<Option<File>, Box<dyn Error>> {
+    let Ok(fd) = env::var(READY_FD_VARIABLE) else {
+        return Ok(None);
+    };
+    env::remove_var(READY_FD_VARIABLE);
+    let fd: RawFd = fd
+        .parse()
+        .ok()
+        .filter(|fd| *fd >= 3)
+        .ok_or_else(|| format!("{} should be a file descriptor above 2", READY_FD_VARIABLE))?;
+    // Fails if it isn't open, and stops any process we start in turn inheriting it
+    signals::add_flags(fd, libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC)?;
+    // SAFETY: it was passed to us for this alone, so nothing else owns it
+    Ok(Some(unsafe { File::from_raw_fd(fd) }))
+}
+
+/// Restart when we receive SIGUSR2: start a new copy of ourselves with the same arguments,
+/// hand it our listeners, and drain every server once it's serving.
+pub(crate) fn restart_on_signal(ctx: &Context) -> std::io::Result<()> {
+    let handoff = ctx.handoff.clone();
+    let registry = ctx.connections.clone();
+    let argv: Vec<String> = iter::once(ctx.program_name.clone())
+        .chain(ctx.problem.clone())
+        .chain(ctx.problem_arguments.iter().cloned())
+        .collect();
+    signals::on_signal(RESTART_SIGNAL, move || restart(&handoff, &registry, &argv))
+}
+
+fn restart(handoff: &Handoff, registry: &ConnectionRegistry, argv: &[String]) {
+    if handoff.is_handed_off() {
+        log::info!("Already handed over to a new process");
+        return;
+    }
+    log::info!(argv = as_debug!(argv); "Restarting, handing listening sockets to a new process");
+    let mut child = match handoff.hand_off(argv) {
+        Ok(child) => child,
+        Err(e) => {
+            log::error!(error = as_display!(e); "Unable to restart; carrying on as we were");
+            return;
+        }
+    };
+    let new_pid = child.id();
+    handoff.handed_off.store(true, Ordering::SeqCst);
+    log::info!(new_pid = as_display!(new_pid); "New process has taken over, draining");
+    registry.drain();
+
+    // It'll usually outlive us, but if it doesn't we shouldn't leave a zombie behind
+    let reaped = thread::Builder::new()
+        .name("handoff-reaper".into())
+        .spawn(move || match child.wait() {
+            Ok(status) => log::warn!(
+                new_pid = as_display!(new_pid),
+                status = as_display!(status);
+                "New process exited before we did"
+            ),
+            Err(e) => log::debug!(error = as_display!(e); "Unable to wait for new process"),
+        });
+    if let Err(e) = reaped {
+        log::debug!(error = as_display!(e); "Unable to start thread waiting for new process");
+    }
+}
@@ -0,0 +1,374 @@
+use crate::check::{Scenario, Step, Transport};
+use crate::{scaffolding::Context, server};
+use log::{as_debug, as_display};
+use server::{PeerAddress, Server as _, ShutdownSignal, Stream, StreamServer};
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// The cipher spec is at most 80 bytes, including the terminating zero byte.
+const MAX_CIPHER_SPEC_LENGTH: usize = 80;
+/// Application lines are at most 5000 characters, including the newline.
+const MAX_LINE_LENGTH: u64 = 5000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    ReverseBits,
+    Xor(u8),
+    XorPos,
+    Add(u8),
+    AddPos,
+}
+
+impl Operation {
+    // Positions are only ever used mod 256, so truncating to u8 is exactly what we want
+    fn encode(&self, byte: u8, position: usize) -> u8 {
+        match self {
+            Operation::ReverseBits => byte.reverse_bits(),
+            Operation::Xor(n) => byte ^ n,
+            Operation::XorPos => byte ^ (position as u8),
+            Operation::Add(n) => byte.wrapping_add(*n),
+            Operation::AddPos => byte.wrapping_add(position as u8),
+        }
+    }
+
+    fn decode(&self, byte: u8, position: usize) -> u8 {
+        match self {
+            Operation::ReverseBits => byte.reverse_bits(),
+            Operation::Xor(n) => byte ^ n,
+            Operation::XorPos => byte ^ (position as u8),
+            Operation::Add(n) => byte.wrapping_sub(*n),
+            Operation::AddPos => byte.wrapping_sub(position as u8),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Cipher {
+    operations: Vec<Operation>,
+}
+
+impl Cipher {
+    /// Read a cipher spec one byte at a time, so nothing after the terminating zero byte is consumed.
+    fn read_spec<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut operations = Vec::new();
+        let mut spec_length = 0;
+        let mut next_byte = || -> io::Result<u8> {
+            spec_length += 1;
+            if spec_length > MAX_CIPHER_SPEC_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "cipher spec too long",
+                ));
+            }
+            let mut buffer = [0u8; 1];
+            reader.read_exact(&mut buffer)?;
+            Ok(buffer[0])
+        };
+        loop {
+            operations.push(match next_byte()? {
+                0x00 => break,
+                0x01 => Operation::ReverseBits,
+                0x02 => Operation::Xor(next_byte()?),
+                0x03 => Operation::XorPos,
+                0x04 => Operation::Add(next_byte()?),
+                0x05 => Operation::AddPos,
+                other => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown cipher operation {:#04x}", other),
+                    ))
+                }
+            });
+        }
+        Ok(Self { operations })
+    }
+
+    fn encode(&self, byte: u8, position: usize) -> u8 {
+        self.operations
+            .iter()
+            .fold(byte, |byte, operation| operation.encode(byte, position))
+    }
+
+    fn decode(&self, byte: u8, position: usize) -> u8 {
+        self.operations
+            .iter()
+            .rev()
+            .fold(byte, |byte, operation| operation.decode(byte, position))
+    }
+
+    /// A cipher is a no-op if it leaves every byte unchanged at every position.
+    /// Positions only matter mod 256, so checking the first 256 covers them all.
+    fn is_no_op(&self) -> bool {
+        (0..256).all(|position| (0..=u8::MAX).all(|byte| self.encode(byte, position) == byte))
+    }
+}
+
+/// Wraps a byte stream, decoding everything read from it and encoding everything written to it.
+/// The client-to-server and server-to-client directions each have their own stream position.
+pub(crate) struct CipherStream<S> {
+    inner: S,
+    cipher: Cipher,
+    client_position: usize,
+    server_position: usize,
+}
+
+impl<S: Read + Write> CipherStream<S> {
+    /// Read the cipher spec from the start of `inner` and wrap it. Fails if the spec is
+    /// invalid or is a no-op cipher, in which case the client should be disconnected.
+    pub(crate) fn negotiate(mut inner: S) -> io::Result<Self> {
+        let cipher = Cipher::read_spec(&mut inner)?;
+        if cipher.is_no_op() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "cipher spec is a no-op",
+            ));
+        }
+        Ok(Self {
+            inner,
+            cipher,
+            client_position: 0,
+            server_position: 0,
+        })
+    }
+}
+
+impl<S: Read> Read for CipherStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        for byte in buf[..bytes_read].iter_mut() {
+            *byte = self.cipher.decode(*byte, self.client_position);
+            self.client_position += 1;
+        }
+        Ok(bytes_read)
+    }
+}
+
+impl<S: Write> Write for CipherStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Encoding depends on position, so we must never report a partial write
+        // for bytes we've already encoded; write_all makes that simple.
+        let encoded: Vec<u8> = buf
+            .iter()
+            .enumerate()
+            .map(|(offset, byte)| self.cipher.encode(*byte, self.server_position + offset))
+            .collect();
+        self.inner.write_all(&encoded)?;
+        self.server_position += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub(crate) fn serve(ctx: &Context) -> Result<ShutdownSignal, Box<dyn Error>> {
+    StreamServer::new().serve(ctx, handle)
+}
+
+fn handle(stream: &mut Stream, remote_address: &PeerAddress) -> Result<(), Box<dyn Error>> {
+    let stream = match CipherStream::negotiate(stream) {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::info!(
+                remote_address = as_display!(remote_address),
+                error = as_display!(e);
+                "Rejecting cipher spec"
+            );
+            return Ok(());
+        }
+    };
+    log::debug!(
+        remote_address = as_display!(remote_address),
+        cipher = as_debug!(stream.cipher.operations);
+        "Negotiated cipher"
+    );
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        (&mut reader).take(MAX_LINE_LENGTH).read_line(&mut line)?;
+        if !line.ends_with('\n') {
+            // Either the client went away, or sent an overlong line
+            return Ok(());
+        }
+        let Some(toy) = most_copies(line.trim_end_matches('\n')) else {
+            log::info!(
+                remote_address = as_display!(remote_address),
+                line = as_display!(line);
+                "Invalid toy request"
+            );
+            return Ok(());
+        };
+        let writer = reader.get_mut();
+        writer.write_all(toy.as_bytes())?;
+        writer.write_all(b"\n")?;
+    }
+}
+
+/// Given `10x toy car,15x dog on a string,4x inflatable motorcycle`, return `15x dog on a string`.
+fn most_copies(request: &str) -> Option<&str> {
+    request
+        .split(',')
+        .map(|toy| {
+            toy.split_once("x ")
+                .and_then(|(count, _)| count.parse::<u64>().ok())
+                .map(|count| (count, toy))
+        })
+        .collect::<Option<Vec<(u64, &str)>>>()?
+        .into_iter()
+        .max_by_key(|(count, _)| *count)
+        .map(|(_, toy)| toy)
+}
+
+pub(crate) fn help(ctx: &Context) -> Result<(), Box<dyn Error>> {
+    println!("Usage: {} insecure_sockets_layer", ctx.program_name);
+    Ok(())
+}
+
+pub(crate) fn scenarios() -> Vec<Scenario> {
+    vec![
+        Scenario {
+            name: "answers the spec example",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, [0x02, 0x7b, 0x05, 0x01, 0x00]),
+                Step::send(
+                    0,
+                    [
+                        0xf2, 0x20, 0xba, 0x44, 0x18, 0x84, 0xba, 0xaa, 0xd0, 0x26, 0x44, 0xa4,
+                        0xa8, 0x7e,
+                    ],
+                ),
+                Step::expect(0, [0x72, 0x20, 0xba, 0xd8, 0x78, 0x70, 0xee]),
+            ],
+        },
+        Scenario {
+            name: "disconnects no-op ciphers",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, [0x02, 0xa0, 0x02, 0x0b, 0x02, 0xab, 0x00]),
+                Step::ExpectClosed(0),
+            ],
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn cipher(spec: &[u8]) -> Cipher {
+        Cipher::read_spec(&mut Cursor::new(spec)).unwrap()
+    }
+
+    fn encode(cipher: &Cipher, data: &[u8], start: usize) -> Vec<u8> {
+        data.iter()
+            .enumerate()
+            .map(|(offset, byte)| cipher.encode(*byte, start + offset))
+            .collect()
+    }
+
+    #[test]
+    fn parses_cipher_specs() {
+        assert_eq!(
+            cipher(&[0x02, 0x7b, 0x05, 0x01, 0x00]).operations,
+            vec![
+                Operation::Xor(123),
+                Operation::AddPos,
+                Operation::ReverseBits
+            ]
+        );
+        // An operand of zero is not the end of the spec
+        assert_eq!(
+            cipher(&[0x04, 0x00, 0x03, 0x00]).operations,
+            vec![Operation::Add(0), Operation::XorPos]
+        );
+        assert!(Cipher::read_spec(&mut Cursor::new([0x06, 0x00])).is_err());
+        assert!(Cipher::read_spec(&mut Cursor::new([0x02])).is_err());
+        assert!(Cipher::read_spec(&mut Cursor::new([0x01; MAX_CIPHER_SPEC_LENGTH])).is_err());
+    }
+
+    #[test]
+    fn encodes_like_the_spec_examples() {
+        let c = cipher(&[0x02, 0x01, 0x01, 0x00]);
+        assert_eq!(encode(&c, b"hello", 0), vec![0x96, 0x26, 0xb6, 0xb6, 0x76]);
+
+        let c = cipher(&[0x02, 0x7b, 0x05, 0x01, 0x00]);
+        assert_eq!(
+            encode(&c, b"4x dog,5x car\n", 0),
+            vec![
+                0xf2, 0x20, 0xba, 0x44, 0x18, 0x84, 0xba, 0xaa, 0xd0, 0x26, 0x44, 0xa4, 0xa8, 0x7e
+            ]
+        );
+        assert_eq!(
+            encode(&c, b"5x car\n", 0),
+            vec![0x72, 0x20, 0xba, 0xd8, 0x78, 0x70, 0xee]
+        );
+    }
+
+    #[test]
+    fn decoding_inverts_encoding() {
+        let c = cipher(&[0x01, 0x02, 0xa0, 0x03, 0x04, 0x07, 0x05, 0x00]);
+        for position in 0..512 {
+            for byte in 0..=u8::MAX {
+                assert_eq!(c.decode(c.encode(byte, position), position), byte);
+            }
+        }
+    }
+
+    #[test]
+    fn detects_no_op_ciphers() {
+        for spec in [
+            vec![0x00],
+            vec![0x02, 0x00, 0x00],
+            vec![0x04, 0x00, 0x00],
+            vec![0x02, 0xab, 0x02, 0xab, 0x00],
+            vec![0x01, 0x01, 0x00],
+            vec![0x02, 0xa0, 0x02, 0x0b, 0x02, 0xab, 0x00],
+            vec![0x03, 0x03, 0x00],
+        ] {
+            assert!(cipher(&spec).is_no_op(), "{:?}", spec);
+        }
+        for spec in [vec![0x01, 0x00], vec![0x05, 0x00], vec![0x05, 0x05, 0x00]] {
+            assert!(!cipher(&spec).is_no_op(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn client_and_server_positions_are_independent() {
+        let mut incoming = encode(&cipher(&[0x05, 0x00]), b"4x dog,5x car\n", 0);
+        incoming.splice(0..0, [0x05, 0x00]);
+        let mut stream =
+            CipherStream::negotiate(Cursor::new(incoming)).expect("addpos is not a no-op");
+
+        let mut line = String::new();
+        BufReader::new(&mut stream).read_line(&mut line).unwrap();
+        assert_eq!(line, "4x dog,5x car\n");
+        assert_eq!(stream.client_position, 14);
+
+        // The response is encoded from position zero, regardless of how much we've read
+        let mut outgoing = Vec::new();
+        let mut writer = CipherStream {
+            inner: &mut outgoing,
+            cipher: stream.cipher.clone(),
+            client_position: stream.client_position,
+            server_position: 0,
+        };
+        writer.write_all(b"5x car\n").unwrap();
+        assert_eq!(outgoing, encode(&stream.cipher, b"5x car\n", 0));
+    }
+
+    #[test]
+    fn finds_the_toy_with_the_most_copies() {
+        assert_eq!(
+            most_copies("10x toy car,15x dog on a string,4x inflatable motorcycle"),
+            Some("15x dog on a string")
+        );
+        assert_eq!(most_copies("3x rat"), Some("3x rat"));
+        assert_eq!(most_copies("3x rat,bogus"), None);
+        assert_eq!(most_copies(""), None);
+    }
+}
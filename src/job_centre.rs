@@ -0,0 +1,610 @@
+use serde::{Deserialize, Serialize};
+
+use crate::check::{Scenario, Step, Transport};
+use crate::{scaffolding::Context, server};
+use log::{as_debug, as_display};
+use server::{ConnectionHandler, PeerAddress, Server as _, ShutdownSignal, Stream, StreamServer};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+/// How often a client waiting for a job is checked to see if it's still there
+const WAITING_CLIENT_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "request", rename_all = "lowercase")]
+enum Request {
+    Put {
+        queue: String,
+        job: serde_json::Map<String, serde_json::Value>,
+        pri: u64,
+    },
+    Get {
+        queues: Vec<String>,
+        #[serde(default)]
+        wait: bool,
+    },
+    Delete {
+        id: u64,
+    },
+    Abort {
+        id: u64,
+    },
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Status {
+    Ok,
+    Error,
+    NoJob,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Response {
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job: Option<serde_json::Map<String, serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pri: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Response {
+    fn with_status(status: Status) -> Self {
+        Self {
+            status,
+            id: None,
+            job: None,
+            pri: None,
+            queue: None,
+            error: None,
+        }
+    }
+
+    fn ok() -> Self {
+        Self::with_status(Status::Ok)
+    }
+
+    fn no_job() -> Self {
+        Self::with_status(Status::NoJob)
+    }
+
+    fn error(message: String) -> Self {
+        Self {
+            error: Some(message),
+            ..Self::with_status(Status::Error)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Job {
+    queue: String,
+    pri: u64,
+    body: serde_json::Map<String, serde_json::Value>,
+    /// The client currently working on this job, if any
+    worker: Option<u64>,
+}
+
+struct JobCentre {
+    next_job_id: u64,
+    jobs: HashMap<u64, Job>,
+    /// queue name -> (priority, job id) for jobs waiting in that queue. Entries are
+    /// removed lazily, so a job id here may since have been deleted or handed out.
+    queues: HashMap<String, BinaryHeap<(u64, Reverse<u64>)>>,
+}
+
+impl JobCentre {
+    fn new() -> Self {
+        Self {
+            next_job_id: 0,
+            jobs: HashMap::new(),
+            queues: HashMap::new(),
+        }
+    }
+
+    fn put(
+        &mut self,
+        queue: String,
+        pri: u64,
+        body: serde_json::Map<String, serde_json::Value>,
+    ) -> u64 {
+        let id = self.next_job_id;
+        self.next_job_id += 1;
+        self.enqueue(id, &queue, pri);
+        self.jobs.insert(
+            id,
+            Job {
+                queue,
+                pri,
+                body,
+                worker: None,
+            },
+        );
+        id
+    }
+
+    fn enqueue(&mut self, id: u64, queue: &str, pri: u64) {
+        self.queues
+            .entry(String::from(queue))
+            .or_default()
+            .push((pri, Reverse(id)));
+    }
+
+    /// Assign the highest priority waiting job in any of `queues` to `client`.
+    fn get(&mut self, queues: &[String], client: u64) -> Option<Response> {
+        // First drop any stale entries from the front of each queue
+        for queue in queues {
+            if let Some(heap) = self.queues.get_mut(queue) {
+                while let Some((_, Reverse(id))) = heap.peek() {
+                    let id = *id;
+                    if self
+                        .jobs
+                        .get(&id)
+                        .map(|job| job.worker.is_none() && &job.queue == queue)
+                        .unwrap_or(false)
+                    {
+                        break;
+                    }
+                    heap.pop();
+                }
+            }
+        }
+
+        let (queue, (_, Reverse(id))) = queues
+            .iter()
+            .filter_map(|queue| {
+                self.queues
+                    .get(queue)
+                    .and_then(|heap| heap.peek())
+                    .map(|top| (queue, *top))
+            })
+            .max_by_key(|(_, top)| *top)?;
+        self.queues.get_mut(queue).and_then(|heap| heap.pop());
+
+        let job = self.jobs.get_mut(&id)?;
+        job.worker = Some(client);
+        Some(Response {
+            id: Some(id),
+            job: Some(job.body.clone()),
+            pri: Some(job.pri),
+            queue: Some(job.queue.clone()),
+            ..Response::ok()
+        })
+    }
+
+    fn delete(&mut self, id: u64) -> bool {
+        // Any entry left in a queue is now stale, and will be cleaned up by get()
+        self.jobs.remove(&id).is_some()
+    }
+
+    fn abort(&mut self, id: u64, client: u64) -> Result<bool, String> {
+        let Some(job) = self.jobs.get_mut(&id) else {
+            return Ok(false);
+        };
+        if job.worker != Some(client) {
+            return Err(format!("Not working on job {}", id));
+        }
+        job.worker = None;
+        let (queue, pri) = (job.queue.clone(), job.pri);
+        self.enqueue(id, &queue, pri);
+        Ok(true)
+    }
+
+    /// Return every job `client` was working on to its queue. Returns how many there were.
+    fn abort_all(&mut self, client: u64) -> usize {
+        let ids: Vec<u64> = self
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.worker == Some(client))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids.iter() {
+            let _ = self.abort(*id, client);
+        }
+        ids.len()
+    }
+}
+
+/// The job centre is shared by every connection to one server. Clients waiting for a job block
+/// on the condvar, which is notified whenever a job is put back into any queue.
+struct SharedJobCentre {
+    centre: Mutex<JobCentre>,
+    job_available: Condvar,
+    next_client_id: AtomicU64,
+}
+
+impl SharedJobCentre {
+    // The jobs are the source of truth, and queue entries are allowed to be stale, so a handler
+    // panicking part way through an update leaves nothing we can't carry on with
+    fn centre(&self) -> MutexGuard<'_, JobCentre> {
+        self.centre.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn new() -> Self {
+        Self {
+            centre: Mutex::new(JobCentre::new()),
+            job_available: Condvar::new(),
+            next_client_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns `None` if the client left while waiting for a job, which `client_gone` tells us.
+    fn handle_request(
+        &self,
+        request: Request,
+        client: u64,
+        client_gone: &dyn Fn() -> bool,
+    ) -> Option<Response> {
+        let mut centre = self.centre();
+        let response = match request {
+            Request::Put { queue, job, pri } => {
+                let id = centre.put(queue, pri, job);
+                self.job_available.notify_all();
+                Response {
+                    id: Some(id),
+                    ..Response::ok()
+                }
+            }
+            Request::Get { queues, wait } => loop {
+                if let Some(response) = centre.get(&queues, client) {
+                    break response;
+                }
+                if !wait {
+                    break Response::no_job();
+                }
+                // Checked before every get, so we don't hand a job to a client that has gone
+                (centre, _) = self
+                    .job_available
+                    .wait_timeout(centre, WAITING_CLIENT_CHECK_INTERVAL)
+                    .unwrap_or_else(PoisonError::into_inner);
+                if client_gone() {
+                    return None;
+                }
+            },
+            Request::Delete { id } => {
+                if centre.delete(id) {
+                    Response::ok()
+                } else {
+                    Response::no_job()
+                }
+            }
+            Request::Abort { id } => match centre.abort(id, client) {
+                Ok(true) => {
+                    self.job_available.notify_all();
+                    Response::ok()
+                }
+                Ok(false) => Response::no_job(),
+                Err(message) => Response::error(message),
+            },
+        };
+        Some(response)
+    }
+
+    fn disconnect(&self, client: u64) -> usize {
+        let aborted = self.centre().abort_all(client);
+        if aborted > 0 {
+            self.job_available.notify_all();
+        }
+        aborted
+    }
+}
+
+impl ConnectionHandler<Stream> for SharedJobCentre {
+    fn handle(
+        &self,
+        stream: &mut Stream,
+        remote_address: &PeerAddress,
+        _shutdown_signal: &ShutdownSignal,
+    ) -> Result<(), Box<dyn Error>> {
+        let client = self.next_client_id.fetch_add(1, Ordering::SeqCst);
+        let result = handle_requests(stream, self, client);
+        let aborted = self.disconnect(client);
+        if aborted > 0 {
+            log::debug!(
+                remote_address = as_display!(remote_address),
+                aborted = as_display!(aborted);
+                "Returned jobs to their queues after disconnect"
+            );
+        }
+        result
+    }
+}
+
+pub(crate) fn serve(ctx: &Context) -> Result<ShutdownSignal, Box<dyn Error>> {
+    StreamServer::new().serve(ctx, SharedJobCentre::new())
+}
+
+fn handle_requests(
+    stream: &mut Stream,
+    centre: &SharedJobCentre,
+    client: u64,
+) -> Result<(), Box<dyn Error>> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let peer = stream.try_clone()?;
+    let client_gone = || peer.is_closed_by_peer();
+
+    for line in reader.lines() {
+        let line = line?;
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                log::debug!(client = as_display!(client), request = as_debug!(request); "Got request");
+                match centre.handle_request(request, client, &client_gone) {
+                    Some(response) => response,
+                    None => {
+                        log::debug!(client = as_display!(client); "Client left while waiting for a job");
+                        break;
+                    }
+                }
+            }
+            Err(e) => Response::error(e.to_string()),
+        };
+        let mut response = serde_json::to_vec(&response)?;
+        response.push(b'\n');
+        stream.write_all(&response)?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn help(ctx: &Context) -> Result<(), Box<dyn Error>> {
+    println!("Usage: {} job_centre", ctx.program_name);
+    Ok(())
+}
+
+pub(crate) fn scenarios() -> Vec<Scenario> {
+    // Queues outlive the checker's connections, so use ones the server can't have seen before
+    let run_id = crate::check::run_id();
+    let queue = format!("check{}", run_id);
+    let put = |pri: u64| {
+        format!(
+            "{{\"request\":\"put\",\"queue\":\"{}\",\"job\":{{\"pri\":{}}},\"pri\":{}}}\n",
+            queue, pri, pri
+        )
+    };
+    let get = |wait: bool| {
+        format!(
+            "{{\"request\":\"get\",\"queues\":[\"{}\"],\"wait\":{}}}\n",
+            queue, wait
+        )
+    };
+    let job_from_queue = format!("\"queue\":\"{}\"", queue);
+    vec![
+        Scenario {
+            name: "rejects invalid requests",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, "{\"request\":\"juggle\"}\n"),
+                Step::expect_matching(0, "{", "\"error\""),
+                Step::send(0, "not json\n"),
+                Step::expect_matching(0, "{", "\"error\""),
+            ],
+        },
+        Scenario {
+            name: "reports when there is no job",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, get(false)),
+                Step::ExpectJsonLine(0, serde_json::json!({"status": "no-job"})),
+            ],
+        },
+        Scenario {
+            name: "gives out the highest priority job first",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, put(1)),
+                Step::expect_matching(0, "{", "\"ok\""),
+                Step::send(0, put(3)),
+                Step::expect_matching(0, "{", "\"ok\""),
+                Step::send(1, get(false)),
+                Step::expect_matching(1, "{", "\"pri\":3"),
+                Step::send(1, get(false)),
+                Step::expect_matching(1, "{", "\"pri\":1"),
+                Step::send(1, get(false)),
+                Step::ExpectJsonLine(1, serde_json::json!({"status": "no-job"})),
+            ],
+        },
+        Scenario {
+            name: "returns jobs when their worker disconnects",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, put(2)),
+                Step::expect_matching(0, "{", "\"ok\""),
+                Step::send(1, get(false)),
+                Step::expect_matching(1, "{", &job_from_queue),
+                Step::Disconnect(1),
+                Step::send(2, get(true)),
+                Step::expect_matching(2, "{", &job_from_queue),
+            ],
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(value: serde_json::Value) -> Request {
+        serde_json::from_value(value).expect("Request should be valid")
+    }
+
+    /// Handle `request` for a client which never leaves.
+    fn handle(centre: &SharedJobCentre, request: Request, client: u64) -> Response {
+        centre
+            .handle_request(request, client, &|| false)
+            .expect("The client never leaves")
+    }
+
+    fn put(centre: &SharedJobCentre, queue: &str, pri: u64) -> u64 {
+        handle(
+            centre,
+            request(json!({"request": "put", "queue": queue, "job": {"pri": pri}, "pri": pri})),
+            0,
+        )
+        .id
+        .expect("Put should return an id")
+    }
+
+    fn get(centre: &SharedJobCentre, queues: &[&str], client: u64) -> Response {
+        handle(
+            centre,
+            request(json!({"request": "get", "queues": queues})),
+            client,
+        )
+    }
+
+    #[test]
+    fn gets_highest_priority_job_across_queues() {
+        let centre = SharedJobCentre::new();
+        put(&centre, "q1", 1);
+        let best = put(&centre, "q2", 3);
+        put(&centre, "q1", 2);
+        put(&centre, "q3", 100);
+
+        let response = get(&centre, &["q1", "q2"], 1);
+        assert_eq!(response.id, Some(best));
+        assert_eq!(response.queue.as_deref(), Some("q2"));
+        assert_eq!(response.pri, Some(3));
+        assert_eq!(get(&centre, &["q1", "q2"], 1).pri, Some(2));
+        assert_eq!(get(&centre, &["q1", "q2"], 1).pri, Some(1));
+        assert_eq!(get(&centre, &["q1", "q2"], 1), Response::no_job());
+    }
+
+    #[test]
+    fn deleted_jobs_are_never_handed_out() {
+        let centre = SharedJobCentre::new();
+        let id = put(&centre, "q", 1);
+        let delete = request(json!({"request": "delete", "id": id}));
+        assert_eq!(handle(&centre, delete, 0), Response::ok());
+        assert_eq!(get(&centre, &["q"], 1), Response::no_job());
+        let delete = request(json!({"request": "delete", "id": id}));
+        assert_eq!(handle(&centre, delete, 0), Response::no_job());
+    }
+
+    #[test]
+    fn only_the_worker_can_abort_a_job() {
+        let centre = SharedJobCentre::new();
+        let id = put(&centre, "q", 1);
+        assert_eq!(get(&centre, &["q"], 1).id, Some(id));
+
+        let abort = || request(json!({"request": "abort", "id": id}));
+        assert_eq!(handle(&centre, abort(), 2).status, Status::Error);
+        assert_eq!(handle(&centre, abort(), 1), Response::ok());
+        assert_eq!(get(&centre, &["q"], 2).id, Some(id));
+    }
+
+    #[test]
+    fn jobs_return_to_their_queue_on_disconnect() {
+        let centre = SharedJobCentre::new();
+        let id = put(&centre, "q", 1);
+        assert_eq!(get(&centre, &["q"], 1).id, Some(id));
+        assert_eq!(get(&centre, &["q"], 2), Response::no_job());
+        assert_eq!(centre.disconnect(1), 1);
+        assert_eq!(get(&centre, &["q"], 2).id, Some(id));
+    }
+
+    #[test]
+    fn waiting_get_blocks_until_a_job_arrives() {
+        let centre = std::sync::Arc::new(SharedJobCentre::new());
+        let waiter = {
+            let centre = centre.clone();
+            std::thread::spawn(move || {
+                handle(
+                    &centre,
+                    request(json!({"request": "get", "queues": ["q"], "wait": true})),
+                    1,
+                )
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let id = put(&centre, "q", 1);
+        assert_eq!(waiter.join().unwrap().id, Some(id));
+    }
+
+    #[test]
+    fn waiting_get_gives_up_when_the_client_leaves() {
+        let centre = std::sync::Arc::new(SharedJobCentre::new());
+        let gone = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let waiter = {
+            let (centre, gone) = (centre.clone(), gone.clone());
+            std::thread::spawn(move || {
+                centre.handle_request(
+                    request(json!({"request": "get", "queues": ["q"], "wait": true})),
+                    1,
+                    &|| gone.load(Ordering::SeqCst),
+                )
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        gone.store(true, Ordering::SeqCst);
+        assert_eq!(waiter.join().unwrap(), None);
+
+        // Nobody took the job, so it's still there
+        let id = put(&centre, "q", 1);
+        assert_eq!(get(&centre, &["q"], 2).id, Some(id));
+    }
+
+    #[test]
+    fn servers_have_separate_job_centres() {
+        let serve = || {
+            let (ctx, address) = crate::scaffolding::test_context("job_centre");
+            StreamServer::new()
+                .serve(&ctx, SharedJobCentre::new())
+                .expect("Server should start");
+            address
+        };
+        let (first, second) = (serve(), serve());
+        let exchange = |address, line: &str| {
+            let mut stream = std::net::TcpStream::connect(address).unwrap();
+            stream.write_all(line.as_bytes()).unwrap();
+            let mut response = String::new();
+            BufReader::new(stream).read_line(&mut response).unwrap();
+            response
+        };
+
+        let put = "{\"request\":\"put\",\"queue\":\"q\",\"job\":{},\"pri\":1}\n";
+        assert_eq!(exchange(first, put), "{\"status\":\"ok\",\"id\":0}\n");
+        let get = "{\"request\":\"get\",\"queues\":[\"q\"]}\n";
+        assert_eq!(exchange(second, get), "{\"status\":\"no-job\"}\n");
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        for line in [
+            r#"{"request": "put", "queue": "q", "job": 1, "pri": 1}"#,
+            r#"{"request": "put", "queue": "q", "job": {}, "pri": -1}"#,
+            r#"{"request": "get"}"#,
+            r#"{"request": "nope"}"#,
+            r#"not json"#,
+        ] {
+            assert!(serde_json::from_str::<Request>(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn serializes_responses() {
+        assert_eq!(
+            serde_json::to_string(&Response::no_job()).unwrap(),
+            r#"{"status":"no-job"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Response {
+                id: Some(12345),
+                ..Response::ok()
+            })
+            .unwrap(),
+            r#"{"status":"ok","id":12345}"#
+        );
+    }
+}
@@ -0,0 +1,468 @@
+//! Limits on who can connect and how often, applied to every connection before it's handled.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    error::Error,
+    fmt::Display,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The most rate limit buckets we keep. Past this, the least recently used is forgotten, so its
+/// address starts afresh with a full bucket.
+const MAX_BUCKETS: usize = 4096;
+
+/// An IP network, like `10.0.0.0/8` or `2001:db8::/32`. A bare address is a network of one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Cidr {
+    network: IpAddr,
+    prefix_length: u8,
+}
+
+impl Cidr {
+    pub(crate) fn contains(&self, address: IpAddr) -> bool {
+        match (self.network, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => prefix_matches(
+                network.to_bits().into(),
+                address.to_bits().into(),
+                32,
+                self.prefix_length,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(address)) => prefix_matches(
+                network.to_bits(),
+                address.to_bits(),
+                128,
+                self.prefix_length,
+            ),
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, address: u128, bits: u8, prefix_length: u8) -> bool {
+    let ignored = bits - prefix_length;
+    ignored == bits || network >> ignored == address >> ignored
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid CIDR '{}', expected for example 10.0.0.0/8", s);
+        let (network, prefix_length) = match s.split_once('/') {
+            Some((network, prefix_length)) => (network, Some(prefix_length)),
+            None => (s, None),
+        };
+        let network = network
+            .parse::<IpAddr>()
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_length = match prefix_length {
+            Some(prefix_length) => prefix_length.parse().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix_length > bits {
+            return Err(invalid());
+        }
+        Ok(Self {
+            network,
+            prefix_length,
+        })
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_length)
+    }
+}
+
+/// New connections allowed from each source IP: `burst` straight away, then `per_second`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RateLimit {
+    pub(crate) per_second: f64,
+    pub(crate) burst: f64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct LimitsConfig {
+    /// The most connections open at once from one source IP
+    pub(crate) max_connections_per_ip: Option<usize>,
+    pub(crate) rate: Option<RateLimit>,
+    /// If not empty, only these networks may connect
+    pub(crate) allow: Vec<Cidr>,
+    /// These networks may not connect, even if they're allowed
+    pub(crate) deny: Vec<Cidr>,
+}
+
+impl LimitsConfig {
+    /// Read MAX_CONNECTIONS_PER_IP, CONNECTION_RATE (new connections per second per IP),
+    /// CONNECTION_BURST (defaults to the rate), and comma-separated ALLOW_CIDRS and DENY_CIDRS
+    /// from the environment. Anything unset isn't limited.
+    pub(crate) fn from_env() -> Result<Self, Box<dyn Error>> {
+        let var = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let mut config = Self::default();
+        if let Some(max_connections_per_ip) = var("MAX_CONNECTIONS_PER_IP") {
+            config.max_connections_per_ip = Some(max_connections_per_ip.parse()?);
+        }
+        if let Some(per_second) = var("CONNECTION_RATE") {
+            let per_second: f64 = per_second.parse()?;
+            let burst = match var("CONNECTION_BURST") {
+                Some(burst) => burst.parse()?,
+                None => per_second.max(1.0),
+            };
+            if !(per_second > 0.0 && burst >= 1.0) {
+                return Err(String::from(
+                    "CONNECTION_RATE must be positive and CONNECTION_BURST at least 1",
+                )
+                .into());
+            }
+            config.rate = Some(RateLimit { per_second, burst });
+        }
+        if let Some(allow) = var("ALLOW_CIDRS") {
+            config.allow = parse_cidrs(&allow)?;
+        }
+        if let Some(deny) = var("DENY_CIDRS") {
+            config.deny = parse_cidrs(&deny)?;
+        }
+        Ok(config)
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+fn parse_cidrs(list: &str) -> Result<Vec<Cidr>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|cidr| !cidr.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Why a connection was turned away.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LimitExceeded {
+    Denied,
+    NotAllowed,
+    TooManyConnections,
+    RateLimited,
+}
+
+impl LimitExceeded {
+    /// A short name for the reason, for logs and metric labels.
+    pub(crate) fn reason(&self) -> &'static str {
+        match self {
+            LimitExceeded::Denied => "denied",
+            LimitExceeded::NotAllowed => "not_allowed",
+            LimitExceeded::TooManyConnections => "too_many_connections",
+            LimitExceeded::RateLimited => "rate_limited",
+        }
+    }
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LimitExceeded::Denied => "source address is denied",
+            LimitExceeded::NotAllowed => "source address is not allowed",
+            LimitExceeded::TooManyConnections => "too many connections from source address",
+            LimitExceeded::RateLimited => "too many new connections from source address",
+        })
+    }
+}
+
+impl Error for LimitExceeded {}
+
+/// Applies a [`LimitsConfig`] to the connections of one server.
+pub(crate) struct Limiter {
+    config: LimitsConfig,
+    state: Mutex<LimiterState>,
+}
+
+#[derive(Default)]
+struct LimiterState {
+    open_connections: HashMap<IpAddr, usize>,
+    buckets: HashMap<IpAddr, Bucket>,
+    /// Every bucket's address by when it was last used, oldest first
+    buckets_by_age: BTreeSet<(Instant, IpAddr)>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Counts towards its source IP's open connections until dropped.
+pub(crate) struct ConnectionPermit {
+    limiter: Arc<Limiter>,
+    address: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state();
+        if let Some(count) = state.open_connections.get_mut(&self.address) {
+            *count -= 1;
+            if *count == 0 {
+                state.open_connections.remove(&self.address);
+            }
+        }
+    }
+}
+
+impl Limiter {
+    pub(crate) fn new(config: LimitsConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            state: Mutex::new(LimiterState::default()),
+        })
+    }
+
+    /// Check whether a connection from `address` may be handled. The permit, if any, must be
+    /// held until the connection closes. There are no permits for connections without an IP
+    /// address, like those over Unix domain sockets, which are never limited.
+    pub(crate) fn admit(
+        self: &Arc<Self>,
+        address: Option<IpAddr>,
+    ) -> Result<Option<ConnectionPermit>, LimitExceeded> {
+        self.admit_at(address, Instant::now())
+    }
+
+    fn admit_at(
+        self: &Arc<Self>,
+        address: Option<IpAddr>,
+        now: Instant,
+    ) -> Result<Option<ConnectionPermit>, LimitExceeded> {
+        let Some(address) = address.map(|address| address.to_canonical()) else {
+            return Ok(None);
+        };
+        if self.config.is_unlimited() {
+            return Ok(None);
+        }
+        if self.config.deny.iter().any(|cidr| cidr.contains(address)) {
+            return Err(LimitExceeded::Denied);
+        }
+        if !self.config.allow.is_empty()
+            && !self.config.allow.iter().any(|cidr| cidr.contains(address))
+        {
+            return Err(LimitExceeded::NotAllowed);
+        }
+
+        let mut state = self.state();
+        let open_connections = state.open_connections.get(&address).copied().unwrap_or(0);
+        if matches!(self.config.max_connections_per_ip, Some(max) if open_connections >= max) {
+            return Err(LimitExceeded::TooManyConnections);
+        }
+        if let Some(rate) = self.config.rate {
+            if !state.take_token(address, rate, now) {
+                return Err(LimitExceeded::RateLimited);
+            }
+        }
+        *state.open_connections.entry(address).or_insert(0) += 1;
+        Ok(Some(ConnectionPermit {
+            limiter: self.clone(),
+            address,
+        }))
+    }
+
+    /// Check a datagram from `address` against the allow and deny lists; the other limits are
+    /// about connections, so don't apply.
+    pub(crate) fn admit_datagram(&self, address: IpAddr) -> Result<(), LimitExceeded> {
+        let address = address.to_canonical();
+        if self.config.deny.iter().any(|cidr| cidr.contains(address)) {
+            return Err(LimitExceeded::Denied);
+        }
+        if !self.config.allow.is_empty()
+            && !self.config.allow.iter().any(|cidr| cidr.contains(address))
+        {
+            return Err(LimitExceeded::NotAllowed);
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, LimiterState> {
+        self.state.lock().expect("Limiter should not be poisoned")
+    }
+}
+
+impl LimiterState {
+    /// Take a token from the bucket for `address`, returning false if it's empty.
+    fn take_token(&mut self, address: IpAddr, rate: RateLimit, now: Instant) -> bool {
+        // Buckets unused for this long are full again, so no different from new ones. Only the
+        // oldest are checked, so each bucket costs us once however many connections we see.
+        let refill_time = Duration::from_secs_f64(rate.burst / rate.per_second);
+        let needs_room = !self.buckets.contains_key(&address);
+        while let Some(&(last_used, oldest)) = self.buckets_by_age.first() {
+            if (self.buckets.len() < MAX_BUCKETS || !needs_room)
+                && now.saturating_duration_since(last_used) < refill_time
+            {
+                break;
+            }
+            self.buckets_by_age.pop_first();
+            self.buckets.remove(&oldest);
+        }
+
+        let bucket = self.buckets.entry(address).or_insert(Bucket {
+            tokens: rate.burst,
+            refilled_at: now,
+        });
+        self.buckets_by_age.remove(&(bucket.refilled_at, address));
+        self.buckets_by_age.insert((now, address));
+        bucket.tokens = bucket.refilled(rate, now);
+        bucket.refilled_at = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, rate: RateLimit, now: Instant) -> f64 {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        (self.tokens + elapsed * rate.per_second).min(rate.burst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_and_matches_cidrs() {
+        let network: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("10.2.0.1".parse().unwrap()));
+        assert!(!network.contains("::1".parse().unwrap()));
+
+        let network: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(network.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!network.contains("2001:db9::1".parse().unwrap()));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains("192.0.2.1".parse().unwrap()));
+        let single: Cidr = "192.0.2.1".parse().unwrap();
+        assert_eq!(single.to_string(), "192.0.2.1/32");
+        assert!(!single.contains("192.0.2.2".parse().unwrap()));
+
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0/8", "10.0.0.0/", "nonsense"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn applies_allow_and_deny_lists() {
+        let limiter = Limiter::new(LimitsConfig {
+            allow: parse_cidrs("10.0.0.0/8, 192.0.2.0/24").unwrap(),
+            deny: parse_cidrs("10.9.0.0/16").unwrap(),
+            ..LimitsConfig::default()
+        });
+        assert!(limiter.admit(ip("10.1.1.1")).is_ok());
+        assert!(limiter.admit(ip("192.0.2.7")).is_ok());
+        assert_eq!(
+            limiter.admit(ip("10.9.1.1")).err(),
+            Some(LimitExceeded::Denied)
+        );
+        assert_eq!(
+            limiter.admit(ip("198.51.100.1")).err(),
+            Some(LimitExceeded::NotAllowed)
+        );
+        assert_eq!(
+            limiter.admit_datagram("10.9.1.1".parse().unwrap()),
+            Err(LimitExceeded::Denied)
+        );
+        assert!(limiter.admit(None).unwrap().is_none());
+    }
+
+    #[test]
+    fn caps_open_connections_per_ip_until_permits_are_dropped() {
+        let limiter = Limiter::new(LimitsConfig {
+            max_connections_per_ip: Some(2),
+            ..LimitsConfig::default()
+        });
+        let first = limiter.admit(ip("192.0.2.1")).unwrap();
+        let _second = limiter.admit(ip("::ffff:192.0.2.1")).unwrap();
+        assert_eq!(
+            limiter.admit(ip("192.0.2.1")).err(),
+            Some(LimitExceeded::TooManyConnections)
+        );
+        assert!(limiter.admit(ip("192.0.2.2")).is_ok());
+        drop(first);
+        assert!(limiter.admit(ip("192.0.2.1")).is_ok());
+    }
+
+    #[test]
+    fn rate_limits_new_connections_with_a_token_bucket() {
+        let limiter = Limiter::new(LimitsConfig {
+            rate: Some(RateLimit {
+                per_second: 2.0,
+                burst: 3.0,
+            }),
+            ..LimitsConfig::default()
+        });
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.admit_at(ip("192.0.2.1"), start).is_ok());
+        }
+        assert_eq!(
+            limiter.admit_at(ip("192.0.2.1"), start).err(),
+            Some(LimitExceeded::RateLimited)
+        );
+        assert!(limiter.admit_at(ip("192.0.2.2"), start).is_ok());
+
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.admit_at(ip("192.0.2.1"), later).is_ok());
+        assert_eq!(
+            limiter.admit_at(ip("192.0.2.1"), later).err(),
+            Some(LimitExceeded::RateLimited)
+        );
+        // Refills no further than the burst
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.admit_at(ip("192.0.2.1"), much_later).is_ok());
+        }
+        assert!(limiter.admit_at(ip("192.0.2.1"), much_later).is_err());
+    }
+
+    #[test]
+    fn keeps_a_bounded_number_of_buckets() {
+        // An hour to refill
+        let limiter = Limiter::new(LimitsConfig {
+            rate: Some(RateLimit {
+                per_second: 1.0 / 3600.0,
+                burst: 1.0,
+            }),
+            ..LimitsConfig::default()
+        });
+        let address = |i: usize| Some(IpAddr::from([10, 0, (i / 256) as u8, (i % 256) as u8]));
+        let start = Instant::now();
+        let at = |i: usize| start + Duration::from_millis(i as u64);
+        for i in 0..=MAX_BUCKETS {
+            drop(limiter.admit_at(address(i), at(i)).unwrap());
+        }
+        assert_eq!(limiter.state().buckets.len(), MAX_BUCKETS);
+        // The least recently used was forgotten, so it starts afresh; the next oldest makes room
+        let now = at(MAX_BUCKETS + 1);
+        assert!(limiter.admit_at(address(0), now).is_ok());
+        assert!(limiter.admit_at(address(2), now).is_err());
+        assert_eq!(limiter.state().buckets_by_age.len(), MAX_BUCKETS);
+
+        // Once they've all refilled, they're forgotten as soon as anyone connects
+        drop(limiter.admit_at(ip("192.0.2.1"), now + Duration::from_secs(3600)));
+        assert_eq!(limiter.state().buckets.len(), 1);
+        assert_eq!(limiter.state().buckets_by_age.len(), 1);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::check::{Scenario, Step, Transport};
+use crate::lrcp::{LrcpServer, LrcpStream};
+use crate::scaffolding::Context;
+use crate::server::Server as _;
+use crate::server::{PeerAddress, ShutdownSignal};
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+
+pub(crate) fn serve(ctx: &Context) -> Result<ShutdownSignal, Box<dyn Error>> {
+    LrcpServer::new().serve(ctx, handle)
+}
+
+fn handle(stream: &mut LrcpStream, _remote_address: &PeerAddress) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(&*stream);
+    let mut writer = &*stream;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        reader.read_until(b'\n', &mut line)?;
+        if line.last() != Some(&b'\n') {
+            // The session closed, possibly part way through a line which we never reverse
+            return Ok(());
+        }
+        line.pop();
+        line.reverse();
+        line.push(b'\n');
+        writer.write_all(&line)?;
+    }
+}
+
+pub(crate) fn help(ctx: &Context) -> Result<(), Box<dyn Error>> {
+    println!("Usage: {} line_reversal", ctx.program_name);
+    Ok(())
+}
+
+pub(crate) fn scenarios() -> Vec<Scenario> {
+    // Session ids are global to the server, so use one it can't have seen before
+    let session = crate::check::run_id();
+    vec![Scenario {
+        name: "reverses a line over LRCP",
+        transport: Transport::Udp,
+        steps: vec![
+            Step::send(0, format!("/connect/{}/", session)),
+            Step::expect(0, format!("/ack/{}/0/", session)),
+            Step::send(0, format!("/data/{}/0/hello\n/", session)),
+            Step::ExpectUnordered(
+                0,
+                vec![
+                    format!("/ack/{}/6/", session).into_bytes(),
+                    format!("/data/{}/0/olleh\n/", session).into_bytes(),
+                ],
+            ),
+            Step::send(0, format!("/ack/{}/6/", session)),
+            Step::send(0, format!("/data/{}/6/a\\/b\n/", session)),
+            Step::ExpectUnordered(
+                0,
+                vec![
+                    format!("/ack/{}/10/", session).into_bytes(),
+                    format!("/data/{}/6/b\\/a\n/", session).into_bytes(),
+                ],
+            ),
+            Step::send(0, format!("/ack/{}/10/", session)),
+            Step::send(0, format!("/close/{}/", session)),
+            Step::expect(0, format!("/close/{}/", session)),
+        ],
+    }]
+}
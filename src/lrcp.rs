@@ -0,0 +1,908 @@
+//! The Line Reversal Control Protocol: reliable, ordered byte streams over UDP.
+//!
+//! Each LRCP session is handed to the handler as an [`LrcpStream`], which implements
+//! [`Read`] and [`Write`] much like a `TcpStream` does.
+
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::{SocketAddr, UdpSocket},
+    os::fd::OwnedFd,
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Condvar, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{as_debug, as_display};
+
+use crate::metrics::ConnectionCounters;
+use crate::server::{ForceCloser, PeerAddress, Server, ShutdownSignal, UNIX_PREFIX};
+use crate::socket_activation;
+
+/// All LRCP messages must be smaller than this.
+const MAX_MESSAGE_SIZE: usize = 1000;
+/// Numeric fields must be smaller than this.
+const MAX_NUMERIC_VALUE: u32 = 2147483648;
+const RETRANSMISSION_TIMEOUT: Duration = Duration::from_secs(3);
+const SESSION_EXPIRY_TIMEOUT: Duration = Duration::from_secs(60);
+const TIMER_INTERVAL: Duration = Duration::from_millis(100);
+/// How long dropping a stream waits for the peer to acknowledge everything sent on it
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the server pump waits for a new session before checking for shutdown
+const PUMP_TIMEOUT: Duration = Duration::from_millis(100);
+
+// --- Wire format ---
+
+#[derive(Debug, PartialEq)]
+enum Message {
+    Connect {
+        session: u32,
+    },
+    Data {
+        session: u32,
+        pos: u32,
+        data: Vec<u8>,
+    },
+    Ack {
+        session: u32,
+        length: u32,
+    },
+    Close {
+        session: u32,
+    },
+}
+
+impl Message {
+    /// Parse a datagram. Anything invalid yields `None`, and must be silently ignored.
+    fn parse(datagram: &[u8]) -> Option<Self> {
+        if datagram.len() >= MAX_MESSAGE_SIZE {
+            return None;
+        }
+        let fields = split_fields(datagram)?;
+        match fields.as_slice() {
+            [kind, session] if kind == b"connect" => Some(Message::Connect {
+                session: parse_number(session)?,
+            }),
+            [kind, session, pos, data] if kind == b"data" => Some(Message::Data {
+                session: parse_number(session)?,
+                pos: parse_number(pos)?,
+                data: data.clone(),
+            }),
+            [kind, session, length] if kind == b"ack" => Some(Message::Ack {
+                session: parse_number(session)?,
+                length: parse_number(length)?,
+            }),
+            [kind, session] if kind == b"close" => Some(Message::Close {
+                session: parse_number(session)?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Message::Connect { session } => format!("/connect/{}/", session).into_bytes(),
+            Message::Data { session, pos, data } => {
+                let mut bytes = format!("/data/{}/{}/", session, pos).into_bytes();
+                bytes.extend_from_slice(&escape(data));
+                bytes.push(b'/');
+                bytes
+            }
+            Message::Ack { session, length } => {
+                format!("/ack/{}/{}/", session, length).into_bytes()
+            }
+            Message::Close { session } => format!("/close/{}/", session).into_bytes(),
+        }
+    }
+}
+
+/// Split `/a/b/c/` into `[a, b, c]`, unescaping `\/` and `\\` within each field.
+/// Returns `None` unless the datagram both starts and ends with an unescaped `/`.
+fn split_fields(datagram: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut bytes = datagram.strip_prefix(b"/")?.iter();
+    let mut fields = Vec::new();
+    let mut field = Vec::new();
+    let mut terminated = false;
+    while let Some(byte) = bytes.next() {
+        terminated = false;
+        match byte {
+            b'\\' => field.push(*bytes.next()?),
+            b'/' => {
+                fields.push(std::mem::take(&mut field));
+                terminated = true;
+            }
+            other => field.push(*other),
+        }
+    }
+    if terminated {
+        Some(fields)
+    } else {
+        None
+    }
+}
+
+fn parse_number(field: &[u8]) -> Option<u32> {
+    if field.is_empty() || !field.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(field)
+        .ok()?
+        .parse::<u32>()
+        .ok()
+        .filter(|n| *n < MAX_NUMERIC_VALUE)
+}
+
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for byte in data {
+        if *byte == b'/' || *byte == b'\\' {
+            escaped.push(b'\\');
+        }
+        escaped.push(*byte);
+    }
+    escaped
+}
+
+// --- Sessions ---
+
+struct SessionState {
+    /// Data received in order but not yet read by the handler
+    unread: VecDeque<u8>,
+    /// Total number of bytes received in order
+    received_length: u32,
+    /// Every byte the handler has ever written
+    sent: Vec<u8>,
+    /// Total number of bytes the peer has acknowledged
+    acked_length: u32,
+    last_transmitted_at: Instant,
+    /// When the oldest unacknowledged data was first sent, if there is any
+    unacked_since: Option<Instant>,
+    closed: bool,
+}
+
+struct Session {
+    id: u32,
+    peer: SocketAddr,
+    state: Mutex<SessionState>,
+    /// Notified when data arrives, data is acknowledged, or the session closes
+    changed: Condvar,
+}
+
+impl Session {
+    fn new(id: u32, peer: SocketAddr) -> Self {
+        Self {
+            id,
+            peer,
+            state: Mutex::new(SessionState {
+                unread: VecDeque::new(),
+                received_length: 0,
+                sent: Vec::new(),
+                acked_length: 0,
+                last_transmitted_at: Instant::now(),
+                unacked_since: None,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SessionState> {
+        self.state.lock().expect("Session should not be poisoned")
+    }
+}
+
+/// State shared between the receiver thread, the timer thread and every stream.
+struct Transport {
+    socket: UdpSocket,
+    sessions: Mutex<HashMap<u32, Arc<Session>>>,
+    /// The server's, once it's serving; the receiver and timer threads stop when it shuts down
+    shutdown_signal: OnceLock<ShutdownSignal>,
+}
+
+impl Transport {
+    fn is_shut_down(&self) -> bool {
+        self.shutdown_signal
+            .get()
+            .is_some_and(ShutdownSignal::is_shutdown_complete)
+    }
+
+    fn send(&self, peer: SocketAddr, message: &Message) {
+        if let Err(e) = self.socket.send_to(&message.to_bytes(), peer) {
+            log::debug!(
+                peer = as_display!(peer),
+                message = as_debug!(message),
+                error = as_display!(e);
+                "Error sending LRCP message"
+            );
+        }
+    }
+
+    fn get_session(&self, id: u32) -> Option<Arc<Session>> {
+        self.sessions
+            .lock()
+            .expect("Sessions should not be poisoned")
+            .get(&id)
+            .cloned()
+    }
+
+    fn close_session(&self, session: &Session) {
+        self.sessions
+            .lock()
+            .expect("Sessions should not be poisoned")
+            .remove(&session.id);
+        let mut state = session.lock();
+        if !state.closed {
+            state.closed = true;
+            session.changed.notify_all();
+        }
+        drop(state);
+        self.send(
+            session.peer,
+            &Message::Close {
+                session: session.id,
+            },
+        );
+    }
+
+    /// Send all data from `from` onwards, split into messages small enough to be valid.
+    fn transmit(&self, session: &Session, state: &mut SessionState, from: u32) {
+        let mut pos = from as usize;
+        while pos < state.sent.len() {
+            let header_length = format!("/data/{}/{}/", session.id, pos).len();
+            // One byte for the trailing slash, and we must be strictly under the limit
+            let budget = MAX_MESSAGE_SIZE - header_length - 2;
+            let mut end = pos;
+            let mut escaped_length = 0;
+            while end < state.sent.len() {
+                let byte_length = match state.sent[end] {
+                    b'/' | b'\\' => 2,
+                    _ => 1,
+                };
+                if escaped_length + byte_length > budget {
+                    break;
+                }
+                escaped_length += byte_length;
+                end += 1;
+            }
+            self.send(
+                session.peer,
+                &Message::Data {
+                    session: session.id,
+                    pos: pos as u32,
+                    data: state.sent[pos..end].to_vec(),
+                },
+            );
+            pos = end;
+        }
+        state.last_transmitted_at = Instant::now();
+    }
+
+    fn handle_message(
+        self: &Arc<Self>,
+        message: Message,
+        peer: SocketAddr,
+        new_sessions: &Sender<(LrcpStream, SocketAddr)>,
+    ) {
+        match message {
+            Message::Connect { session } => {
+                let new_session = match self
+                    .sessions
+                    .lock()
+                    .expect("Sessions should not be poisoned")
+                    .entry(session)
+                {
+                    // Duplicate connect, so just ack it again
+                    Entry::Occupied(_) => None,
+                    Entry::Vacant(entry) => {
+                        Some(entry.insert(Arc::new(Session::new(session, peer))).clone())
+                    }
+                };
+                if let Some(new_session) = new_session {
+                    let stream = LrcpStream {
+                        transport: self.clone(),
+                        session: new_session,
+                        counters: None,
+                    };
+                    if new_sessions.send((stream, peer)).is_err() {
+                        // Nobody is accepting sessions any more; dropping the stream closes the session
+                        return;
+                    }
+                }
+                self.send(peer, &Message::Ack { session, length: 0 });
+            }
+            Message::Data { session, pos, data } => {
+                let Some(session) = self.get_session(session) else {
+                    self.send(peer, &Message::Close { session });
+                    return;
+                };
+                let mut state = session.lock();
+                if pos == state.received_length {
+                    match state.received_length.checked_add(data.len() as u32) {
+                        Some(length) if length < MAX_NUMERIC_VALUE => {
+                            state.received_length = length;
+                            state.unread.extend(data);
+                            session.changed.notify_all();
+                        }
+                        _ => {
+                            drop(state);
+                            self.close_session(&session);
+                            return;
+                        }
+                    }
+                }
+                // Either way, tell the peer how much we have, so it can retransmit if necessary
+                let length = state.received_length;
+                drop(state);
+                self.send(
+                    peer,
+                    &Message::Ack {
+                        session: session.id,
+                        length,
+                    },
+                );
+            }
+            Message::Ack { session, length } => {
+                let Some(session) = self.get_session(session) else {
+                    self.send(peer, &Message::Close { session });
+                    return;
+                };
+                let mut state = session.lock();
+                if length <= state.acked_length {
+                    // Duplicate ack, nothing to do
+                } else if length as usize > state.sent.len() {
+                    // Peer is misbehaving
+                    drop(state);
+                    self.close_session(&session);
+                } else {
+                    state.acked_length = length;
+                    session.changed.notify_all();
+                    if (length as usize) < state.sent.len() {
+                        state.unacked_since = Some(Instant::now());
+                        self.transmit(&session, &mut state, length);
+                    } else {
+                        state.unacked_since = None;
+                    }
+                }
+            }
+            Message::Close { session } => match self.get_session(session) {
+                Some(session) => self.close_session(&session),
+                None => self.send(peer, &Message::Close { session }),
+            },
+        }
+    }
+
+    /// Retransmit unacknowledged data and expire sessions whose peer has gone quiet.
+    fn tick(&self) {
+        let sessions: Vec<Arc<Session>> = self
+            .sessions
+            .lock()
+            .expect("Sessions should not be poisoned")
+            .values()
+            .cloned()
+            .collect();
+        for session in sessions {
+            let mut state = session.lock();
+            let Some(unacked_since) = state.unacked_since else {
+                continue;
+            };
+            if unacked_since.elapsed() >= SESSION_EXPIRY_TIMEOUT {
+                log::debug!(session = as_display!(session.id); "LRCP session expired");
+                drop(state);
+                self.close_session(&session);
+            } else if state.last_transmitted_at.elapsed() >= RETRANSMISSION_TIMEOUT {
+                let from = state.acked_length;
+                self.transmit(&session, &mut state, from);
+            }
+        }
+    }
+}
+
+/// One end of an LRCP session. Dropping it closes the session.
+pub(crate) struct LrcpStream {
+    transport: Arc<Transport>,
+    session: Arc<Session>,
+    /// Where to count the bytes read and written, once the server has attached its counters
+    counters: Option<Arc<ConnectionCounters>>,
+}
+
+impl Read for &LrcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.session.lock();
+        while state.unread.is_empty() && !state.closed {
+            state = self
+                .session
+                .changed
+                .wait(state)
+                .expect("Session should not be poisoned");
+        }
+        let count = buf.len().min(state.unread.len());
+        for (target, byte) in buf.iter_mut().zip(state.unread.drain(..count)) {
+            *target = byte;
+        }
+        if let Some(counters) = &self.counters {
+            counters.read(count);
+        }
+        Ok(count)
+    }
+}
+
+impl Write for &LrcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.session.lock();
+        if state.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "LRCP session closed",
+            ));
+        }
+        if state.sent.len() + buf.len() >= MAX_NUMERIC_VALUE as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "LRCP session length limit reached",
+            ));
+        }
+        let from = state.sent.len() as u32;
+        state.sent.extend_from_slice(buf);
+        if state.unacked_since.is_none() {
+            state.unacked_since = Some(Instant::now());
+        }
+        self.transport.transmit(&self.session, &mut state, from);
+        if let Some(counters) = &self.counters {
+            counters.written(buf.len());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for LrcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for LrcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Drop for LrcpStream {
+    fn drop(&mut self) {
+        // Closing throws away anything unacknowledged, so give the peer a chance to catch up
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        let mut state = self.session.lock();
+        while !state.closed && (state.acked_length as usize) < state.sent.len() {
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            state = self
+                .session
+                .changed
+                .wait_timeout(state, remaining)
+                .expect("Session should not be poisoned")
+                .0;
+        }
+        let closed = state.closed;
+        drop(state);
+        if !closed {
+            self.transport.close_session(&self.session);
+        }
+    }
+}
+
+// --- Server ---
+
+pub(crate) struct LrcpListener {
+    transport: Arc<Transport>,
+    new_sessions: Receiver<(LrcpStream, SocketAddr)>,
+}
+
+pub(crate) struct LrcpServer;
+
+impl LrcpServer {
+    pub(crate) fn new() -> Self {
+        Self {}
+    }
+
+    /// Start receiving LRCP messages on `socket`.
+    fn listen_on(socket: UdpSocket) -> io::Result<LrcpListener> {
+        // Time out now and then, so the receiver can notice shutdown
+        socket.set_read_timeout(Some(TIMER_INTERVAL))?;
+        let transport = Arc::new(Transport {
+            socket,
+            sessions: Mutex::new(HashMap::new()),
+            shutdown_signal: OnceLock::new(),
+        });
+        let (new_sessions_sender, new_sessions) = channel();
+
+        let transport_for_receiver = transport.clone();
+        thread::Builder::new()
+            .name("lrcp-receiver".into())
+            .spawn(move || {
+                let mut buffer = [0u8; MAX_MESSAGE_SIZE];
+                while !transport_for_receiver.is_shut_down() {
+                    let (bytes_read, peer) =
+                        match transport_for_receiver.socket.recv_from(&mut buffer) {
+                            Ok(result) => result,
+                            Err(e)
+                                if matches!(
+                                    e.kind(),
+                                    io::ErrorKind::Interrupted
+                                        | io::ErrorKind::WouldBlock
+                                        | io::ErrorKind::TimedOut
+                                ) =>
+                            {
+                                continue
+                            }
+                            Err(e) => {
+                                log::error!(
+                                    error = as_display!(e);
+                                    "Error receiving LRCP message; exiting receiver thread"
+                                );
+                                break;
+                            }
+                        };
+                    match Message::parse(&buffer[..bytes_read]) {
+                        Some(message) => {
+                            log::debug!(peer = as_display!(peer), message = as_debug!(message); "Got LRCP message");
+                            transport_for_receiver.handle_message(
+                                message,
+                                peer,
+                                &new_sessions_sender,
+                            );
+                        }
+                        None => {
+                            log::debug!(peer = as_display!(peer); "Ignoring invalid LRCP message");
+                        }
+                    }
+                }
+            })?;
+
+        let transport_for_timer = transport.clone();
+        thread::Builder::new()
+            .name("lrcp-timer".into())
+            .spawn(move || {
+                while !transport_for_timer.is_shut_down() {
+                    thread::sleep(TIMER_INTERVAL);
+                    transport_for_timer.tick();
+                }
+            })?;
+
+        Ok(LrcpListener {
+            transport,
+            new_sessions,
+        })
+    }
+}
+
+impl Server for LrcpServer {
+    type Listener = LrcpListener;
+    type ConnectionLike = LrcpStream;
+
+    fn get_listener(bind_address: &str) -> io::Result<Self::Listener> {
+        if bind_address.starts_with(UNIX_PREFIX) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "LRCP runs over UDP, so can't be served over Unix domain sockets",
+            ));
+        }
+        Self::listen_on(UdpSocket::bind(bind_address)?)
+    }
+
+    fn adopt_listener(socket: OwnedFd) -> io::Result<Self::Listener> {
+        Self::listen_on(socket_activation::udp_socket(socket)?)
+    }
+
+    fn pump(listener: &Self::Listener) -> io::Result<(Self::ConnectionLike, PeerAddress)> {
+        // Time out now and then, so the accept loop can notice shutdown
+        let (stream, peer) =
+            listener
+                .new_sessions
+                .recv_timeout(PUMP_TIMEOUT)
+                .map_err(|e| match e {
+                    RecvTimeoutError::Timeout => io::Error::new(io::ErrorKind::Interrupted, e),
+                    RecvTimeoutError::Disconnected => io::Error::new(io::ErrorKind::BrokenPipe, e),
+                })?;
+        Ok((stream, peer.into()))
+    }
+
+    fn watch_shutdown(listener: &Self::Listener, shutdown_signal: &ShutdownSignal) {
+        let _ = listener
+            .transport
+            .shutdown_signal
+            .set(shutdown_signal.clone());
+    }
+
+    fn get_local_address(listener: &Self::Listener) -> io::Result<PeerAddress> {
+        listener
+            .transport
+            .socket
+            .local_addr()
+            .map(PeerAddress::from)
+    }
+
+    fn reject(
+        mut connection: Self::ConnectionLike,
+        _remote_address: &PeerAddress,
+        message: Option<&str>,
+    ) -> io::Result<()> {
+        if let Some(message) = message {
+            connection.write_all(format!("{}\n", message).as_bytes())?;
+        }
+        // Close straight away rather than waiting on the peer, since this holds up accepting
+        connection.transport.close_session(&connection.session);
+        Ok(())
+    }
+
+    fn attach_counters(connection: &mut Self::ConnectionLike, counters: &Arc<ConnectionCounters>) {
+        connection.counters = Some(counters.clone());
+    }
+
+    fn force_closer(connection: &Self::ConnectionLike) -> Option<ForceCloser> {
+        let transport = connection.transport.clone();
+        let session = connection.session.clone();
+        Some(Box::new(move || transport.close_session(&session)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_valid_messages() {
+        assert_eq!(
+            Message::parse(b"/connect/1234567/"),
+            Some(Message::Connect { session: 1234567 })
+        );
+        assert_eq!(
+            Message::parse(b"/data/1234567/0/hello\n/"),
+            Some(Message::Data {
+                session: 1234567,
+                pos: 0,
+                data: b"hello\n".to_vec()
+            })
+        );
+        assert_eq!(
+            Message::parse(b"/ack/1234567/1024/"),
+            Some(Message::Ack {
+                session: 1234567,
+                length: 1024
+            })
+        );
+        assert_eq!(
+            Message::parse(b"/close/1234567/"),
+            Some(Message::Close { session: 1234567 })
+        );
+    }
+
+    #[test]
+    fn unescapes_data() {
+        assert_eq!(
+            Message::parse(br"/data/1/0/foo\/bar\\baz/"),
+            Some(Message::Data {
+                session: 1,
+                pos: 0,
+                data: br"foo/bar\baz".to_vec()
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_messages() {
+        for datagram in [
+            b"".as_slice(),
+            b"/",
+            b"connect/1/",
+            b"/connect/1",
+            b"/connect/",
+            b"/connect/1/2/",
+            b"/connect/-1/",
+            b"/connect/2147483648/",
+            b"/connect/ 1/",
+            b"/ack/1/",
+            b"/data/1/0/",
+            // unescaped slash in the data
+            b"/data/1/0/foo/bar/",
+            // the final slash is escaped, so the message isn't terminated
+            br"/data/1/0/foo\/",
+            b"/bogus/1/",
+        ] {
+            assert_eq!(
+                Message::parse(datagram),
+                None,
+                "{}",
+                String::from_utf8_lossy(datagram)
+            );
+        }
+        let mut too_long = b"/data/1/0/".to_vec();
+        too_long.resize(MAX_MESSAGE_SIZE - 1, b'a');
+        too_long.push(b'/');
+        assert_eq!(Message::parse(&too_long), None);
+    }
+
+    #[test]
+    fn escaping_round_trips() {
+        let message = Message::Data {
+            session: 1,
+            pos: 2,
+            data: br"/\/\ok".to_vec(),
+        };
+        assert_eq!(message.to_bytes(), br"/data/1/2/\/\\\/\\ok/".to_vec());
+        assert_eq!(Message::parse(&message.to_bytes()), Some(message));
+    }
+
+    fn connect(server: &UdpSocket, session: u32) -> UdpSocket {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        client
+            .send(&Message::Connect { session }.to_bytes())
+            .unwrap();
+        client
+    }
+
+    fn receive(client: &UdpSocket) -> Message {
+        let mut buffer = [0u8; MAX_MESSAGE_SIZE];
+        let length = client.recv(&mut buffer).unwrap();
+        Message::parse(&buffer[..length]).expect("Server should send valid messages")
+    }
+
+    #[test]
+    fn delivers_data_in_order_and_retransmits() {
+        let listener = LrcpServer::get_listener("127.0.0.1:0").unwrap();
+        let client = connect(&listener.transport.socket, 42);
+        assert_eq!(
+            receive(&client),
+            Message::Ack {
+                session: 42,
+                length: 0
+            }
+        );
+        let (mut stream, _) = LrcpServer::pump(&listener).unwrap();
+
+        // Out of order data is not accepted, and we are told what the server actually has
+        client
+            .send(
+                &Message::Data {
+                    session: 42,
+                    pos: 3,
+                    data: b"lo".to_vec(),
+                }
+                .to_bytes(),
+            )
+            .unwrap();
+        assert_eq!(
+            receive(&client),
+            Message::Ack {
+                session: 42,
+                length: 0
+            }
+        );
+        client
+            .send(
+                &Message::Data {
+                    session: 42,
+                    pos: 0,
+                    data: b"hel".to_vec(),
+                }
+                .to_bytes(),
+            )
+            .unwrap();
+        assert_eq!(
+            receive(&client),
+            Message::Ack {
+                session: 42,
+                length: 3
+            }
+        );
+        let mut buffer = [0u8; 16];
+        assert_eq!(stream.read(&mut buffer).unwrap(), 3);
+        assert_eq!(&buffer[..3], b"hel");
+
+        stream.write_all(b"a/b\n").unwrap();
+        let expected = Message::Data {
+            session: 42,
+            pos: 0,
+            data: b"a/b\n".to_vec(),
+        };
+        assert_eq!(receive(&client), expected);
+        // No ack, so the data is sent again
+        assert_eq!(receive(&client), expected);
+        client
+            .send(
+                &Message::Ack {
+                    session: 42,
+                    length: 4,
+                }
+                .to_bytes(),
+            )
+            .unwrap();
+
+        client
+            .send(&Message::Close { session: 42 }.to_bytes())
+            .unwrap();
+        assert_eq!(receive(&client), Message::Close { session: 42 });
+        assert_eq!(stream.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn waits_for_acks_before_closing_on_drop() {
+        let listener = LrcpServer::get_listener("127.0.0.1:0").unwrap();
+        let client = connect(&listener.transport.socket, 9);
+        receive(&client);
+        let (mut stream, _) = LrcpServer::pump(&listener).unwrap();
+        stream.write_all(b"bye\n").unwrap();
+        let dropped = thread::spawn(move || drop(stream));
+
+        assert!(matches!(receive(&client), Message::Data { session: 9, .. }));
+        thread::sleep(Duration::from_millis(200));
+        assert!(!dropped.is_finished(), "Should wait for the ack");
+        client
+            .send(
+                &Message::Ack {
+                    session: 9,
+                    length: 4,
+                }
+                .to_bytes(),
+            )
+            .unwrap();
+        assert_eq!(receive(&client), Message::Close { session: 9 });
+        dropped.join().unwrap();
+    }
+
+    #[test]
+    fn stops_background_threads_after_shutdown() {
+        let listener = LrcpServer::get_listener("127.0.0.1:0").unwrap();
+        let transport = Arc::downgrade(&listener.transport);
+        let mut shutdown_signal = ShutdownSignal::new();
+        LrcpServer::watch_shutdown(&listener, &shutdown_signal);
+        drop(listener);
+        thread::sleep(TIMER_INTERVAL * 2);
+        assert!(transport.upgrade().is_some(), "Still running");
+
+        shutdown_signal.start_shutdown();
+        shutdown_signal.complete_shutdown();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while transport.upgrade().is_some() {
+            assert!(Instant::now() < deadline, "Threads should have stopped");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn unknown_sessions_are_closed() {
+        let listener = LrcpServer::get_listener("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .connect(listener.transport.socket.local_addr().unwrap())
+            .unwrap();
+        client
+            .send(
+                &Message::Data {
+                    session: 7,
+                    pos: 0,
+                    data: b"hi".to_vec(),
+                }
+                .to_bytes(),
+            )
+            .unwrap();
+        assert_eq!(receive(&client), Message::Close { session: 7 });
+    }
+}
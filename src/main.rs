@@ -1,18 +1,42 @@
+mod admin;
+mod check;
+mod handoff;
+mod limits;
 mod logger;
+mod lrcp;
+mod metrics;
+mod pass_fds;
+mod proxy_protocol;
 #[macro_use]
 mod scaffolding;
 mod server;
+mod signals;
+mod socket_activation;
 
 use std::env;
 use std::error::Error;
+use std::net::TcpListener;
+use std::os::fd::AsFd;
 
+use handoff::HandoffGuard;
 use scaffolding::Context;
 
+/// Stop accepting, and exit once open connections have finished; see `drain <pid>`
+const DRAIN_SIGNAL: libc::c_int = libc::SIGUSR1;
+
 problem_list! {
     smoke_test
     prime_time
     means_to_an_end
     budget_chat
+    unusual_database_program
+    mob_in_the_middle
+    speed_daemon
+    line_reversal
+    insecure_sockets_layer
+    job_centre
+    voracious_code_storage
+    pest_control
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -20,22 +44,95 @@ fn main() -> Result<(), Box<dyn Error>> {
     let ctx = Context::new(
         env::args().collect(),
         env::var("BIND_ADDRESS").unwrap_or(String::from("127.0.0.1:0")),
-    );
+    )
+    .with_worker_pool(server::WorkerPoolConfig::from_env()?)
+    .with_proxy_protocol(proxy_protocol::enabled_from_env()?)
+    .with_limits(limits::LimitsConfig::from_env()?);
+    let inherited_sockets = socket_activation::from_env()?;
+    let ctx = ctx.with_inherited_sockets(inherited_sockets);
+    if let Some(ready_pipe) = handoff::ready_pipe_from_env()? {
+        ctx.handoff.set_ready_pipe(ready_pipe);
+    }
 
+    // Kept until we exit, so the metrics and admin listeners are handed over if we restart
+    let mut handoff_guards = Vec::new();
     let handler = match ctx.problem.as_deref() {
         None => handle_no_problem_specified,
-        Some("help") => match ctx.problem_arguments.get(0) {
+        Some("help") => match ctx.problem_arguments.front() {
             None => handle_basic_help,
             Some(problem) => get_problem_help(problem).unwrap_or(handle_help_for_unknown_problem),
         },
-        Some(problem) => get_problem_handler(problem).unwrap_or(handle_problem_not_found),
+        Some("check") => handle_check,
+        Some("drain") => handle_drain,
+        Some(problem) => match get_problem_handler(problem) {
+            Some(handler) => {
+                handoff_guards.extend(serve_metrics(&ctx)?);
+                handoff_guards.extend(serve_admin(&ctx)?);
+                handoff::restart_on_signal(&ctx)?;
+                drain_on_signal(&ctx)?;
+                handler
+            }
+            None => handle_problem_not_found,
+        },
     };
 
     handler(&ctx)
 }
 
+/// Serve metrics over HTTP if METRICS_ADDRESS is set, for example to `127.0.0.1:9100`. Health
+/// checks are served from the same address.
+fn serve_metrics(ctx: &Context) -> Result<Option<HandoffGuard>, Box<dyn Error>> {
+    let Ok(address) = env::var("METRICS_ADDRESS") else {
+        return Ok(None);
+    };
+    let listener = bind_or_inherit(ctx, &address, "metrics")?;
+    let handoff_guard = ctx.handoff.add_listener(listener.as_fd(), "metrics")?;
+    metrics::serve_http(listener, ctx.metrics.clone(), ctx.health.clone())?;
+    Ok(Some(handoff_guard))
+}
+
+/// Serve the admin port if ADMIN_ADDRESS is set, for example to `127.0.0.1:9101`. Clients must
+/// authenticate with ADMIN_TOKEN.
+fn serve_admin(ctx: &Context) -> Result<Option<HandoffGuard>, Box<dyn Error>> {
+    let Ok(address) = env::var("ADMIN_ADDRESS") else {
+        return Ok(None);
+    };
+    let token = env::var("ADMIN_TOKEN").unwrap_or_default();
+    if token.is_empty() {
+        return Err(String::from("ADMIN_TOKEN must be set to serve the admin port").into());
+    }
+    let listener = bind_or_inherit(ctx, &address, "admin")?;
+    let handoff_guard = ctx.handoff.add_listener(listener.as_fd(), "admin")?;
+    admin::serve(listener, token, ctx.connections.clone())?;
+    Ok(Some(handoff_guard))
+}
+
+/// Listen on the inherited socket called `name` if there is one, and otherwise on `address`.
+fn bind_or_inherit(ctx: &Context, address: &str, name: &str) -> std::io::Result<TcpListener> {
+    let inherited = ctx
+        .inherited_sockets
+        .as_deref()
+        .and_then(|sockets| socket_activation::named(sockets, name));
+    match inherited {
+        Some(socket) => socket_activation::tcp_listener(socket.try_clone()?),
+        None => TcpListener::bind(address),
+    }
+}
+
+/// Drain every server when we receive DRAIN_SIGNAL. A second one changes nothing; ctrl-c cuts a
+/// drain short.
+fn drain_on_signal(ctx: &Context) -> std::io::Result<()> {
+    let registry = ctx.connections.clone();
+    signals::on_signal(DRAIN_SIGNAL, move || match registry.drain() {
+        true => log::info!(reason = "drain signal received"; "Draining"),
+        false => log::info!("Already draining or shutting down"),
+    })
+}
+
 fn print_available_problems(ctx: &Context) {
     println!("Usage: {} <problem_name> [...]", ctx.program_name);
+    println!("       {} check <problem_name> <address>", ctx.program_name);
+    println!("       {} drain <pid>", ctx.program_name);
     println!("Available problems:");
     for problem_name in get_problem_names() {
         println!("  {}", problem_name);
@@ -52,12 +149,48 @@ fn handle_basic_help(ctx: &Context) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+fn handle_check(ctx: &Context) -> Result<(), Box<dyn Error>> {
+    let (Some(problem), Some(address)) =
+        (ctx.problem_arguments.front(), ctx.problem_arguments.get(1))
+    else {
+        print_available_problems(ctx);
+        return Err(String::from("Usage: check <problem_name> <address>").into());
+    };
+    match get_problem_scenarios(problem) {
+        Some(scenarios) => check::check(problem, &scenarios(), address),
+        None => handle_help_for_unknown_problem(ctx),
+    }
+}
+
+/// Tell the server running as `pid` to drain.
+fn handle_drain(ctx: &Context) -> Result<(), Box<dyn Error>> {
+    let Some(pid) = ctx.problem_arguments.front() else {
+        print_available_problems(ctx);
+        return Err(String::from("Usage: drain <pid>").into());
+    };
+    let pid: libc::pid_t = pid.parse()?;
+    // kill would signal a whole process group otherwise
+    if pid <= 0 {
+        return Err(format!("Not a process ID: {}", pid).into());
+    }
+    // SAFETY: kill doesn't touch memory
+    if unsafe { libc::kill(pid, DRAIN_SIGNAL) } == -1 {
+        return Err(format!(
+            "Unable to signal process {}: {}",
+            pid,
+            std::io::Error::last_os_error()
+        )
+        .into());
+    }
+    Ok(())
+}
+
 fn handle_help_for_unknown_problem(ctx: &Context) -> Result<(), Box<dyn Error>> {
     print_available_problems(ctx);
     Err(format!(
         "Problem '{}' not found.",
         ctx.problem_arguments
-            .get(0)
+            .front()
             .expect("We are here precisely because this is set")
     )
     .into())
@@ -73,3 +206,46 @@ fn handle_problem_not_found(ctx: &Context) -> Result<(), Box<dyn Error>> {
     )
     .into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check::Transport;
+    use crate::scaffolding::{adopting_context, test_context};
+    use std::net::UdpSocket;
+    use std::os::fd::OwnedFd;
+
+    #[test]
+    fn every_server_passes_its_own_checks() {
+        // mob_in_the_middle needs an upstream chat server to relay to
+        let (chat_ctx, chat_address) = test_context("budget_chat");
+        let _chat = budget_chat::serve(&chat_ctx).expect("Chat server should start");
+
+        for problem in get_problem_names() {
+            let scenarios = get_problem_scenarios(problem).unwrap()();
+            let (socket, address): (OwnedFd, _) =
+                match scenarios.iter().any(|s| s.transport == Transport::Udp) {
+                    true => {
+                        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+                        let address = socket.local_addr().unwrap();
+                        (socket.into(), address)
+                    }
+                    false => {
+                        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                        let address = listener.local_addr().unwrap();
+                        (listener.into(), address)
+                    }
+                };
+            let mut ctx = adopting_context(problem, vec![socket]);
+            if problem == "mob_in_the_middle" {
+                ctx.problem_arguments.push_back(chat_address.to_string());
+            }
+            let _shutdown_signal = get_problem_server(problem).unwrap()(&ctx)
+                .unwrap_or_else(|e| panic!("{} should start: {}", problem, e));
+
+            if let Err(e) = check::check(problem, &scenarios, &address.to_string()) {
+                panic!("{}: {}", problem, e);
+            }
+        }
+    }
+}
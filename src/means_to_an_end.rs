@@ -1,18 +1,15 @@
+use crate::check::{Scenario, Step, Transport};
 use crate::{scaffolding::Context, server};
-use server::{Server as _, TcpServer};
+use server::{PeerAddress, Server as _, ShutdownSignal, Stream, StreamServer};
 use std::collections::BTreeMap;
 use std::error::Error;
 use std::io::{ErrorKind, Read, Write};
-use std::net::{SocketAddr, TcpStream};
 
-pub(crate) fn run(ctx: &Context) -> Result<(), Box<dyn Error>> {
-    let shutdown_signal = TcpServer::new().serve(ctx, handle)?;
-    shutdown_signal.set_as_ctrl_c_handler()?;
-    shutdown_signal.sleep_until_shutdown();
-    Ok(())
+pub(crate) fn serve(ctx: &Context) -> Result<ShutdownSignal, Box<dyn Error>> {
+    StreamServer::new().serve(ctx, handle)
 }
 
-fn handle(stream: &mut TcpStream, _remote_address: &SocketAddr) -> Result<(), Box<dyn Error>> {
+fn handle(stream: &mut Stream, _remote_address: &PeerAddress) -> Result<(), Box<dyn Error>> {
     let mut database = BTreeMap::<i32, i32>::new();
 
     // all incoming messages are exactly 9 bytes long (convenient, right?)
@@ -64,3 +61,61 @@ pub(crate) fn help(ctx: &Context) -> Result<(), Box<dyn Error>> {
     println!("Usage: {} means_to_an_end", ctx.program_name);
     Ok(())
 }
+
+fn message(kind: u8, first: i32, second: i32) -> Vec<u8> {
+    let mut message = vec![kind];
+    message.extend_from_slice(&first.to_be_bytes());
+    message.extend_from_slice(&second.to_be_bytes());
+    message
+}
+
+pub(crate) fn scenarios() -> Vec<Scenario> {
+    vec![
+        Scenario {
+            name: "averages out-of-order inserts",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, message(b'I', 12347, 100)),
+                Step::send(0, message(b'I', 12345, 101)),
+                Step::send(0, message(b'I', 40960, 5)),
+                Step::send(0, message(b'I', 12346, 102)),
+                Step::send(0, message(b'Q', 12288, 16384)),
+                Step::expect(0, 101i32.to_be_bytes()),
+            ],
+        },
+        Scenario {
+            name: "averages negative prices without overflow",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, message(b'I', 2, -10)),
+                Step::send(0, message(b'I', 1, i32::MAX)),
+                Step::send(0, message(b'I', 3, i32::MAX)),
+                Step::send(0, message(b'Q', 1, 3)),
+                Step::expect(0, 1431655761i32.to_be_bytes()),
+            ],
+        },
+        Scenario {
+            name: "returns zero for empty and backwards ranges",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, message(b'I', 100, 5)),
+                Step::send(0, message(b'Q', 200, 300)),
+                Step::expect(0, 0i32.to_be_bytes()),
+                Step::send(0, message(b'Q', 101, 99)),
+                Step::expect(0, 0i32.to_be_bytes()),
+            ],
+        },
+        Scenario {
+            name: "keeps each session separate",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, message(b'I', 1, 100)),
+                Step::send(1, message(b'I', 1, 200)),
+                Step::send(1, message(b'Q', 0, 10)),
+                Step::expect(1, 200i32.to_be_bytes()),
+                Step::send(0, message(b'Q', 0, 10)),
+                Step::expect(0, 100i32.to_be_bytes()),
+            ],
+        },
+    ]
+}
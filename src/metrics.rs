@@ -0,0 +1,472 @@
+//! Runtime statistics for a server, served in the Prometheus text format from a tiny HTTP listener.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::as_display;
+
+use crate::server::{Health, ReadBefore};
+
+/// Upper bounds of the connection duration histogram buckets, in seconds
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+/// How long a scraper gets to send its whole request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// Nothing we serve needs a request bigger than this
+const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+/// Requests served at once; connections beyond this are closed straight away
+const MAX_CONCURRENT_REQUESTS: usize = 8;
+
+/// Counters for one problem's servers. Everything only ever goes up.
+pub(crate) struct Metrics {
+    problem: String,
+    connections_accepted: AtomicU64,
+    connections_rejected: AtomicU64,
+    /// Rejections because of a [`crate::limits::LimitsConfig`], by reason
+    connections_limited: Mutex<BTreeMap<&'static str, u64>>,
+    connections_completed: AtomicU64,
+    handler_errors: AtomicU64,
+    handler_panics: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    connection_duration: Histogram,
+}
+
+impl Metrics {
+    pub(crate) fn new(problem: &str) -> Self {
+        Self {
+            problem: String::from(problem),
+            connections_accepted: AtomicU64::new(0),
+            connections_rejected: AtomicU64::new(0),
+            connections_limited: Mutex::new(BTreeMap::new()),
+            connections_completed: AtomicU64::new(0),
+            handler_errors: AtomicU64::new(0),
+            handler_panics: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            connection_duration: Histogram::new(DURATION_BUCKETS),
+        }
+    }
+
+    pub(crate) fn accepted(&self) {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A connection turned away without being handled.
+    pub(crate) fn rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A connection or datagram turned away by a limit, for `reason`. Connections are also counted
+    /// as rejected.
+    pub(crate) fn limited(&self, reason: &'static str) {
+        *self
+            .connections_limited
+            .lock()
+            .expect("Limited connection counts should not be poisoned")
+            .entry(reason)
+            .or_insert(0) += 1;
+    }
+
+    /// A handler has returned, `duration` after its connection was accepted.
+    pub(crate) fn completed(&self, duration: Duration, failed: bool) {
+        self.connections_completed.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.handler_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.connection_duration.observe(duration);
+    }
+
+    /// A handler panicked. It's also counted as completed with an error, if it was handling a
+    /// connection.
+    pub(crate) fn panicked(&self) {
+        self.handler_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn read(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn written(&self, bytes: usize) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Everything, in the Prometheus text exposition format.
+    pub(crate) fn render(&self) -> String {
+        let labels = format!("problem=\"{}\"", escape_label(&self.problem));
+        let mut output = String::new();
+        for (name, help, counter) in [
+            (
+                "protohackers_connections_accepted_total",
+                "Connections accepted by a listener.",
+                &self.connections_accepted,
+            ),
+            (
+                "protohackers_connections_rejected_total",
+                "Connections turned away without being handled.",
+                &self.connections_rejected,
+            ),
+            (
+                "protohackers_connections_completed_total",
+                "Connections whose handler has returned.",
+                &self.connections_completed,
+            ),
+            (
+                "protohackers_handler_errors_total",
+                "Connections whose handler returned an error.",
+                &self.handler_errors,
+            ),
+            (
+                "protohackers_handler_panics_total",
+                "Handlers which panicked.",
+                &self.handler_panics,
+            ),
+            (
+                "protohackers_bytes_read_total",
+                "Bytes handlers have read from clients.",
+                &self.bytes_read,
+            ),
+            (
+                "protohackers_bytes_written_total",
+                "Bytes handlers have written to clients.",
+                &self.bytes_written,
+            ),
+        ] {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} counter", name);
+            let _ = writeln!(
+                output,
+                "{}{{{}}} {}",
+                name,
+                labels,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+        let name = "protohackers_connections_limited_total";
+        let _ = writeln!(
+            output,
+            "# HELP {} Connections and datagrams turned away by a limit, by reason.",
+            name
+        );
+        let _ = writeln!(output, "# TYPE {} counter", name);
+        for (reason, count) in self
+            .connections_limited
+            .lock()
+            .expect("Limited connection counts should not be poisoned")
+            .iter()
+        {
+            let _ = writeln!(
+                output,
+                "{}{{{},reason=\"{}\"}} {}",
+                name, labels, reason, count
+            );
+        }
+        self.connection_duration.render(
+            &mut output,
+            "protohackers_connection_duration_seconds",
+            "How long connections were open, from being accepted to their handler returning.",
+            &labels,
+        );
+        output
+    }
+}
+
+/// Bytes read from and written to one connection, which also count towards its server's [`Metrics`].
+pub(crate) struct ConnectionCounters {
+    metrics: Arc<Metrics>,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl ConnectionCounters {
+    pub(crate) fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            metrics,
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn read(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.metrics.read(bytes);
+    }
+
+    pub(crate) fn written(&self, bytes: usize) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.metrics.written(bytes);
+    }
+
+    pub(crate) fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations in each bucket, not cumulative; the last is everything beyond the last bound
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, output: &mut String, name: &str, help: &str, labels: &str) {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        let bounds = self.bounds.iter().map(f64::to_string);
+        for (bucket, bound) in self
+            .buckets
+            .iter()
+            .zip(bounds.chain([String::from("+Inf")]))
+        {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                output,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        let sum = Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)).as_secs_f64();
+        let _ = writeln!(output, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(
+            output,
+            "{}_count{{{}}} {}",
+            name,
+            labels,
+            self.count.load(Ordering::Relaxed)
+        );
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve `metrics` at `http://<address>/metrics`, and `health` at `http://<address>/health`, on
+/// `listener` from background threads, one per request. Returns the address we're listening on.
+pub(crate) fn serve_http(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+) -> io::Result<SocketAddr> {
+    let local_address = listener.local_addr()?;
+    log::info!(address = as_display!(local_address); "Serving metrics");
+    thread::Builder::new()
+        .name("metrics-http".into())
+        .spawn(move || {
+            let in_flight = Arc::new(AtomicUsize::new(0));
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::debug!(error = as_display!(e); "Error accepting metrics request");
+                        continue;
+                    }
+                };
+                if in_flight.fetch_add(1, Ordering::SeqCst) >= MAX_CONCURRENT_REQUESTS {
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    log::debug!("Too many metrics requests at once, dropping one");
+                    continue;
+                }
+                let (metrics, health, finished) =
+                    (metrics.clone(), health.clone(), in_flight.clone());
+                let spawned = thread::Builder::new().name("metrics-request".into()).spawn(
+                    move || {
+                        if let Err(e) = respond(stream, &metrics, &health) {
+                            log::debug!(error = as_display!(e); "Error serving metrics request");
+                        }
+                        finished.fetch_sub(1, Ordering::SeqCst);
+                    },
+                );
+                if let Err(e) = spawned {
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    log::debug!(error = as_display!(e); "Unable to start a metrics request thread");
+                }
+            }
+        })?;
+    Ok(local_address)
+}
+
+fn respond(stream: TcpStream, metrics: &Metrics, health: &Health) -> io::Result<()> {
+    let request = ReadBefore::new(&stream, REQUEST_TIMEOUT);
+    let mut reader = BufReader::new(request.take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // We don't care about any of the headers, but they have to be read before we respond
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), Some("/health")) if health.is_healthy() => ("200 OK", String::from("ok\n")),
+        (Some("GET"), Some("/health")) => (
+            "503 Service Unavailable",
+            health
+                .problems()
+                .into_iter()
+                .map(|(listener, problem)| format!("{}: {}\n", listener, problem))
+                .collect(),
+        ),
+        (Some("GET"), _) => ("404 Not Found", String::from("Not found\n")),
+        _ => (
+            "405 Method Not Allowed",
+            String::from("Method not allowed\n"),
+        ),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::time::Instant;
+
+    fn get(address: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn renders_counters_and_cumulative_histogram_buckets() {
+        let metrics = Metrics::new("smoke_test");
+        metrics.accepted();
+        metrics.accepted();
+        metrics.rejected();
+        metrics.limited("rate_limited");
+        metrics.limited("rate_limited");
+        metrics.completed(Duration::from_millis(3), false);
+        metrics.completed(Duration::from_secs(2), true);
+        metrics.panicked();
+        metrics.read(10);
+        metrics.written(7);
+
+        let output = metrics.render();
+        for line in [
+            "# TYPE protohackers_connections_accepted_total counter",
+            "protohackers_connections_accepted_total{problem=\"smoke_test\"} 2",
+            "protohackers_connections_rejected_total{problem=\"smoke_test\"} 1",
+            "protohackers_connections_completed_total{problem=\"smoke_test\"} 2",
+            "protohackers_connections_limited_total{problem=\"smoke_test\",reason=\"rate_limited\"} 2",
+            "protohackers_handler_errors_total{problem=\"smoke_test\"} 1",
+            "protohackers_handler_panics_total{problem=\"smoke_test\"} 1",
+            "protohackers_bytes_read_total{problem=\"smoke_test\"} 10",
+            "protohackers_bytes_written_total{problem=\"smoke_test\"} 7",
+            "# TYPE protohackers_connection_duration_seconds histogram",
+            "protohackers_connection_duration_seconds_bucket{problem=\"smoke_test\",le=\"0.005\"} 1",
+            "protohackers_connection_duration_seconds_bucket{problem=\"smoke_test\",le=\"1\"} 1",
+            "protohackers_connection_duration_seconds_bucket{problem=\"smoke_test\",le=\"2.5\"} 2",
+            "protohackers_connection_duration_seconds_bucket{problem=\"smoke_test\",le=\"+Inf\"} 2",
+            "protohackers_connection_duration_seconds_sum{problem=\"smoke_test\"} 2.003",
+            "protohackers_connection_duration_seconds_count{problem=\"smoke_test\"} 2",
+        ] {
+            assert!(output.lines().any(|l| l == line), "{} not in\n{}", line, output);
+        }
+    }
+
+    #[test]
+    fn serves_metrics_over_http() {
+        let metrics = Arc::new(Metrics::new("prime_time"));
+        metrics.accepted();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = serve_http(listener, metrics, Arc::new(Health::default())).unwrap();
+
+        let response = get(
+            address,
+            "GET /metrics HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n",
+        );
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        assert!(
+            body.contains("protohackers_connections_accepted_total{problem=\"prime_time\"} 1\n")
+        );
+
+        assert!(get(address, "GET /health HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get(address, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 "));
+        assert!(get(address, "POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405 "));
+    }
+
+    #[test]
+    fn slow_clients_hold_up_nobody_and_are_cut_off() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = serve_http(
+            listener,
+            Arc::new(Metrics::new("prime_time")),
+            Arc::new(Health::default()),
+        )
+        .unwrap();
+
+        // A byte at a time, never finishing the headers
+        let mut slow = TcpStream::connect(address).unwrap();
+        let start = Instant::now();
+        let trickle = thread::spawn(move || {
+            while slow.write_all(b"X").is_ok() && start.elapsed() < REQUEST_TIMEOUT * 3 {
+                thread::sleep(Duration::from_millis(100));
+            }
+            start.elapsed()
+        });
+
+        assert!(get(address, "GET /health HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(start.elapsed() < REQUEST_TIMEOUT);
+        assert!(trickle.join().unwrap() < REQUEST_TIMEOUT * 3);
+    }
+}
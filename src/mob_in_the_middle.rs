@@ -0,0 +1,256 @@
+use crate::check::{Scenario, Step, Transport};
+use crate::{scaffolding::Context, server};
+use log::as_display;
+use server::{ConnectionHandler, PeerAddress, Server as _, ShutdownSignal, Stream, StreamServer};
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::thread;
+
+const DEFAULT_UPSTREAM_ADDRESS: &str = "chat.protohackers.com:16963";
+const TONYS_ADDRESS: &str = "7YWHMfk9JZe0LM0g1ZauHuiSxhI";
+
+pub(crate) fn serve(ctx: &Context) -> Result<ShutdownSignal, Box<dyn Error>> {
+    let upstream_address = ctx
+        .problem_arguments
+        .front()
+        .cloned()
+        .unwrap_or(String::from(DEFAULT_UPSTREAM_ADDRESS));
+    log::info!(upstream_address = as_display!(upstream_address); "Proxying to upstream");
+
+    StreamServer::new().serve(ctx, proxy_to(upstream_address))
+}
+
+fn proxy_to(upstream_address: String) -> impl ConnectionHandler<Stream> {
+    move |client: &mut Stream, remote_address: &PeerAddress| {
+        handle(client, remote_address, &upstream_address)
+    }
+}
+
+fn handle(
+    client: &mut Stream,
+    remote_address: &PeerAddress,
+    upstream_address: &str,
+) -> Result<(), Box<dyn Error>> {
+    let upstream = Stream::connect(upstream_address)?;
+
+    let upstream_reader = upstream.try_clone()?;
+    let client_writer = client.try_clone()?;
+    let remote_address_for_downstream = remote_address.clone();
+    let downstream = thread::spawn(move || {
+        if let Err(e) = relay(upstream_reader, client_writer) {
+            log::debug!(
+                remote_address = as_display!(remote_address_for_downstream),
+                error = as_display!(e);
+                "Error relaying from upstream to client"
+            );
+        }
+    });
+
+    let result = relay(client.try_clone()?, upstream);
+    // Whichever side hung up first, make sure both sides are closed so the other relay finishes too
+    let _ = client.shutdown(Shutdown::Both);
+    let _ = downstream.join();
+    result
+}
+
+/// Copy complete lines from `from` to `to`, rewriting Boguscoin addresses on the way.
+/// When `from` closes, `to` is shut down. Only newline-terminated lines are messages, so a
+/// partial line at the end of the stream is discarded.
+fn relay(from: Stream, mut to: Stream) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(from);
+    let mut line = Vec::new();
+    let result = loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => break Ok(()),
+            Ok(_) if line.last() != Some(&b'\n') => {
+                log::debug!(
+                    bytes = as_display!(line.len());
+                    "Discarding partial line at end of stream"
+                );
+                break Ok(());
+            }
+            Ok(_) => {
+                let mut rewritten = rewrite_line(&line[..line.len() - 1]);
+                rewritten.push(b'\n');
+                if let Err(e) = to.write_all(&rewritten) {
+                    break Err(e.into());
+                }
+            }
+            Err(e) => break Err(e.into()),
+        }
+    };
+    let _ = to.shutdown(Shutdown::Both);
+    result
+}
+
+/// Rewrite the addresses in `line`, leaving every other byte as it was, even if it isn't UTF-8.
+fn rewrite_line(line: &[u8]) -> Vec<u8> {
+    line.split(|&byte| byte == b' ')
+        .map(|word| {
+            if is_boguscoin_address(word) {
+                TONYS_ADDRESS.as_bytes()
+            } else {
+                word
+            }
+        })
+        .collect::<Vec<&[u8]>>()
+        .join(&b' ')
+}
+
+fn is_boguscoin_address(word: &[u8]) -> bool {
+    word.starts_with(b"7")
+        && (26..=35).contains(&word.len())
+        && word.iter().all(|c| c.is_ascii_alphanumeric())
+}
+
+pub(crate) fn help(ctx: &Context) -> Result<(), Box<dyn Error>> {
+    println!(
+        "Usage: {} mob_in_the_middle [upstream_address]",
+        ctx.program_name
+    );
+    println!(
+        "  upstream_address defaults to {}",
+        DEFAULT_UPSTREAM_ADDRESS
+    );
+    Ok(())
+}
+
+pub(crate) fn scenarios() -> Vec<Scenario> {
+    // Meant for an upstream with an empty room, such as our own budget_chat. Names must be unique
+    // in the room, and the room outlives the checker's connections.
+    let run_id = crate::check::run_id();
+    let alice = format!("alice{}", run_id);
+    let bob = format!("bob{}", run_id);
+    vec![Scenario {
+        name: "rewrites Boguscoin addresses both ways",
+        transport: Transport::Tcp,
+        steps: vec![
+            Step::expect_matching(0, "", ""),
+            Step::send(0, format!("{}\n", alice)),
+            Step::expect_matching(0, "*", ""),
+            Step::expect_matching(1, "", ""),
+            Step::send(1, format!("{}\n", bob)),
+            Step::expect_matching(1, "*", &alice),
+            Step::expect_matching(0, "*", &bob),
+            Step::send(1, "Pay me at 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX\n"),
+            Step::expect(0, format!("[{}] Pay me at {}\n", bob, TONYS_ADDRESS)),
+            Step::send(0, "7adNeSwJkMakpEcln9HEtthSRtxdmEHOT8T is mine\n"),
+            Step::expect(1, format!("[{}] {} is mine\n", alice, TONYS_ADDRESS)),
+        ],
+    }]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget_chat;
+    use crate::scaffolding::test_context;
+    use std::net::{SocketAddr, TcpStream};
+    use std::time::Duration;
+
+    #[test]
+    fn rewrites_addresses_anywhere_in_the_line() {
+        assert_eq!(
+            rewrite_line(b"7F1u3wSD5RbOHQmupo9nx4TnhQ"),
+            TONYS_ADDRESS.as_bytes()
+        );
+        assert_eq!(
+            rewrite_line(b"Send to 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX please"),
+            format!("Send to {} please", TONYS_ADDRESS).into_bytes()
+        );
+        assert_eq!(
+            rewrite_line(b"7LOrwbDlS8NujgjddyogWgIM93MV5N2VR 7adNeSwJkMakpEcln9HEtthSRtxdmEHOT8T"),
+            format!("{} {}", TONYS_ADDRESS, TONYS_ADDRESS).into_bytes()
+        );
+    }
+
+    #[test]
+    fn leaves_bytes_which_are_not_utf8_alone() {
+        assert_eq!(
+            rewrite_line(b"caf\xe9 7F1u3wSD5RbOHQmupo9nx4TnhQ \xff"),
+            [&b"caf\xe9 "[..], TONYS_ADDRESS.as_bytes(), b" \xff"].concat()
+        );
+    }
+
+    #[test]
+    fn leaves_non_addresses_alone() {
+        for line in [
+            "Hi alice",
+            // too short (25 characters)
+            "7F1u3wSD5RbOHQmupo9nx4Tnh",
+            // too long (36 characters)
+            "7F1u3wSD5RbOHQmupo9nx4TnhQ7F1u3wSD5R",
+            // doesn't start with a 7
+            "8F1u3wSD5RbOHQmupo9nx4TnhQ",
+            // not alphanumeric
+            "7F1u3wSD5RbOHQmupo9nx4TnhQ-1234",
+            // not bounded by spaces
+            "This is a product ID, not a Boguscoin: 7F1u3wSD5RbOHQmupo9nx4TnhQ-1234",
+            "",
+        ] {
+            assert_eq!(rewrite_line(line.as_bytes()), line.as_bytes());
+        }
+    }
+
+    fn serve(problem: &str, handler: impl ConnectionHandler<Stream>) -> SocketAddr {
+        let (ctx, address) = test_context(problem);
+        StreamServer::new()
+            .serve(&ctx, handler)
+            .expect("Server should start");
+        address
+    }
+
+    fn connect(address: SocketAddr) -> (BufReader<TcpStream>, TcpStream) {
+        let stream = TcpStream::connect(address).expect("Should be able to connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        (BufReader::new(stream.try_clone().unwrap()), stream)
+    }
+
+    fn read_line(reader: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).expect("Should read a line");
+        line
+    }
+
+    #[test]
+    fn proxies_our_own_budget_chat() {
+        let chat_address = serve("budget_chat", budget_chat::Chatroom::default());
+        let proxy_address = serve("mob_in_the_middle", proxy_to(chat_address.to_string()));
+
+        // alice talks to the chat directly, bob goes via the proxy
+        let (mut alice_reader, mut alice) = connect(chat_address);
+        assert_eq!(read_line(&mut alice_reader), "Name pls:\n");
+        alice.write_all(b"alice\n").unwrap();
+        assert_eq!(read_line(&mut alice_reader), "* The room contains: \n");
+
+        let (mut bob_reader, mut bob) = connect(proxy_address);
+        assert_eq!(read_line(&mut bob_reader), "Name pls:\n");
+        bob.write_all(b"bob\n").unwrap();
+        assert_eq!(read_line(&mut bob_reader), "* The room contains: alice\n");
+        assert_eq!(read_line(&mut alice_reader), "* bob joined\n");
+
+        bob.write_all(b"Pay me at 7iKDZEwPZSqIvDnHvVN2r0hUWXD5rHX\n")
+            .unwrap();
+        assert_eq!(
+            read_line(&mut alice_reader),
+            format!("[bob] Pay me at {}\n", TONYS_ADDRESS)
+        );
+
+        alice
+            .write_all(b"7adNeSwJkMakpEcln9HEtthSRtxdmEHOT8T is mine\n")
+            .unwrap();
+        assert_eq!(
+            read_line(&mut bob_reader),
+            format!("[alice] {} is mine\n", TONYS_ADDRESS)
+        );
+
+        // A partial line is never forwarded, and bob's upstream connection closes with his
+        bob.write_all(b"half a line").unwrap();
+        bob.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(read_line(&mut alice_reader), "* bob left\n");
+    }
+}
@@ -0,0 +1,36 @@
+//! Passing open file descriptors to a child process at the numbers it expects them, the way
+//! socket activation does. The integration tests include this file too, so it stands alone.
+
+use std::{
+    io,
+    os::{fd::RawFd, unix::process::CommandExt},
+    process::Command,
+};
+
+/// Have `command` start with `fds` as its file descriptors 3 onwards, in order. Nothing else we
+/// have open is inherited, as the standard library opens everything close-on-exec.
+pub(crate) fn pass_fds(command: &mut Command, fds: Vec<RawFd>) {
+    let first_free = 3 + fds.len() as RawFd;
+    // Filled in after fork, where we mustn't allocate
+    let mut moved = vec![-1; fds.len()];
+    // SAFETY: only async-signal-safe calls happen between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            // Move everything above where it's going first, so nothing is overwritten before it
+            // has been moved
+            for (fd, moved) in fds.iter().zip(moved.iter_mut()) {
+                *moved = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, first_free);
+                if *moved == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            // Unlike the originals, the copies dup2 makes are inherited across exec
+            for (target, moved) in (3..).zip(moved.iter()) {
+                if libc::dup2(*moved, target) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
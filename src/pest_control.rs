@@ -0,0 +1,905 @@
+use crate::check::{Scenario, Step, Transport};
+use crate::{scaffolding::Context, server};
+use log::{as_debug, as_display};
+use server::{ConnectionHandler, PeerAddress, Server as _, ShutdownSignal, Stream, StreamServer};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, PoisonError};
+
+const DEFAULT_AUTHORITY_ADDRESS: &str = "pestcontrol.protohackers.com:20547";
+const PROTOCOL_NAME: &str = "pestcontrol";
+const PROTOCOL_VERSION: u32 = 1;
+/// Type, length and checksum
+const MIN_MESSAGE_LENGTH: u32 = 6;
+/// Nothing legitimate comes close to this, and it stops a bogus length from eating all our memory
+const MAX_MESSAGE_LENGTH: u32 = 1024 * 1024;
+
+type SharedSiteAuthority = Arc<Mutex<Option<SiteAuthority>>>;
+
+// --- Codec ---
+// Every message is a u8 type, a u32 total length, the content, and a u8 checksum which
+// makes the sum of every byte in the message zero. Integers are big-endian u32 unless
+// noted; strings and arrays are prefixed with a u32 length.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    Cull,
+    Conserve,
+}
+
+impl Action {
+    fn to_byte(self) -> u8 {
+        match self {
+            Action::Cull => 0x90,
+            Action::Conserve => 0xa0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Target {
+    species: String,
+    min: u32,
+    max: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Observation {
+    species: String,
+    count: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Message {
+    Hello {
+        protocol: String,
+        version: u32,
+    },
+    Error {
+        message: String,
+    },
+    Ok,
+    DialAuthority {
+        site: u32,
+    },
+    TargetPopulations {
+        site: u32,
+        populations: Vec<Target>,
+    },
+    CreatePolicy {
+        species: String,
+        action: Action,
+    },
+    DeletePolicy {
+        policy: u32,
+    },
+    PolicyResult {
+        policy: u32,
+    },
+    SiteVisit {
+        site: u32,
+        populations: Vec<Observation>,
+    },
+}
+
+#[derive(Debug)]
+enum ProtocolError {
+    Io(io::Error),
+    Invalid(String),
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(value: io::Error) -> Self {
+        ProtocolError::Io(value)
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Io(e) => write!(f, "{}", e),
+            ProtocolError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+fn invalid<T>(reason: impl Into<String>) -> Result<T, ProtocolError> {
+    Err(ProtocolError::Invalid(reason.into()))
+}
+
+/// Reads fields from the content of a single message, which has already been checksummed.
+struct ContentReader<'a> {
+    content: &'a [u8],
+}
+
+impl<'a> ContentReader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], ProtocolError> {
+        if count > self.content.len() {
+            return invalid("message content too short");
+        }
+        let (taken, rest) = self.content.split_at(count);
+        self.content = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        Ok(u32::from_be_bytes(
+            self.take(4)?.try_into().expect("We took exactly 4 bytes"),
+        ))
+    }
+
+    fn str(&mut self) -> Result<String, ProtocolError> {
+        let length = self.u32()? as usize;
+        match std::str::from_utf8(self.take(length)?) {
+            Ok(s) => Ok(String::from(s)),
+            Err(_) => invalid("string is not valid ASCII"),
+        }
+    }
+
+    fn array<T>(
+        &mut self,
+        mut element: impl FnMut(&mut Self) -> Result<T, ProtocolError>,
+    ) -> Result<Vec<T>, ProtocolError> {
+        let count = self.u32()?;
+        // Don't trust the count for preallocation; each element is at least one byte anyway
+        let mut elements = Vec::with_capacity((count as usize).min(self.content.len()));
+        for _ in 0..count {
+            elements.push(element(self)?);
+        }
+        Ok(elements)
+    }
+
+    fn finish(&self) -> Result<(), ProtocolError> {
+        if self.content.is_empty() {
+            Ok(())
+        } else {
+            invalid("unused bytes in message content")
+        }
+    }
+}
+
+#[derive(Default)]
+struct ContentWriter {
+    content: Vec<u8>,
+}
+
+impl ContentWriter {
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.content.push(value);
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.content.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn str(&mut self, value: &str) -> &mut Self {
+        self.u32(value.len() as u32);
+        self.content.extend_from_slice(value.as_bytes());
+        self
+    }
+}
+
+impl Message {
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self, ProtocolError> {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header)?;
+        let message_type = header[0];
+        let length = u32::from_be_bytes(header[1..].try_into().expect("Header is 5 bytes"));
+        if !(MIN_MESSAGE_LENGTH..=MAX_MESSAGE_LENGTH).contains(&length) {
+            return invalid(format!("invalid message length {}", length));
+        }
+        let mut rest = vec![0u8; length as usize - header.len()];
+        reader.read_exact(&mut rest)?;
+        let checksum = header
+            .iter()
+            .chain(rest.iter())
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if checksum != 0 {
+            return invalid("invalid checksum");
+        }
+
+        let mut content = ContentReader {
+            content: &rest[..rest.len() - 1],
+        };
+        let message = match message_type {
+            0x50 => Message::Hello {
+                protocol: content.str()?,
+                version: content.u32()?,
+            },
+            0x51 => Message::Error {
+                message: content.str()?,
+            },
+            0x52 => Message::Ok,
+            0x53 => Message::DialAuthority {
+                site: content.u32()?,
+            },
+            0x54 => Message::TargetPopulations {
+                site: content.u32()?,
+                populations: content.array(|c| {
+                    Ok(Target {
+                        species: c.str()?,
+                        min: c.u32()?,
+                        max: c.u32()?,
+                    })
+                })?,
+            },
+            0x55 => Message::CreatePolicy {
+                species: content.str()?,
+                action: match content.u8()? {
+                    0x90 => Action::Cull,
+                    0xa0 => Action::Conserve,
+                    other => return invalid(format!("unknown action {:#04x}", other)),
+                },
+            },
+            0x56 => Message::DeletePolicy {
+                policy: content.u32()?,
+            },
+            0x57 => Message::PolicyResult {
+                policy: content.u32()?,
+            },
+            0x58 => Message::SiteVisit {
+                site: content.u32()?,
+                populations: content.array(|c| {
+                    Ok(Observation {
+                        species: c.str()?,
+                        count: c.u32()?,
+                    })
+                })?,
+            },
+            other => return invalid(format!("unknown message type {:#04x}", other)),
+        };
+        content.finish()?;
+        Ok(message)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut content = ContentWriter::default();
+        let message_type = match self {
+            Message::Hello { protocol, version } => {
+                content.str(protocol).u32(*version);
+                0x50
+            }
+            Message::Error { message } => {
+                content.str(message);
+                0x51
+            }
+            Message::Ok => 0x52,
+            Message::DialAuthority { site } => {
+                content.u32(*site);
+                0x53
+            }
+            Message::TargetPopulations { site, populations } => {
+                content.u32(*site).u32(populations.len() as u32);
+                for target in populations {
+                    content.str(&target.species).u32(target.min).u32(target.max);
+                }
+                0x54
+            }
+            Message::CreatePolicy { species, action } => {
+                content.str(species).u8(action.to_byte());
+                0x55
+            }
+            Message::DeletePolicy { policy } => {
+                content.u32(*policy);
+                0x56
+            }
+            Message::PolicyResult { policy } => {
+                content.u32(*policy);
+                0x57
+            }
+            Message::SiteVisit { site, populations } => {
+                content.u32(*site).u32(populations.len() as u32);
+                for observation in populations {
+                    content.str(&observation.species).u32(observation.count);
+                }
+                0x58
+            }
+        };
+
+        let length = content.content.len() as u32 + MIN_MESSAGE_LENGTH;
+        let mut bytes = Vec::with_capacity(length as usize);
+        bytes.push(message_type);
+        bytes.extend_from_slice(&length.to_be_bytes());
+        bytes.extend_from_slice(&content.content);
+        let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        bytes.push(0u8.wrapping_sub(sum));
+        bytes
+    }
+
+    fn hello() -> Self {
+        Message::Hello {
+            protocol: String::from(PROTOCOL_NAME),
+            version: PROTOCOL_VERSION,
+        }
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Message::Error {
+            message: message.into(),
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+}
+
+fn check_hello(message: Message) -> Result<(), ProtocolError> {
+    match message {
+        Message::Hello { protocol, version }
+            if protocol == PROTOCOL_NAME && version == PROTOCOL_VERSION =>
+        {
+            Ok(())
+        }
+        Message::Hello { protocol, version } => invalid(format!(
+            "unsupported protocol {} version {}",
+            protocol, version
+        )),
+        other => invalid(format!("expected Hello, got {:?}", other)),
+    }
+}
+
+// --- Authority ---
+
+/// Our connection to the authority server for a single site, and the policies we've created there.
+struct SiteAuthority {
+    site: u32,
+    stream: TcpStream,
+    targets: Vec<Target>,
+    /// species -> (policy id, action)
+    policies: HashMap<String, (u32, Action)>,
+}
+
+impl SiteAuthority {
+    fn connect(address: &str, site: u32) -> Result<Self, Box<dyn Error>> {
+        let mut stream = TcpStream::connect(address)?;
+        Message::hello().write_to(&mut stream)?;
+        check_hello(Message::read_from(&mut stream)?)?;
+        Message::DialAuthority { site }.write_to(&mut stream)?;
+        let targets = match Message::read_from(&mut stream)? {
+            Message::TargetPopulations {
+                site: target_site,
+                populations,
+            } if target_site == site => populations,
+            other => {
+                return Err(format!(
+                    "expected TargetPopulations for site {}, got {:?}",
+                    site, other
+                )
+                .into())
+            }
+        };
+        log::debug!(site = as_display!(site), targets = as_debug!(targets); "Connected to authority");
+        Ok(Self {
+            site,
+            stream,
+            targets,
+            policies: HashMap::new(),
+        })
+    }
+
+    fn request(&mut self, request: Message) -> Result<Message, Box<dyn Error>> {
+        request.write_to(&mut self.stream)?;
+        match Message::read_from(&mut self.stream)? {
+            Message::Error { message } => Err(format!("authority error: {}", message).into()),
+            response => Ok(response),
+        }
+    }
+
+    /// Create and delete policies so that they match what the observed populations need.
+    fn reconcile(&mut self, observed: &HashMap<String, u32>) -> Result<(), Box<dyn Error>> {
+        for target in self.targets.clone() {
+            // Species we didn't see at all have a population of zero
+            let count = observed.get(&target.species).copied().unwrap_or(0);
+            let wanted = if count < target.min {
+                Some(Action::Conserve)
+            } else if count > target.max {
+                Some(Action::Cull)
+            } else {
+                None
+            };
+            let existing = self.policies.get(&target.species).copied();
+            if existing.map(|(_, action)| action) == wanted {
+                continue;
+            }
+            if let Some((policy, _)) = existing {
+                match self.request(Message::DeletePolicy { policy })? {
+                    Message::Ok => {
+                        self.policies.remove(&target.species);
+                    }
+                    other => return Err(format!("expected OK, got {:?}", other).into()),
+                }
+            }
+            if let Some(action) = wanted {
+                match self.request(Message::CreatePolicy {
+                    species: target.species.clone(),
+                    action,
+                })? {
+                    Message::PolicyResult { policy } => {
+                        self.policies
+                            .insert(target.species.clone(), (policy, action));
+                    }
+                    other => return Err(format!("expected PolicyResult, got {:?}", other).into()),
+                }
+            }
+            log::debug!(
+                site = as_display!(self.site),
+                species = as_display!(target.species),
+                count = as_display!(count),
+                action = as_debug!(wanted);
+                "Updated policy"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The authority server, and our connections to it for every site we've seen.
+struct Authority {
+    address: String,
+    /// site -> our connection to the authority server for that site, if we have one.
+    /// Each site has its own lock so that visits to different sites don't block each other.
+    sites: Mutex<HashMap<u32, SharedSiteAuthority>>,
+}
+
+impl Authority {
+    fn new(address: String) -> Self {
+        Self {
+            address,
+            sites: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn record_visit(
+        &self,
+        site: u32,
+        observed: &HashMap<String, u32>,
+    ) -> Result<(), Box<dyn Error>> {
+        // Each change to the sites is a single insert, so it's safe to carry on if one panicked
+        let site_authority = self
+            .sites
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(site)
+            .or_default()
+            .clone();
+        let mut authority = site_authority.lock().unwrap_or_else(|poisoned| {
+            // A panic part way through reconciling leaves the connection in an unknown state,
+            // just as an error does
+            let mut authority = poisoned.into_inner();
+            *authority = None;
+            site_authority.clear_poison();
+            authority
+        });
+        if authority.is_none() {
+            *authority = Some(SiteAuthority::connect(&self.address, site)?);
+        }
+        let result = authority
+            .as_mut()
+            .expect("We just made sure this is set")
+            .reconcile(observed);
+        if result.is_err() {
+            // We don't know what state the authority connection is in, so start afresh next time
+            *authority = None;
+        }
+        result
+    }
+}
+
+// --- Clients ---
+
+pub(crate) fn serve(ctx: &Context) -> Result<ShutdownSignal, Box<dyn Error>> {
+    let authority_address = ctx
+        .problem_arguments
+        .front()
+        .cloned()
+        .unwrap_or(String::from(DEFAULT_AUTHORITY_ADDRESS));
+    log::info!(authority_address = as_display!(authority_address); "Using authority server");
+
+    StreamServer::new().serve(ctx, reporting_to(authority_address))
+}
+
+fn reporting_to(authority_address: String) -> impl ConnectionHandler<Stream> {
+    let authority = Arc::new(Authority::new(authority_address));
+    move |stream: &mut Stream, remote_address: &PeerAddress| {
+        handle(stream, remote_address, &authority)
+    }
+}
+
+fn handle(
+    stream: &mut Stream,
+    remote_address: &PeerAddress,
+    authority: &Authority,
+) -> Result<(), Box<dyn Error>> {
+    Message::hello().write_to(stream)?;
+    match handle_messages(stream, remote_address, authority) {
+        Ok(()) => Ok(()),
+        Err(ProtocolError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => Ok(()),
+        Err(ProtocolError::Io(e)) => Err(e.into()),
+        Err(ProtocolError::Invalid(reason)) => {
+            log::info!(
+                remote_address = as_display!(remote_address),
+                reason = as_display!(reason);
+                "Disconnecting client after protocol error"
+            );
+            Message::error(reason).write_to(stream)?;
+            Ok(())
+        }
+    }
+}
+
+fn handle_messages(
+    stream: &mut Stream,
+    remote_address: &PeerAddress,
+    authority: &Authority,
+) -> Result<(), ProtocolError> {
+    check_hello(Message::read_from(stream)?)?;
+    loop {
+        let (site, populations) = match Message::read_from(stream)? {
+            Message::SiteVisit { site, populations } => (site, populations),
+            other => return invalid(format!("unexpected message {:?}", other)),
+        };
+
+        let mut observed = HashMap::new();
+        for observation in populations {
+            if *observed
+                .entry(observation.species.clone())
+                .or_insert(observation.count)
+                != observation.count
+            {
+                return invalid(format!("conflicting counts for {}", observation.species));
+            }
+        }
+
+        if let Err(e) = authority.record_visit(site, &observed) {
+            // This is a problem between us and the authority, not the client's fault
+            log::error!(
+                remote_address = as_display!(remote_address),
+                site = as_display!(site),
+                error = as_display!(e);
+                "Unable to update policies"
+            );
+        }
+    }
+}
+
+pub(crate) fn help(ctx: &Context) -> Result<(), Box<dyn Error>> {
+    println!(
+        "Usage: {} pest_control [authority_address]",
+        ctx.program_name
+    );
+    println!(
+        "  authority_address defaults to {}",
+        DEFAULT_AUTHORITY_ADDRESS
+    );
+    Ok(())
+}
+
+pub(crate) fn scenarios() -> Vec<Scenario> {
+    // Anything beyond this needs the authority server, so stick to the handshake
+    let mut bad_checksum = Message::SiteVisit {
+        site: 1,
+        populations: vec![],
+    }
+    .to_bytes();
+    if let Some(checksum) = bad_checksum.last_mut() {
+        *checksum = checksum.wrapping_add(1);
+    }
+    vec![
+        Scenario {
+            name: "rejects messages with bad checksums",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::expect(0, Message::hello().to_bytes()),
+                Step::send(0, Message::hello().to_bytes()),
+                Step::send(0, bad_checksum),
+                Step::expect(0, [0x51]),
+            ],
+        },
+        Scenario {
+            name: "rejects the wrong protocol",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::expect(0, Message::hello().to_bytes()),
+                Step::send(
+                    0,
+                    Message::Hello {
+                        protocol: String::from("pestcontrolled"),
+                        version: PROTOCOL_VERSION,
+                    }
+                    .to_bytes(),
+                ),
+                Step::expect(0, [0x51]),
+            ],
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scaffolding::test_context;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn encodes_and_decodes_the_spec_example() {
+        let bytes = [
+            0x50, 0x00, 0x00, 0x00, 0x19, 0x00, 0x00, 0x00, 0x0b, 0x70, 0x65, 0x73, 0x74, 0x63,
+            0x6f, 0x6e, 0x74, 0x72, 0x6f, 0x6c, 0x00, 0x00, 0x00, 0x01, 0xce,
+        ];
+        assert_eq!(Message::hello().to_bytes(), bytes);
+        assert_eq!(
+            Message::read_from(&mut &bytes[..]).unwrap(),
+            Message::hello()
+        );
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        for message in [
+            Message::hello(),
+            Message::error("bad"),
+            Message::Ok,
+            Message::DialAuthority { site: 12345 },
+            Message::TargetPopulations {
+                site: 12345,
+                populations: vec![Target {
+                    species: String::from("dog"),
+                    min: 1,
+                    max: 3,
+                }],
+            },
+            Message::CreatePolicy {
+                species: String::from("dog"),
+                action: Action::Conserve,
+            },
+            Message::DeletePolicy { policy: 123 },
+            Message::PolicyResult { policy: 123 },
+            Message::SiteVisit {
+                site: 12345,
+                populations: vec![
+                    Observation {
+                        species: String::from("dog"),
+                        count: 1,
+                    },
+                    Observation {
+                        species: String::from("rat"),
+                        count: 5,
+                    },
+                ],
+            },
+        ] {
+            assert_eq!(
+                Message::read_from(&mut &message.to_bytes()[..]).unwrap(),
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        let good = Message::DialAuthority { site: 1 }.to_bytes();
+
+        let fix_checksum = |bytes: &mut Vec<u8>| {
+            let length = bytes.len();
+            bytes[length - 1] = bytes[..length - 1]
+                .iter()
+                .fold(0u8, |sum, byte| sum.wrapping_sub(*byte));
+        };
+
+        let mut bad_checksum = good.clone();
+        *bad_checksum.last_mut().unwrap() ^= 1;
+
+        // Length claims an extra byte of content, which is unused by DialAuthority
+        let mut too_long = good.clone();
+        too_long[4] += 1;
+        too_long.insert(too_long.len() - 1, 0);
+        fix_checksum(&mut too_long);
+
+        let mut unknown_type = good.clone();
+        unknown_type[0] = 0x59;
+        fix_checksum(&mut unknown_type);
+
+        for bytes in [bad_checksum, too_long, unknown_type] {
+            assert!(matches!(
+                Message::read_from(&mut &bytes[..]),
+                Err(ProtocolError::Invalid(_))
+            ));
+        }
+        assert!(matches!(
+            Message::read_from(&mut &[0x52, 0xff, 0xff, 0xff, 0xff][..]),
+            Err(ProtocolError::Invalid(_))
+        ));
+    }
+
+    /// A stand-in for the real authority server. Every site has the same targets,
+    /// and every policy change is reported on the returned channel.
+    fn start_authority(targets: Vec<Target>) -> (SocketAddr, Receiver<(u32, Message)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (events, events_receiver) = channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (stream, targets, events) = (stream.unwrap(), targets.clone(), events.clone());
+                thread::spawn(move || serve_authority(stream, targets, events));
+            }
+        });
+        (address, events_receiver)
+    }
+
+    fn serve_authority(
+        mut stream: TcpStream,
+        targets: Vec<Target>,
+        events: Sender<(u32, Message)>,
+    ) -> Result<(), ProtocolError> {
+        Message::hello().write_to(&mut stream)?;
+        check_hello(Message::read_from(&mut stream)?)?;
+        let site = match Message::read_from(&mut stream)? {
+            Message::DialAuthority { site } => site,
+            other => return invalid(format!("{:?}", other)),
+        };
+        Message::TargetPopulations {
+            site,
+            populations: targets,
+        }
+        .write_to(&mut stream)?;
+        let mut next_policy = 1;
+        loop {
+            let request = Message::read_from(&mut stream)?;
+            let response = match request {
+                Message::CreatePolicy { .. } => {
+                    next_policy += 1;
+                    Message::PolicyResult {
+                        policy: next_policy - 1,
+                    }
+                }
+                Message::DeletePolicy { .. } => Message::Ok,
+                _ => Message::error("unexpected"),
+            };
+            response.write_to(&mut stream)?;
+            let _ = events.send((site, request));
+        }
+    }
+
+    fn visit(stream: &mut TcpStream, site: u32, populations: &[(&str, u32)]) {
+        Message::SiteVisit {
+            site,
+            populations: populations
+                .iter()
+                .map(|(species, count)| Observation {
+                    species: String::from(*species),
+                    count: *count,
+                })
+                .collect(),
+        }
+        .write_to(stream)
+        .unwrap();
+    }
+
+    #[test]
+    fn reconciles_policies_with_the_authority() {
+        let (authority_address, events) = start_authority(vec![
+            Target {
+                species: String::from("dog"),
+                min: 1,
+                max: 3,
+            },
+            Target {
+                species: String::from("rat"),
+                min: 0,
+                max: 10,
+            },
+        ]);
+        let (ctx, address) = test_context("pest_control");
+        StreamServer::new()
+            .serve(&ctx, reporting_to(authority_address.to_string()))
+            .unwrap();
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(Message::read_from(&mut client).unwrap(), Message::hello());
+        Message::hello().write_to(&mut client).unwrap();
+
+        let next_event = || events.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // No dogs at all: conserve them. Too many rats: cull them. Cats aren't our concern.
+        visit(&mut client, 7, &[("rat", 11), ("cat", 100)]);
+        assert_eq!(
+            next_event(),
+            (
+                7,
+                Message::CreatePolicy {
+                    species: String::from("dog"),
+                    action: Action::Conserve
+                }
+            )
+        );
+        assert_eq!(
+            next_event(),
+            (
+                7,
+                Message::CreatePolicy {
+                    species: String::from("rat"),
+                    action: Action::Cull
+                }
+            )
+        );
+
+        // Dogs are fine now, so that policy goes; rats still need culling, so nothing changes
+        visit(&mut client, 7, &[("dog", 2), ("rat", 11)]);
+        assert!(matches!(next_event(), (7, Message::DeletePolicy { .. })));
+
+        // A different site gets its own connection and its own policies
+        visit(&mut client, 8, &[("dog", 4), ("rat", 0)]);
+        assert_eq!(
+            next_event(),
+            (
+                8,
+                Message::CreatePolicy {
+                    species: String::from("dog"),
+                    action: Action::Cull
+                }
+            )
+        );
+        assert!(events.recv_timeout(Duration::from_millis(200)).is_err());
+
+        // Conflicting counts are a protocol error
+        visit(&mut client, 7, &[("dog", 2), ("dog", 3)]);
+        assert!(matches!(
+            Message::read_from(&mut client),
+            Ok(Message::Error { .. })
+        ));
+    }
+
+    #[test]
+    fn starts_afresh_after_a_panic_while_reconciling_a_site() {
+        let (authority_address, events) = start_authority(vec![Target {
+            species: String::from("dog"),
+            min: 1,
+            max: 3,
+        }]);
+        let authority = Authority::new(authority_address.to_string());
+        let no_dogs = HashMap::new();
+        let conserve_dogs = (
+            7,
+            Message::CreatePolicy {
+                species: String::from("dog"),
+                action: Action::Conserve,
+            },
+        );
+        let next_event = || events.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        authority.record_visit(7, &no_dogs).unwrap();
+        assert_eq!(next_event(), conserve_dogs);
+
+        let site = authority.sites.lock().unwrap()[&7].clone();
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _authority = site.lock().unwrap();
+            panic!("Deliberate panic while reconciling");
+        }));
+        assert!(panicked.is_err());
+
+        // A new connection has no policies yet, so it makes the one we had again
+        authority.record_visit(7, &no_dogs).unwrap();
+        assert_eq!(next_event(), conserve_dogs);
+        // And it's kept from then on
+        authority.record_visit(7, &no_dogs).unwrap();
+        assert!(events.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}
@@ -1,11 +1,11 @@
 use serde::{Deserialize, Serialize};
 
+use crate::check::{Scenario, Step, Transport};
 use crate::{scaffolding::Context, server};
-use server::{Server as _, TcpServer};
+use server::{PeerAddress, Server as _, ShutdownSignal, Stream, StreamServer};
 use std::error::Error;
 use std::fmt::Display;
 use std::io::{BufRead, BufReader, Write};
-use std::net::{SocketAddr, TcpStream};
 
 #[derive(Debug)]
 enum Method {
@@ -46,7 +46,8 @@ impl Display for Method {
 #[serde(untagged)]
 enum Number {
     Integer(i64),
-    Float(f64),
+    // We never look at the value, but we need it to deserialize
+    Float(#[allow(dead_code)] f64),
 }
 
 #[derive(Debug, Deserialize)]
@@ -61,14 +62,11 @@ struct Response {
     prime: bool,
 }
 
-pub(crate) fn run(ctx: &Context) -> Result<(), Box<dyn Error>> {
-    let shutdown_signal = TcpServer::new().serve(ctx, handle)?;
-    shutdown_signal.set_as_ctrl_c_handler()?;
-    shutdown_signal.sleep_until_shutdown();
-    Ok(())
+pub(crate) fn serve(ctx: &Context) -> Result<ShutdownSignal, Box<dyn Error>> {
+    StreamServer::new().serve(ctx, handle)
 }
 
-fn handle(stream: &mut TcpStream, _remote_address: &SocketAddr) -> Result<(), Box<dyn Error>> {
+fn handle(stream: &mut Stream, _remote_address: &PeerAddress) -> Result<(), Box<dyn Error>> {
     let reader = BufReader::new(stream.try_clone()?);
 
     for line in reader.lines() {
@@ -121,3 +119,78 @@ pub(crate) fn help(ctx: &Context) -> Result<(), Box<dyn Error>> {
     println!("Usage: {} prime_time", ctx.program_name);
     Ok(())
 }
+
+pub(crate) fn scenarios() -> Vec<Scenario> {
+    let conforming = |number: serde_json::Value, prime: bool| {
+        vec![
+            Step::send(
+                0,
+                format!("{{\"method\":\"isPrime\",\"number\":{}}}\n", number),
+            ),
+            Step::ExpectJsonLine(0, serde_json::json!({"method": "isPrime", "prime": prime})),
+        ]
+    };
+    // A malformed request must get a malformed response (anything will do) and a disconnect
+    let malformed = |request: &str| {
+        vec![
+            Step::send(0, request),
+            Step::expect_matching(0, "", ""),
+            Step::ExpectClosed(0),
+        ]
+    };
+    vec![
+        Scenario {
+            name: "answers conforming requests",
+            transport: Transport::Tcp,
+            steps: [
+                conforming(serde_json::json!(7), true),
+                conforming(serde_json::json!(8), false),
+                conforming(serde_json::json!(-7), false),
+                conforming(serde_json::json!(7.5), false),
+                conforming(serde_json::json!(2147483647u64), true),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+        },
+        Scenario {
+            name: "ignores extra fields",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, "{\"method\":\"isPrime\",\"number\":3,\"extra\":true}\n"),
+                Step::ExpectJsonLine(0, serde_json::json!({"method": "isPrime", "prime": true})),
+            ],
+        },
+        Scenario {
+            name: "rejects invalid JSON",
+            transport: Transport::Tcp,
+            steps: malformed("{\"method\":\"isPrime\",\"number\":\n"),
+        },
+        Scenario {
+            name: "rejects a missing number",
+            transport: Transport::Tcp,
+            steps: malformed("{\"method\":\"isPrime\"}\n"),
+        },
+        Scenario {
+            name: "rejects an unknown method",
+            transport: Transport::Tcp,
+            steps: malformed("{\"method\":\"isPrimal\",\"number\":7}\n"),
+        },
+        Scenario {
+            name: "rejects a number in a string",
+            transport: Transport::Tcp,
+            steps: malformed("{\"method\":\"isPrime\",\"number\":\"7\"}\n"),
+        },
+        Scenario {
+            name: "answers requests before a malformed one",
+            transport: Transport::Tcp,
+            steps: [
+                conforming(serde_json::json!(13), true),
+                malformed("not json\n"),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+        },
+    ]
+}
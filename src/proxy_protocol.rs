@@ -0,0 +1,336 @@
+//! The PROXY protocol, which load balancers use to tell us who they're forwarding a connection for.
+//! Every connection starts with a header in one of two versions: a line of text (v1) or a binary
+//! block (v2). See <https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt>.
+
+use std::{
+    error::Error,
+    io::{self, Read},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::server::{PeerAddress, ReadBefore, Stream};
+
+/// How long a connection has to send its header before we give up on it
+pub(crate) const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest v1 header allowed, including the CRLF
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Long enough to tell the versions apart, and short enough that we never read past a v1 header
+const SIGNATURE_LENGTH: usize = 12;
+
+/// Read PROXY_PROTOCOL from the environment: `on` to require a header on every connection, or
+/// `off` (the default) to take connections at face value.
+pub(crate) fn enabled_from_env() -> Result<bool, Box<dyn Error>> {
+    match std::env::var("PROXY_PROTOCOL").ok().as_deref() {
+        None | Some("off") => Ok(false),
+        Some("on") => Ok(true),
+        Some(other) => {
+            Err(format!("Unknown PROXY_PROTOCOL '{}', expected on or off", other).into())
+        }
+    }
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Read a header from the start of `stream`, giving up if it isn't all there within `timeout`.
+/// Returns the address the connection is really from, or `None` if the header doesn't say
+/// (UNKNOWN or LOCAL).
+pub(crate) fn read_from_stream(
+    stream: &mut Stream,
+    timeout: Duration,
+) -> io::Result<Option<PeerAddress>> {
+    let mut header = ReadBefore::new(&*stream, timeout);
+    let result = read_header(&mut header).map_err(|e| match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(
+            io::ErrorKind::TimedOut,
+            "timed out waiting for PROXY header",
+        ),
+        _ => e,
+    });
+    stream.set_read_timeout(None)?;
+    result
+}
+
+/// Read a v1 or v2 header, and nothing after it, from `reader`.
+pub(crate) fn read_header(reader: &mut impl Read) -> io::Result<Option<PeerAddress>> {
+    let mut start = [0u8; SIGNATURE_LENGTH];
+    reader.read_exact(&mut start)?;
+    if start.starts_with(V1_PREFIX) {
+        read_v1(reader, &start)
+    } else if &start == V2_SIGNATURE {
+        read_v2(reader)
+    } else {
+        Err(invalid("connection did not start with a PROXY header"))
+    }
+}
+
+fn read_v1(reader: &mut impl Read, start: &[u8]) -> io::Result<Option<PeerAddress>> {
+    let mut line = start.to_vec();
+    // Byte at a time, so whatever follows the header is left for the handler
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY v1 header is too long"));
+        }
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[V1_PREFIX.len()..line.len() - 2])
+        .map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        // The rest of the line is meaningless, so it can be anything
+        ["UNKNOWN", ..] => Ok(None),
+        ["TCP4", source, _, source_port, destination_port] => {
+            parse_v1_port(destination_port)?;
+            Ok(Some(PeerAddress::Inet(SocketAddr::new(
+                parse_v1_address::<Ipv4Addr>(source)?.into(),
+                parse_v1_port(source_port)?,
+            ))))
+        }
+        ["TCP6", source, _, source_port, destination_port] => {
+            parse_v1_port(destination_port)?;
+            Ok(Some(PeerAddress::Inet(SocketAddr::new(
+                parse_v1_address::<Ipv6Addr>(source)?.into(),
+                parse_v1_port(source_port)?,
+            ))))
+        }
+        _ => Err(invalid(format!("malformed PROXY v1 header '{}'", line))),
+    }
+}
+
+fn parse_v1_address<A: FromStr>(address: &str) -> io::Result<A> {
+    address
+        .parse()
+        .map_err(|_| invalid(format!("invalid address '{}' in PROXY v1 header", address)))
+}
+
+fn parse_v1_port(port: &str) -> io::Result<u16> {
+    if port.len() > 1 && port.starts_with('0') || !port.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid(format!(
+            "invalid port '{}' in PROXY v1 header",
+            port
+        )));
+    }
+    port.parse()
+        .map_err(|_| invalid(format!("invalid port '{}' in PROXY v1 header", port)))
+}
+
+fn read_v2(reader: &mut impl Read) -> io::Result<Option<PeerAddress>> {
+    let mut fixed = [0u8; 4];
+    reader.read_exact(&mut fixed)?;
+    let [version_and_command, family_and_protocol, length_high, length_low] = fixed;
+    let mut addresses = vec![0u8; u16::from_be_bytes([length_high, length_low]) as usize];
+    reader.read_exact(&mut addresses)?;
+
+    if version_and_command >> 4 != 2 {
+        return Err(invalid(format!(
+            "unsupported PROXY header version {}",
+            version_and_command >> 4
+        )));
+    }
+    match version_and_command & 0x0f {
+        // A health check from the balancer itself, so the connection is what it looks like
+        0x0 => return Ok(None),
+        0x1 => {}
+        command => return Err(invalid(format!("unknown PROXY v2 command {}", command))),
+    }
+    let (family, protocol) = (family_and_protocol >> 4, family_and_protocol & 0x0f);
+    if family != 0x0 && protocol != 0x1 {
+        return Err(invalid(format!(
+            "PROXY v2 header is for transport protocol {}, not a stream",
+            protocol
+        )));
+    }
+    let too_short = || invalid("PROXY v2 header is too short for its address family");
+    // Addresses are source then destination, followed by any TLVs we don't care about
+    match family {
+        0x0 => Ok(None),
+        0x1 => {
+            let addresses = addresses.get(..12).ok_or_else(too_short)?;
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(PeerAddress::Inet(SocketAddr::new(ip.into(), port))))
+        }
+        0x2 => {
+            let addresses = addresses.get(..36).ok_or_else(too_short)?;
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(PeerAddress::Inet(SocketAddr::new(ip.into(), port))))
+        }
+        0x3 => {
+            let source = addresses.get(..108).ok_or_else(too_short)?;
+            let path = &source[..source.iter().position(|&b| b == 0).unwrap_or(source.len())];
+            Ok(Some(PeerAddress::Unix(if path.is_empty() {
+                None
+            } else {
+                Some(PathBuf::from(String::from_utf8_lossy(path).into_owned()))
+            })))
+        }
+        family => Err(invalid(format!(
+            "unknown PROXY v2 address family {}",
+            family
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn parse(header: &[u8]) -> io::Result<Option<PeerAddress>> {
+        read_header(&mut &header[..])
+    }
+
+    fn inet(address: &str) -> Option<PeerAddress> {
+        Some(PeerAddress::Inet(address.parse().unwrap()))
+    }
+
+    fn v2(command: u8, family_and_protocol: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family_and_protocol);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn parses_v1_headers() {
+        assert_eq!(
+            parse(b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n").unwrap(),
+            inet("192.0.2.1:56324")
+        );
+        assert_eq!(
+            parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 65535 9000\r\n").unwrap(),
+            inet("[2001:db8::1]:65535")
+        );
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert_eq!(
+            parse(b"PROXY UNKNOWN ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_v1_headers() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.7 56324\r\n"[..],
+            b"PROXY TCP4 192.0.2.1  198.51.100.7 56324 443\r\n",
+            b"PROXY TCP4 2001:db8::1 198.51.100.7 56324 443\r\n",
+            b"PROXY TCP6 192.0.2.1 198.51.100.7 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.7 65536 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.7 056324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.7 +5632 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.7 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\n",
+            b"proxy TCP4 192.0.2.1 198.51.100.7 56324 443\r\n",
+            b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        ] {
+            let error = parse(header).expect_err(&header.escape_ascii().to_string());
+            assert_ne!(error.kind(), io::ErrorKind::TimedOut);
+        }
+
+        let mut too_long = b"PROXY UNKNOWN ".to_vec();
+        too_long.resize(200, b'x');
+        too_long.extend_from_slice(b"\r\n");
+        assert!(parse(&too_long).is_err());
+    }
+
+    #[test]
+    fn parses_v2_headers() {
+        let mut tcp4 = vec![192, 0, 2, 1, 198, 51, 100, 7];
+        tcp4.extend_from_slice(&56324u16.to_be_bytes());
+        tcp4.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(
+            parse(&v2(0x1, 0x11, &tcp4)).unwrap(),
+            inet("192.0.2.1:56324")
+        );
+
+        let mut tcp6 = Ipv6Addr::from_str("2001:db8::1").unwrap().octets().to_vec();
+        tcp6.extend_from_slice(&Ipv6Addr::from_str("2001:db8::2").unwrap().octets());
+        tcp6.extend_from_slice(&9000u16.to_be_bytes());
+        tcp6.extend_from_slice(&443u16.to_be_bytes());
+        // A TLV, which we skip over
+        tcp6.extend_from_slice(&[0x04, 0x00, 0x01, 0xff]);
+        assert_eq!(
+            parse(&v2(0x1, 0x21, &tcp6)).unwrap(),
+            inet("[2001:db8::1]:9000")
+        );
+
+        // UNSPEC and LOCAL both mean the connection is what it looks like
+        assert_eq!(parse(&v2(0x1, 0x00, &[])).unwrap(), None);
+        assert_eq!(parse(&v2(0x0, 0x11, &tcp4)).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_v2_headers() {
+        let tcp4 = [192, 0, 2, 1, 198, 51, 100, 7, 0, 80, 1, 187];
+        let mut wrong_version = v2(0x1, 0x11, &tcp4);
+        wrong_version[12] = 0x11;
+        for header in [
+            wrong_version,
+            v2(0x2, 0x11, &tcp4),
+            v2(0x1, 0x11, &tcp4[..11]),
+            v2(0x1, 0x21, &tcp4),
+            v2(0x1, 0x12, &tcp4),
+            v2(0x1, 0x41, &tcp4),
+        ] {
+            assert!(parse(&header).is_err(), "{}", header.escape_ascii());
+        }
+        // Truncated, so the length promises more than there is
+        let truncated = v2(0x1, 0x11, &tcp4);
+        assert!(parse(&truncated[..truncated.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn gives_up_on_slow_headers() {
+        let (client, server) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut server = Stream::from(server);
+        (&client).write_all(b"PROXY TCP4 ").unwrap();
+        let error = read_from_stream(&mut server, Duration::from_millis(100)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn gives_up_on_headers_trickled_in_under_the_timeout() {
+        let (client, server) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut server = Stream::from(server);
+        let timeout = Duration::from_millis(200);
+        let trickle = std::thread::spawn(move || {
+            for byte in b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n" {
+                if (&client).write_all(&[*byte]).is_err() {
+                    break;
+                }
+                std::thread::sleep(timeout / 4);
+            }
+        });
+        let start = std::time::Instant::now();
+        let error = read_from_stream(&mut server, timeout).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < timeout * 2);
+        drop(server);
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn leaves_the_rest_of_the_stream_alone() {
+        for mut header in [
+            b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\n".to_vec(),
+            v2(0x1, 0x11, &[192, 0, 2, 1, 198, 51, 100, 7, 0, 80, 1, 187]),
+        ] {
+            header.extend_from_slice(b"hello\n");
+            let mut reader = &header[..];
+            read_header(&mut reader).unwrap();
+            assert_eq!(reader, b"hello\n");
+        }
+    }
+}
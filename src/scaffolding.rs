@@ -1,41 +1,155 @@
 use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::admin::ConnectionRegistry;
+use crate::handoff::Handoff;
+use crate::limits::LimitsConfig;
+use crate::metrics::Metrics;
+use crate::server::{Health, ShutdownSignal, WorkerPoolConfig};
+use crate::socket_activation::InheritedSocket;
 
 pub(crate) struct Context {
     pub(crate) program_name: String,
     pub(crate) problem: Option<String>,
     pub(crate) problem_arguments: VecDeque<String>,
-    pub(crate) bind_address: String,
+    /// Every address to listen on; the server shares one worker pool and shutdown between them
+    pub(crate) bind_addresses: Vec<String>,
+    /// Sockets our parent is listening on for us; when set, these are served instead of
+    /// `bind_addresses`
+    pub(crate) inherited_sockets: Option<Vec<InheritedSocket>>,
+    pub(crate) worker_pool: WorkerPoolConfig,
+    /// Whether every connection starts with a PROXY protocol header giving the real client address
+    pub(crate) proxy_protocol: bool,
+    /// Who may connect, and how often
+    pub(crate) limits: LimitsConfig,
+    /// Shared by every server started with this context
+    pub(crate) metrics: Arc<Metrics>,
+    /// Every connection being handled by servers started with this context
+    pub(crate) connections: Arc<ConnectionRegistry>,
+    /// Whether the listeners of servers started with this context are working
+    pub(crate) health: Arc<Health>,
+    /// The listeners to pass on if we restart
+    pub(crate) handoff: Arc<Handoff>,
 }
 
 impl Context {
+    /// `bind_address` is a comma-separated list, for example `0.0.0.0:9000,[::]:9000`.
     pub(crate) fn new(mut args: VecDeque<String>, bind_address: String) -> Self {
         let program_name = args.pop_front().expect("We expect argv[0]");
         let problem = args.pop_front();
         let problem_arguments = args;
+        let metrics = Arc::new(Metrics::new(problem.as_deref().unwrap_or_default()));
         Self {
             program_name,
             problem,
             problem_arguments,
-            bind_address,
+            bind_addresses: bind_address
+                .split(',')
+                .map(str::trim)
+                .filter(|address| !address.is_empty())
+                .map(String::from)
+                .collect(),
+            inherited_sockets: None,
+            worker_pool: WorkerPoolConfig::default(),
+            proxy_protocol: false,
+            limits: LimitsConfig::default(),
+            metrics,
+            connections: Arc::new(ConnectionRegistry::new()),
+            health: Arc::new(Health::default()),
+            handoff: Arc::new(Handoff::new()),
+        }
+    }
+
+    pub(crate) fn with_worker_pool(self, worker_pool: WorkerPoolConfig) -> Self {
+        Self {
+            worker_pool,
+            ..self
+        }
+    }
+
+    pub(crate) fn with_proxy_protocol(self, proxy_protocol: bool) -> Self {
+        Self {
+            proxy_protocol,
+            ..self
+        }
+    }
+
+    pub(crate) fn with_limits(self, limits: LimitsConfig) -> Self {
+        Self { limits, ..self }
+    }
+
+    pub(crate) fn with_inherited_sockets(
+        self,
+        inherited_sockets: Option<Vec<InheritedSocket>>,
+    ) -> Self {
+        Self {
+            inherited_sockets,
+            ..self
         }
     }
 }
 
+/// Serve a problem with `serve` until we're told to shut down, for example by ctrl-c.
+pub(crate) fn run(
+    ctx: &Context,
+    serve: fn(&Context) -> Result<ShutdownSignal, Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let shutdown_signal = serve(ctx)?;
+    // If we're taking over from another process, it can drain now
+    ctx.handoff.notify_ready();
+    shutdown_signal.set_as_ctrl_c_handler()?;
+    shutdown_signal.sleep_until_shutdown()
+}
+
+/// A context for serving `problem` on a port of its own on localhost, and that port's address.
+/// The socket is bound before the server starts, so no other test can take the port meanwhile.
+#[cfg(test)]
+pub(crate) fn test_context(problem: &str) -> (Context, std::net::SocketAddr) {
+    let listener =
+        std::net::TcpListener::bind("127.0.0.1:0").expect("Should be able to bind a free port");
+    let address = listener.local_addr().unwrap();
+    (adopting_context(problem, vec![listener.into()]), address)
+}
+
+/// A context for serving `problem` on `sockets`, which are already bound. Like a supervisor, the
+/// context keeps them open until it's dropped.
+#[cfg(test)]
+pub(crate) fn adopting_context(problem: &str, sockets: Vec<std::os::fd::OwnedFd>) -> Context {
+    Context::new(
+        VecDeque::from([String::from("protohackers"), String::from(problem)]),
+        String::new(),
+    )
+    .with_inherited_sockets(Some(
+        sockets.into_iter().map(InheritedSocket::from).collect(),
+    ))
+}
+
 /// Generate boilerplate for each problem, permitting dispatch between them.
 /// Requires a whitespace-separated list of problems. Each problem must have
 /// a module of the same name, which should NOT have a `mod` statement otherwise.
 ///
-/// Each module must have two functions with the following signatures:
+/// Each module must have three functions with the following signatures:
 ///
-///     pub(crate) fn run(ctx: &Context) -> Result<(), Box<dyn std::error::Error>>
+///     pub(crate) fn serve(ctx: &Context) -> Result<crate::server::ShutdownSignal, Box<dyn std::error::Error>>
 ///     pub(crate) fn help(ctx: &Context) -> Result<(), Box<dyn std::error::Error>>
+///     pub(crate) fn scenarios() -> Vec<crate::check::Scenario>
 macro_rules! problem_list {
     { $($name:ident)+ } => {
         $(mod $name;)+
 
         fn get_problem_handler(problem_name: &str) -> Option<fn(&Context) -> Result<(), Box<dyn Error>>> {
             match problem_name {
-                $(stringify!($name) => Some($name::run),)+
+                $(stringify!($name) => Some(|ctx| $crate::scaffolding::run(ctx, $name::serve)),)+
+                _ => None,
+            }
+        }
+
+        #[cfg(test)]
+        fn get_problem_server(
+            problem_name: &str,
+        ) -> Option<fn(&Context) -> Result<server::ShutdownSignal, Box<dyn Error>>> {
+            match problem_name {
+                $(stringify!($name) => Some($name::serve),)+
                 _ => None,
             }
         }
@@ -47,6 +161,13 @@ macro_rules! problem_list {
             }
         }
 
+        fn get_problem_scenarios(problem_name: &str) -> Option<fn() -> Vec<check::Scenario>> {
+            match problem_name {
+                $(stringify!($name) => Some($name::scenarios),)+
+                _ => None,
+            }
+        }
+
         fn get_problem_names() -> Vec<&'static str> {
             vec![$(stringify!($name)),+]
         }
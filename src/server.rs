@@ -1,11 +1,24 @@
 use std::{
-    cmp::max,
+    any::Any,
+    collections::{BTreeMap, HashMap},
     error::Error,
-    io,
-    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    fmt::Display,
+    fs,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+        unix::{
+            fs::FileTypeExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    str::FromStr,
     sync::{
         atomic::{AtomicUsize, Ordering},
-        mpsc, Arc, OnceLock,
+        mpsc, Arc, Condvar, Mutex, MutexGuard,
     },
     thread,
     time::{Duration, Instant},
@@ -13,21 +26,406 @@ use std::{
 
 use log::{as_debug, as_display};
 
-use crate::scaffolding::Context;
+use crate::{
+    admin::ConnectionRegistry,
+    limits::{ConnectionPermit, LimitExceeded, Limiter},
+    metrics::{ConnectionCounters, Metrics},
+    proxy_protocol,
+    scaffolding::Context,
+    socket_activation,
+};
 
 const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to log that we're still waiting for connections to finish while draining
+const DRAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+/// How long handlers get to return after their connections are force-closed at the shutdown deadline
+const FORCE_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
 const SLEEP_DURATION: Duration = Duration::from_millis(500);
+const WAKE_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long accepting blocks before checking whether we've started draining
+const ACCEPT_TIMEOUT: Duration = Duration::from_millis(250);
+/// The first wait before retrying after a transient socket error, which doubles each time it recurs
+const MIN_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+/// The largest payload an IPv4 UDP datagram can carry
+const DEFAULT_MAX_DATAGRAM_SIZE: usize = 65507;
+const DEFAULT_WORKERS: usize = 1024;
+const DEFAULT_QUEUE_CAPACITY: usize = 128;
+/// Bind addresses with this prefix are paths to Unix domain sockets
+pub(crate) const UNIX_PREFIX: &str = "unix:";
+
+/// Where a connection came from, or where a server is listening.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PeerAddress {
+    Inet(SocketAddr),
+    /// A Unix domain socket, and its path if it has one. Clients usually don't.
+    Unix(Option<PathBuf>),
+}
+
+impl Display for PeerAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddress::Inet(address) => write!(f, "{}", address),
+            PeerAddress::Unix(Some(path)) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+            PeerAddress::Unix(None) => write!(f, "{}(unnamed)", UNIX_PREFIX),
+        }
+    }
+}
+
+impl PeerAddress {
+    /// The IP address, unless this is a Unix domain socket.
+    pub(crate) fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddress::Inet(address) => Some(address.ip()),
+            PeerAddress::Unix(_) => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddress {
+    fn from(address: SocketAddr) -> Self {
+        PeerAddress::Inet(address)
+    }
+}
+
+impl From<std::os::unix::net::SocketAddr> for PeerAddress {
+    fn from(address: std::os::unix::net::SocketAddr) -> Self {
+        PeerAddress::Unix(address.as_pathname().map(Path::to_path_buf))
+    }
+}
+
+/// A connection accepted by a [`StreamServer`], over TCP or a Unix domain socket.
+pub(crate) struct Stream {
+    socket: Socket,
+    /// Where to count the bytes read and written, once the server has attached its counters
+    counters: Option<Arc<ConnectionCounters>>,
+}
+
+enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Self {
+            socket: Socket::Tcp(stream),
+            counters: None,
+        }
+    }
+}
+
+impl From<UnixStream> for Stream {
+    fn from(stream: UnixStream) -> Self {
+        Self {
+            socket: Socket::Unix(stream),
+            counters: None,
+        }
+    }
+}
+
+impl Stream {
+    /// Connect to `address`, which is either `host:port` or `unix:/path/to/socket`.
+    pub(crate) fn connect(address: &str) -> io::Result<Self> {
+        match address.strip_prefix(UNIX_PREFIX) {
+            Some(path) => UnixStream::connect(path).map(Stream::from),
+            None => TcpStream::connect(address).map(Stream::from),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        let socket = match &self.socket {
+            Socket::Tcp(stream) => stream.try_clone().map(Socket::Tcp),
+            Socket::Unix(stream) => stream.try_clone().map(Socket::Unix),
+        }?;
+        Ok(Self {
+            socket,
+            counters: self.counters.clone(),
+        })
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match &self.socket {
+            Socket::Tcp(stream) => stream.shutdown(how),
+            Socket::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.socket {
+            Socket::Tcp(stream) => stream.set_read_timeout(timeout),
+            Socket::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    /// Whether the peer has hung up, checked without waiting or consuming anything it has sent.
+    pub(crate) fn is_closed_by_peer(&self) -> bool {
+        let fd = match &self.socket {
+            Socket::Tcp(stream) => stream.as_raw_fd(),
+            Socket::Unix(stream) => stream.as_raw_fd(),
+        };
+        let mut byte = 0u8;
+        // SAFETY: byte has room for the one byte we ask for
+        let peeked = unsafe {
+            libc::recv(
+                fd,
+                &mut byte as *mut u8 as *mut libc::c_void,
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        match peeked {
+            0 => true,
+            1.. => false,
+            _ => !matches!(
+                io::Error::last_os_error().kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+            ),
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = match &self.socket {
+            Socket::Tcp(stream) => (&*stream).read(buf),
+            Socket::Unix(stream) => (&*stream).read(buf),
+        }?;
+        if let Some(counters) = &self.counters {
+            counters.read(bytes_read);
+        }
+        Ok(bytes_read)
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes_written = match &self.socket {
+            Socket::Tcp(stream) => (&*stream).write(buf),
+            Socket::Unix(stream) => (&*stream).write(buf),
+        }?;
+        if let Some(counters) = &self.counters {
+            counters.written(bytes_written);
+        }
+        Ok(bytes_written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.socket {
+            Socket::Tcp(stream) => (&*stream).flush(),
+            Socket::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
 
-type Handler<T> = fn(&mut T, &SocketAddr) -> Result<(), Box<dyn Error>>;
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+/// Streams whose reads can be made to time out.
+pub(crate) trait ReadTimeout {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl ReadTimeout for Stream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Stream::set_read_timeout(self, timeout)
+    }
+}
+
+impl ReadTimeout for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Reads from a stream until a deadline, however slowly the other end sends: each read only
+/// waits for whatever time is left, unlike a read timeout, which a byte now and then resets.
+pub(crate) struct ReadBefore<'a, S> {
+    stream: &'a S,
+    deadline: Option<Instant>,
+}
+
+impl<'a, S: ReadTimeout> ReadBefore<'a, S> {
+    /// Read from `stream` for no longer than `timeout` from now.
+    pub(crate) fn new(stream: &'a S, timeout: Duration) -> Self {
+        Self {
+            stream,
+            deadline: Some(Instant::now() + timeout),
+        }
+    }
+
+    /// Let reads wait as long as they like from now on.
+    pub(crate) fn clear_deadline(&mut self) -> io::Result<()> {
+        self.deadline = None;
+        self.stream.set_read_timeout(None)
+    }
+}
+
+impl<'a, S: ReadTimeout> Read for ReadBefore<'a, S>
+where
+    &'a S: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.stream.set_read_timeout(Some(remaining))?;
+        }
+        let mut stream = self.stream;
+        stream.read(buf)
+    }
+}
+
+/// Handles every connection a server accepts. Implementations can own state shared between
+/// connections; `serve` keeps a single instance for the life of the server.
+///
+/// Any `Fn(&mut T, &PeerAddress) -> Result<(), Box<dyn Error>>`, including a plain `fn`, is a
+/// `ConnectionHandler` with no hooks.
+pub(crate) trait ConnectionHandler<T>: Send + Sync + 'static {
+    /// Called once for each address the server listens on, once it has started listening.
+    fn on_listen(&self, _local_address: &PeerAddress) {}
+
+    /// Called on a worker thread for each connection, which is closed when this returns.
+    /// Long-running handlers should watch `shutdown_signal`, by polling it or registering a
+    /// callback with [`ShutdownSignal::on_shutdown`]; connections still open at the shutdown
+    /// deadline are closed underneath them where the server supports it.
+    fn handle(
+        &self,
+        connection: &mut T,
+        remote_address: &PeerAddress,
+        shutdown_signal: &ShutdownSignal,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Called once, when the server has finished shutting down.
+    fn on_shutdown(&self) {}
+}
+
+impl<T, F> ConnectionHandler<T> for F
+where
+    F: Fn(&mut T, &PeerAddress) -> Result<(), Box<dyn Error>> + Send + Sync + 'static,
+{
+    fn handle(
+        &self,
+        connection: &mut T,
+        remote_address: &PeerAddress,
+        _shutdown_signal: &ShutdownSignal,
+    ) -> Result<(), Box<dyn Error>> {
+        self(connection, remote_address)
+    }
+}
+
+/// Closes a connection from another thread, so that a handler blocked reading from it returns.
+pub(crate) type ForceCloser = Box<dyn FnOnce() + Send>;
+
+type ShutdownCallback = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct ShutdownCallbacks {
+    next_id: u64,
+    /// Each callback runs once we reach its phase
+    callbacks: HashMap<u64, (ShutdownPhase, ShutdownCallback)>,
+}
+
+/// Unregisters a shutdown callback when dropped, if it hasn't already run.
+#[must_use = "the callback is unregistered when this is dropped"]
+pub(crate) struct ShutdownCallbackGuard {
+    callbacks: Arc<Mutex<ShutdownCallbacks>>,
+    id: u64,
+}
+
+impl Drop for ShutdownCallbackGuard {
+    fn drop(&mut self) {
+        self.callbacks
+            .lock()
+            .expect("Shutdown callbacks should not be poisoned")
+            .callbacks
+            .remove(&self.id);
+    }
+}
+
+/// The phases a server goes through, in order. A server can skip draining, but never goes back.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum ShutdownPhase {
+    Running,
+    /// No longer accepting, but leaving open connections to finish however long they take
+    Draining,
+    ShuttingDown,
+    Complete,
+}
 
+/// Tracks a server through shutdown. Waiting for a phase blocks on a condition variable,
+/// so waiters wake as soon as it changes.
 pub struct ShutdownSignal {
-    once: Arc<OnceLock<OnceLock<()>>>,
+    phase: Arc<(Mutex<ShutdownPhase>, Condvar)>,
+    callbacks: Arc<Mutex<ShutdownCallbacks>>,
+    /// Why we're shutting down, if it's because something went wrong
+    failure: Arc<Mutex<Option<String>>>,
 }
 
 impl ShutdownSignal {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
-            once: Arc::new(OnceLock::new()),
+            phase: Arc::new((Mutex::new(ShutdownPhase::Running), Condvar::new())),
+            callbacks: Arc::new(Mutex::new(ShutdownCallbacks::default())),
+            failure: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Run `callback` when shutdown starts, or straight away if it already has. The callback
+    /// runs on whichever thread starts the shutdown, so it should be quick. Draining doesn't
+    /// count: handlers are left alone until the drain is over.
+    pub(crate) fn on_shutdown(
+        &self,
+        callback: impl FnOnce() + Send + 'static,
+    ) -> ShutdownCallbackGuard {
+        self.on_phase(ShutdownPhase::ShuttingDown, callback)
+    }
+
+    /// Run `callback` when we stop accepting connections, because we've started draining or
+    /// shutting down, or straight away if we already have.
+    pub(crate) fn on_stop_accepting(
+        &self,
+        callback: impl FnOnce() + Send + 'static,
+    ) -> ShutdownCallbackGuard {
+        self.on_phase(ShutdownPhase::Draining, callback)
+    }
+
+    fn on_phase(
+        &self,
+        phase: ShutdownPhase,
+        callback: impl FnOnce() + Send + 'static,
+    ) -> ShutdownCallbackGuard {
+        let mut callbacks = self
+            .callbacks
+            .lock()
+            .expect("Shutdown callbacks should not be poisoned");
+        let id = callbacks.next_id;
+        callbacks.next_id += 1;
+        // Checked with the lock held, so advance either sees this callback or we see it advanced
+        if *self.phase() >= phase {
+            drop(callbacks);
+            callback();
+        } else {
+            callbacks.callbacks.insert(id, (phase, Box::new(callback)));
+        }
+        ShutdownCallbackGuard {
+            callbacks: self.callbacks.clone(),
+            id,
         }
     }
 
@@ -39,271 +437,2975 @@ impl ShutdownSignal {
                     reason = "ctrl-c received";
                     "Shutting down"
                 );
+            } else if cloned.stop_draining() {
+                log::info!(
+                    reason = "ctrl-c received";
+                    "No longer waiting for connections to drain, shutting down"
+                );
             } else {
                 log::info!("Already shutting down");
             }
         })
     }
 
-    pub fn sleep_until_shutdown(&self) {
-        while !self.is_shutdown_complete() {
-            std::thread::sleep(std::time::Duration::from_millis(100));
+    fn phase(&self) -> MutexGuard<'_, ShutdownPhase> {
+        self.phase
+            .0
+            .lock()
+            .expect("Shutdown phase should not be poisoned")
+    }
+
+    /// Block until `phase` is no longer `from`, or `timeout` passes. Returns true if it changed.
+    fn wait_while(&self, from: &[ShutdownPhase], timeout: Option<Duration>) -> bool {
+        let (_, changed) = &*self.phase;
+        let phase = self.phase();
+        let phase = match timeout {
+            None => changed
+                .wait_while(phase, |phase| from.contains(phase))
+                .expect("Shutdown phase should not be poisoned"),
+            Some(timeout) => {
+                changed
+                    .wait_timeout_while(phase, timeout, |phase| from.contains(phase))
+                    .expect("Shutdown phase should not be poisoned")
+                    .0
+            }
+        };
+        !from.contains(&phase)
+    }
+
+    fn set_phase(&self, from: ShutdownPhase, to: ShutdownPhase) -> bool {
+        let mut phase = self.phase();
+        if *phase != from {
+            return false;
+        }
+        *phase = to;
+        self.phase.1.notify_all();
+        true
+    }
+
+    /// Move from `from` to `to`, running every callback waiting for a phase up to `to`.
+    fn advance(&self, from: ShutdownPhase, to: ShutdownPhase) -> bool {
+        let due = {
+            let mut callbacks = self
+                .callbacks
+                .lock()
+                .expect("Shutdown callbacks should not be poisoned");
+            if !self.set_phase(from, to) {
+                return false;
+            }
+            let due_ids = callbacks
+                .callbacks
+                .iter()
+                .filter(|(_, (phase, _))| *phase <= to)
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>();
+            due_ids
+                .into_iter()
+                .filter_map(|id| callbacks.callbacks.remove(&id))
+                .collect::<Vec<_>>()
+        };
+        for (_, callback) in due {
+            callback();
+        }
+        true
+    }
+
+    /// Block until shutdown completes. Returns an error if we shut down because of one.
+    pub fn sleep_until_shutdown(&self) -> Result<(), Box<dyn Error>> {
+        self.wait_while(
+            &[
+                ShutdownPhase::Running,
+                ShutdownPhase::Draining,
+                ShutdownPhase::ShuttingDown,
+            ],
+            None,
+        );
+        match self.failure().clone() {
+            Some(reason) => Err(reason.into()),
+            None => Ok(()),
         }
     }
 
     #[allow(dead_code)]
     pub fn sleep_until_shutdown_or_timeout(&mut self, timeout: Duration) -> bool {
-        let stop_at = Instant::now() + timeout;
-        while !self.is_shutdown_complete() && Instant::now() < stop_at {
-            std::thread::sleep(max(timeout / 100, SLEEP_DURATION));
+        let shutdown_due_to_timeout = !self.wait_while(
+            &[
+                ShutdownPhase::Running,
+                ShutdownPhase::Draining,
+                ShutdownPhase::ShuttingDown,
+            ],
+            Some(timeout),
+        );
+        if !self.start_shutdown() {
+            self.stop_draining();
         }
-        let shutdown_due_to_timeout = !self.is_shutdown_complete();
-        self.start_shutdown();
         self.complete_shutdown();
         shutdown_due_to_timeout
     }
 
+    /// Whether handlers should wrap up; not while draining.
     pub fn is_shutdown_initiated(&self) -> bool {
-        self.once.get().is_some()
+        *self.phase() >= ShutdownPhase::ShuttingDown
+    }
+
+    /// Whether servers should take on new connections or datagrams.
+    pub(crate) fn is_accepting(&self) -> bool {
+        *self.phase() == ShutdownPhase::Running
     }
 
     pub fn is_shutdown_complete(&self) -> bool {
-        self.once
-            .get()
-            .map(|inner| inner.get().is_some())
-            .unwrap_or(false)
+        *self.phase() == ShutdownPhase::Complete
     }
 
     pub fn start_shutdown(&mut self) -> bool {
-        self.once.set(OnceLock::new()).is_ok()
+        self.advance(ShutdownPhase::Running, ShutdownPhase::ShuttingDown)
+    }
+
+    /// Shut down because something has gone wrong which we can't recover from. Only the first
+    /// failure is kept.
+    pub(crate) fn fail(&mut self, reason: impl Into<String>) {
+        self.failure().get_or_insert_with(|| reason.into());
+        if !self.start_shutdown() {
+            self.stop_draining();
+        }
+    }
+
+    fn failure(&self) -> MutexGuard<'_, Option<String>> {
+        self.failure
+            .lock()
+            .expect("Shutdown failure should not be poisoned")
+    }
+
+    /// Stop accepting, but leave open connections to finish however long they take; the
+    /// server shuts down once they have. Returns false if we'd already stopped accepting.
+    pub(crate) fn start_drain(&mut self) -> bool {
+        self.advance(ShutdownPhase::Running, ShutdownPhase::Draining)
+    }
+
+    /// Turn a drain into an ordinary shutdown, with a deadline. Returns false if we weren't draining.
+    pub(crate) fn stop_draining(&mut self) -> bool {
+        self.advance(ShutdownPhase::Draining, ShutdownPhase::ShuttingDown)
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        *self.phase() == ShutdownPhase::Draining
     }
 
+    /// Returns `None` if shutdown hasn't started, otherwise whether this call completed it.
     pub fn complete_shutdown(&mut self) -> Option<bool> {
-        self.once.get().map(|inner| inner.set(()).is_ok())
+        match *self.phase() {
+            ShutdownPhase::Running | ShutdownPhase::Draining => return None,
+            ShutdownPhase::Complete => return Some(false),
+            ShutdownPhase::ShuttingDown => {}
+        }
+        Some(self.set_phase(ShutdownPhase::ShuttingDown, ShutdownPhase::Complete))
     }
 }
 
 impl Clone for ShutdownSignal {
     fn clone(&self) -> Self {
         Self {
-            once: self.once.clone(),
+            phase: self.phase.clone(),
+            callbacks: self.callbacks.clone(),
+            failure: self.failure.clone(),
         }
     }
 }
 
-pub(crate) trait Server {
-    type Listener: Send + 'static;
-    type ConnectionLike: Send + 'static;
+/// Whether every listener is working, for anything that wants to know if we're healthy.
+#[derive(Default)]
+pub(crate) struct Health {
+    /// What's wrong with each listener that has a problem
+    problems: Mutex<BTreeMap<String, String>>,
+}
 
-    fn serve(
-        &self,
-        ctx: &Context,
-        handler: Handler<Self::ConnectionLike>,
-    ) -> Result<ShutdownSignal, Box<dyn Error>> {
-        let active_threads = Arc::new(AtomicUsize::new(0));
-        let listener = Self::get_listener(ctx.bind_address.as_str())?;
-        let shutdown_signal = ShutdownSignal::new();
-        let mut shutdown_signal_clone = shutdown_signal.clone();
-        let mut shutdown_signal_clone_for_accept_and_forward_thread = shutdown_signal.clone();
-        let local_address = Self::get_local_address(&listener)?;
+impl Health {
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.locked_problems().is_empty()
+    }
 
-        log::info!(
-            address = as_display!(local_address),
-            pid = as_display!(std::process::id());
-            "Listening"
-        );
+    /// Each listener with a problem, and what it is.
+    pub(crate) fn problems(&self) -> Vec<(String, String)> {
+        self.locked_problems()
+            .iter()
+            .map(|(listener, problem)| (listener.clone(), problem.clone()))
+            .collect()
+    }
 
-        thread::Builder::new()
-            .name("server-controller".into())
-            .spawn(move || {
-                let (request_sender, request_receiver) = mpsc::channel::<(Self::ConnectionLike, SocketAddr)>();
-                if thread::Builder::new()
-                    .name("accept-and-forward".into())
-                    .spawn(move || {
-                        loop {
-                            match Self::pump(&listener) {
-                                Ok(pump_result) => {
-                                    if let Err(e) = request_sender.send(pump_result) {
-                                        if shutdown_signal_clone_for_accept_and_forward_thread.start_shutdown() {
-                                            log::error!(
-                                                location = "accept-and-forward thread -> pump loop -> sending connection to request receiver",
-                                                error = as_display!(e),
-                                                reason = "error sending connection to request receiver";
-                                                "Shutting down"
-                                            );
-                                        }
-                                        break;
-                                    }
-                                },
-                                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
-                                    log::debug!(
-                                        location = "accept-and-forward thread -> pump loop -> result of pumping the listener",
-                                        error = as_display!(e);
-                                        "std::io::ErrorKind::Interrupted received, continuing"
-                                    );
-                                }
-                                Err(e) => {
-                                    log::error!(
-                                        location = "accept-and-forward thread -> pump loop -> result of pumping the listener",
-                                        error = as_display!(e);
-                                        "Error accepting connection; exiting accept-and-forward thread"
-                                    );
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                ).is_err() {
-                    log::error!("Unable to spawn thread to accept connections");
-                }
+    fn set_problem(&self, listener: &PeerAddress, problem: String) {
+        self.locked_problems().insert(listener.to_string(), problem);
+    }
 
-                while !shutdown_signal_clone.is_shutdown_initiated() {
-                    match request_receiver.recv_timeout(SLEEP_DURATION) {
-                        Ok((mut stream, remote_address)) => {
-                            log::info!(
-                                remote_address = as_display!(remote_address);
-                                "Got a connection"
-                            );
-                            let active_threads_clone = active_threads.clone();
-                            let request_id = format!(
-                                "{}-{}",
-                                remote_address.port(),
-                                match remote_address.ip() {
-                                    std::net::IpAddr::V4(ipv4) => format!("{}", Into::<u32>::into(ipv4)),
-                                    std::net::IpAddr::V6(ipv6) => format!("{}", Into::<u128>::into(ipv6)),
-                                }
-                            );
-                            let request_handler = move || {
-                                active_threads_clone.fetch_add(1, Ordering::SeqCst);
-                                if let Some(err) = handler(&mut stream, &remote_address).err() {
-                                    log::error!(
-                                        error = as_display!(err),
-                                        other_threads = as_display!(active_threads_clone.fetch_sub(1, Ordering::SeqCst)),
-                                        remote_address = as_display!(remote_address);
-                                        "Request complete"
-                                    );
-                                } else {
-                                    log::info!(
-                                        other_threads = as_display!(active_threads_clone.fetch_sub(1, Ordering::SeqCst)),
-                                        remote_address = as_display!(remote_address);
-                                        "Request complete"
-                                    );
-                                }
-                            };
-                            if thread::Builder::new()
-                                .name(format!("request-handler-{}", request_id))
-                                .spawn(request_handler)
-                                .is_err()
-                            {
-                                log::error!(
-                                    other_threads = as_display!(active_threads.fetch_sub(1, Ordering::SeqCst)),
-                                    remote_address = as_display!(remote_address);
-                                    "Unable to spawn thread to handle request"
-                                )
-                            }
-                        }
-                        Err(mpsc::RecvTimeoutError::Timeout) => { }
-                        Err(err) => {
-                            log::error!(
-                                error = as_display!(err);
-                                "Request receiver error, shutting down"
-                            );
-                            shutdown_signal_clone.start_shutdown();
-                        }
-                    }
-                }
+    fn clear_problem(&self, listener: &PeerAddress) {
+        self.locked_problems().remove(&listener.to_string());
+    }
 
-                // shutdown time!
+    fn locked_problems(&self) -> MutexGuard<'_, BTreeMap<String, String>> {
+        self.problems.lock().expect("Health should not be poisoned")
+    }
+}
 
-                let stop_at = Instant::now() + SHUTDOWN_TIMEOUT;
-                log::info!(
-                    shutdown_timeout = as_debug!(SHUTDOWN_TIMEOUT),
-                    active_threads = as_display!(active_threads.load(Ordering::SeqCst));
-                    "Shutdown signal received"
-                );
-                while Instant::now() < stop_at && active_threads.load(Ordering::SeqCst) > 0 {
-                    std::thread::sleep(SLEEP_DURATION);
-                }
-                if active_threads.load(Ordering::SeqCst) > 0 {
-                    log::warn!(
-                        active_threads = as_display!(active_threads.load(Ordering::SeqCst)),
-                        shutdown_timeout = as_debug!(SHUTDOWN_TIMEOUT),
-                        reason = "shutdown timeout reached";
-                        "Stopping controller despite active threads"
-                    );
-                }
-                shutdown_signal_clone.complete_shutdown();
-            })?;
-        Ok(shutdown_signal)
+/// Exponential backoff between retries of something which keeps failing.
+#[derive(Default)]
+struct Backoff {
+    next: Option<Duration>,
+}
+
+impl Backoff {
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.next.unwrap_or(MIN_BACKOFF);
+        self.next = Some((delay * 2).min(MAX_BACKOFF));
+        delay
     }
 
-    fn get_listener<A: ToSocketAddrs>(bind_address: A) -> io::Result<Self::Listener>;
+    /// Start again from the shortest delay. Returns true if we'd been backing off.
+    fn reset(&mut self) -> bool {
+        self.next.take().is_some()
+    }
+}
 
-    fn pump(listener: &Self::Listener) -> io::Result<(Self::ConnectionLike, SocketAddr)>;
+/// Whether an error accepting a connection or receiving a datagram is likely to go away by
+/// itself, like running out of file descriptors or a client hanging up before we accepted it.
+/// accept(2) says that the network errors here should be treated like EAGAIN.
+fn is_transient(error: &io::Error) -> bool {
+    if matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+    ) {
+        return true;
+    }
+    match error.raw_os_error() {
+        Some(
+            libc::EMFILE
+            | libc::ENFILE
+            | libc::ENOBUFS
+            | libc::ENOMEM
+            | libc::EPERM
+            | libc::EPROTO
+            | libc::ENETDOWN
+            | libc::ENOPROTOOPT
+            | libc::EHOSTDOWN
+            | libc::EHOSTUNREACH
+            | libc::EOPNOTSUPP
+            | libc::ENETUNREACH,
+        ) => true,
+        // Only Linux has this one
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        Some(libc::ENONET) => true,
+        _ => false,
+    }
+}
 
-    fn get_local_address(listener: &Self::Listener) -> io::Result<SocketAddr>;
+/// What to do with a new connection when every worker is busy and the pending queue is full.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum QueueFullPolicy {
+    /// Close the connection straight away
+    Reject,
+    /// Stop accepting until there is room in the queue, leaving new connections in the OS backlog
+    Hold,
+    /// Send this message, followed by a newline, then close the connection
+    CloseWithMessage(String),
 }
 
-pub(crate) struct TcpServer();
+impl FromStr for QueueFullPolicy {
+    type Err = String;
 
-impl TcpServer {
-    pub(crate) fn new() -> Self {
-        Self {}
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(QueueFullPolicy::Reject),
+            "hold" => Ok(QueueFullPolicy::Hold),
+            _ => match s.strip_prefix("close:") {
+                Some(message) => Ok(QueueFullPolicy::CloseWithMessage(String::from(message))),
+                None => Err(format!(
+                    "Unknown queue full policy '{}', expected reject, hold or close:<message>",
+                    s
+                )),
+            },
+        }
     }
 }
 
-pub(crate) struct UdpServer;
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct WorkerPoolConfig {
+    /// The most connections handled at once; workers are started as they're needed, up to this many
+    pub(crate) workers: usize,
+    /// The most connections waiting for a worker
+    pub(crate) queue_capacity: usize,
+    pub(crate) queue_full_policy: QueueFullPolicy,
+    /// How long a drain waits for connections to finish before shutting down anyway; `None` waits
+    /// for as long as they take
+    pub(crate) drain_timeout: Option<Duration>,
+    /// How long connections get to finish once we shut down, before they're force-closed
+    pub(crate) shutdown_timeout: Duration,
+}
 
-impl UdpServer {
-    pub(crate) fn new() -> Self {
-        Self {}
+impl Default for WorkerPoolConfig {
+    fn default() -> Self {
+        Self {
+            workers: DEFAULT_WORKERS,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            queue_full_policy: QueueFullPolicy::Hold,
+            drain_timeout: None,
+            shutdown_timeout: SHUTDOWN_TIMEOUT,
+        }
     }
 }
 
-impl Server for TcpServer {
-    type Listener = TcpListener;
-    type ConnectionLike = TcpStream;
+impl WorkerPoolConfig {
+    /// Read WORKERS, QUEUE_CAPACITY, QUEUE_FULL_POLICY and DRAIN_TIMEOUT (in seconds) from the
+    /// environment, using defaults for any that are unset.
+    pub(crate) fn from_env() -> Result<Self, Box<dyn Error>> {
+        let mut config = Self::default();
+        if let Ok(workers) = std::env::var("WORKERS") {
+            config.workers = workers.parse()?;
+        }
+        if let Ok(queue_capacity) = std::env::var("QUEUE_CAPACITY") {
+            config.queue_capacity = queue_capacity.parse()?;
+        }
+        if let Ok(queue_full_policy) = std::env::var("QUEUE_FULL_POLICY") {
+            config.queue_full_policy = queue_full_policy.parse()?;
+        }
+        if let Ok(drain_timeout) = std::env::var("DRAIN_TIMEOUT") {
+            config.drain_timeout = Some(Duration::from_secs(drain_timeout.parse()?));
+        }
+        if config.workers == 0 || config.queue_capacity == 0 {
+            return Err(String::from("WORKERS and QUEUE_CAPACITY must be at least 1").into());
+        }
+        Ok(config)
+    }
+}
+
+type Rejecter<T> = fn(T, &PeerAddress, Option<&str>) -> io::Result<()>;
+/// Reads a PROXY protocol header from the start of a connection, returning the client address
+/// it gives, if any.
+pub(crate) type ProxyHeaderReader<T> = fn(&mut T) -> io::Result<Option<PeerAddress>>;
+
+/// A connection waiting in the queue for a worker.
+struct QueuedConnection<T> {
+    /// Its ID in the [`ConnectionRegistry`]
+    id: u64,
+    stream: T,
+    remote_address: PeerAddress,
+    /// The address of the listener which accepted it
+    listener: PeerAddress,
+    accepted_at: Instant,
+    counters: Arc<ConnectionCounters>,
+    /// Held until the connection closes, if it counts towards a limit
+    permit: Option<ConnectionPermit>,
+}
+
+/// What a [`WorkerPool`] needs to do with its server's connections besides handle them.
+struct ConnectionHooks<T> {
+    reject: Rejecter<T>,
+    force_closer: fn(&T) -> Option<ForceCloser>,
+    /// Set if every connection starts with a PROXY protocol header
+    proxy_header_reader: Option<ProxyHeaderReader<T>>,
+    attach_counters: fn(&mut T, &Arc<ConnectionCounters>),
+}
+
+/// A pool of worker threads fed by a bounded queue. Workers are spawned when a connection
+/// is queued and no worker is idle, and exit once shutdown has started and the queue is empty.
+struct WorkerPool<T> {
+    config: WorkerPoolConfig,
+    handler: Arc<dyn ConnectionHandler<T>>,
+    hooks: ConnectionHooks<T>,
+    metrics: Arc<Metrics>,
+    limiter: Arc<Limiter>,
+    registry: Arc<ConnectionRegistry>,
+    /// Our server's ID in the registry
+    server_id: usize,
+    sender: mpsc::SyncSender<QueuedConnection<T>>,
+    receiver: Mutex<mpsc::Receiver<QueuedConnection<T>>>,
+    shutdown_signal: ShutdownSignal,
+    /// Worker threads which are running, whether busy or idle
+    workers: AtomicUsize,
+    /// Connections being handled right now
+    active_threads: AtomicUsize,
+    /// Connections waiting in the queue
+    pending: AtomicUsize,
+    /// Notified whenever a connection stops being active or pending, for wait_until_idle
+    idle: (Mutex<()>, Condvar),
+}
 
-    fn get_listener<A: ToSocketAddrs>(bind_address: A) -> io::Result<Self::Listener> {
-        Self::Listener::bind(bind_address)
+impl<T: Send + 'static> WorkerPool<T> {
+    fn new(
+        config: WorkerPoolConfig,
+        handler: Arc<dyn ConnectionHandler<T>>,
+        hooks: ConnectionHooks<T>,
+        metrics: Arc<Metrics>,
+        limiter: Arc<Limiter>,
+        registry: Arc<ConnectionRegistry>,
+        shutdown_signal: ShutdownSignal,
+    ) -> Arc<Self> {
+        let (sender, receiver) = mpsc::sync_channel(config.queue_capacity);
+        let server_id = registry.add_server(&shutdown_signal);
+        Arc::new(Self {
+            config,
+            handler,
+            hooks,
+            metrics,
+            limiter,
+            registry,
+            server_id,
+            sender,
+            receiver: Mutex::new(receiver),
+            shutdown_signal,
+            workers: AtomicUsize::new(0),
+            active_threads: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+            idle: (Mutex::new(()), Condvar::new()),
+        })
     }
 
-    fn pump(listener: &Self::Listener) -> io::Result<(Self::ConnectionLike, SocketAddr)> {
-        listener.accept()
+    /// Block until nothing is active or pending, or `timeout` passes. Returns true if idle.
+    fn wait_until_idle(&self, timeout: Duration) -> bool {
+        let (lock, changed) = &self.idle;
+        let guard = lock.lock().expect("Idle lock should not be poisoned");
+        let _guard = changed
+            .wait_timeout_while(guard, timeout, |_| !self.is_idle())
+            .expect("Idle lock should not be poisoned");
+        self.is_idle()
     }
 
-    fn get_local_address(listener: &Self::Listener) -> io::Result<SocketAddr> {
-        listener.local_addr()
+    fn notify_idle_waiters(&self) {
+        let (lock, changed) = &self.idle;
+        // Taking the lock means a waiter is either not yet checking is_idle, or already waiting
+        let _guard = lock.lock().expect("Idle lock should not be poisoned");
+        changed.notify_all();
     }
-}
 
-impl Server for UdpServer {
-    type Listener = UdpSocket;
-    type ConnectionLike = UdpSocket;
+    /// Close every connection still being handled or queued, returning how many there were.
+    fn force_close_all(&self) -> usize {
+        self.registry.close_all(self.server_id)
+    }
 
-    fn get_listener<A: ToSocketAddrs>(bind_address: A) -> io::Result<Self::Listener> {
-        Self::Listener::bind(bind_address)
+    fn is_idle(&self) -> bool {
+        self.active_threads.load(Ordering::SeqCst) == 0 && self.pending.load(Ordering::SeqCst) == 0
     }
 
-    fn pump(listener: &Self::Listener) -> io::Result<(Self::ConnectionLike, SocketAddr)> {
-        // The docs for peek/peek_from say:
-        //      Do not use this function to implement busy waiting, instead use libc::poll to synchronize IO events on one or more sockets.
-        // However I don't want to bring in libc and deal with unsafe polling; nor deal with how to avoid draining the socket when I do poll.
-        // In particular, since we want to return (a copy of) the socket so the handler can also write to it, we can't drain it.
-        // I think actually the real victory would be to make the handler a struct that implements UdpServer, which is a trait in this world.
-        // But that's not how this application is presently designed, so I'm not going to do that either right now.
-        let (bytes, peer_addr) = listener.peek_from(&mut [0u8; 1])?;
-        if bytes > 0 {
-            Ok((listener.try_clone()?, peer_addr))
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::Interrupted,
-                "No data available",
-            ))
+    /// Connections being handled or waiting for a worker.
+    fn open_connections(&self) -> usize {
+        self.active_threads.load(Ordering::SeqCst) + self.pending.load(Ordering::SeqCst)
+    }
+
+    /// Queue a connection for the next free worker, applying the queue full policy if there's no room.
+    fn dispatch(self: &Arc<Self>, stream: T, remote_address: PeerAddress, listener: PeerAddress) {
+        self.metrics.accepted();
+        // Behind a proxy, we don't know who a connection is from until a worker reads its header
+        let permit = match self.hooks.proxy_header_reader {
+            Some(_) => None,
+            None => match self.admit(&remote_address, &listener) {
+                Ok(permit) => permit,
+                Err(_) => {
+                    self.metrics.rejected();
+                    if let Err(e) = (self.hooks.reject)(stream, &remote_address, None) {
+                        log::debug!(
+                            remote_address = as_display!(remote_address),
+                            error = as_display!(e);
+                            "Error rejecting connection"
+                        );
+                    }
+                    return;
+                }
+            },
+        };
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        let counters = Arc::new(ConnectionCounters::new(self.metrics.clone()));
+        let id = self.registry.register(
+            self.server_id,
+            remote_address.clone(),
+            listener.clone(),
+            counters.clone(),
+            (self.hooks.force_closer)(&stream),
+        );
+        let connection = QueuedConnection {
+            id,
+            stream,
+            remote_address,
+            listener,
+            accepted_at: Instant::now(),
+            counters,
+            permit,
+        };
+        let result = match self.sender.try_send(connection) {
+            Err(mpsc::TrySendError::Full(job))
+                if self.config.queue_full_policy == QueueFullPolicy::Hold =>
+            {
+                log::warn!(
+                    connection_id = as_display!(job.id),
+                    remote_address = as_display!(job.remote_address),
+                    listener = as_display!(job.listener),
+                    queue_capacity = as_display!(self.config.queue_capacity);
+                    "Queue full, waiting for a free worker"
+                );
+                self.ensure_worker_available();
+                self.sender
+                    .send(job)
+                    .map_err(|mpsc::SendError(job)| mpsc::TrySendError::Disconnected(job))
+            }
+            result => result,
+        };
+        match result {
+            Ok(()) => self.ensure_worker_available(),
+            Err(
+                mpsc::TrySendError::Full(QueuedConnection {
+                    id,
+                    stream,
+                    remote_address,
+                    listener,
+                    ..
+                })
+                | mpsc::TrySendError::Disconnected(QueuedConnection {
+                    id,
+                    stream,
+                    remote_address,
+                    listener,
+                    ..
+                }),
+            ) => {
+                self.registry.remove(id);
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                self.metrics.rejected();
+                self.notify_idle_waiters();
+                let message = match &self.config.queue_full_policy {
+                    QueueFullPolicy::CloseWithMessage(message) => Some(message.as_str()),
+                    _ => None,
+                };
+                log::warn!(
+                    connection_id = as_display!(id),
+                    remote_address = as_display!(remote_address),
+                    listener = as_display!(listener),
+                    active_threads = as_display!(self.active_threads.load(Ordering::SeqCst)),
+                    queue_capacity = as_display!(self.config.queue_capacity);
+                    "Queue full, rejecting connection"
+                );
+                if let Err(e) = (self.hooks.reject)(stream, &remote_address, message) {
+                    log::debug!(
+                        remote_address = as_display!(remote_address),
+                        error = as_display!(e);
+                        "Error rejecting connection"
+                    );
+                }
+            }
+        }
+    }
+
+    /// Start another worker if every running worker is busy and we're allowed more.
+    fn ensure_worker_available(self: &Arc<Self>) {
+        let busy_or_claimed =
+            self.active_threads.load(Ordering::SeqCst) + self.pending.load(Ordering::SeqCst);
+        let workers = self.workers.load(Ordering::SeqCst);
+        if workers >= busy_or_claimed || workers >= self.config.workers {
+            return;
+        }
+        if self
+            .workers
+            .compare_exchange(workers, workers + 1, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            // Someone else just started a worker
+            return;
+        }
+        // If the thread doesn't start, dropping the closure gives up the slot
+        let slot = WorkerSlot { pool: self.clone() };
+        if let Err(e) = thread::Builder::new()
+            .name(format!("worker-{}", workers))
+            .spawn(move || slot.pool.work())
+        {
+            log::error!(
+                error = as_display!(e),
+                workers = as_display!(workers);
+                "Unable to spawn worker thread"
+            );
         }
     }
 
-    fn get_local_address(listener: &Self::Listener) -> io::Result<SocketAddr> {
-        listener.local_addr()
+    fn work(&self) {
+        loop {
+            let next = {
+                let receiver = self.receiver.lock().expect("Queue should not be poisoned");
+                match receiver.recv_timeout(SLEEP_DURATION) {
+                    Ok(job) => {
+                        // Count the connection as active before it stops being pending,
+                        // so is_idle never sees a gap between the two
+                        let active = ActiveConnection::start(self, job.id);
+                        self.pending.fetch_sub(1, Ordering::SeqCst);
+                        Some((active, job))
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+            };
+            let Some((
+                active,
+                QueuedConnection {
+                    id,
+                    mut stream,
+                    remote_address,
+                    listener,
+                    accepted_at,
+                    counters,
+                    mut permit,
+                },
+            )) = next
+            else {
+                if self.shutdown_signal.is_shutdown_initiated() {
+                    break;
+                }
+                continue;
+            };
+            let admitted = self
+                .read_proxy_header(&mut stream, &remote_address, &listener)
+                .and_then(|client_address| {
+                    if self.hooks.proxy_header_reader.is_some() {
+                        permit = self.admit(&client_address, &listener)?;
+                    }
+                    Ok(client_address)
+                });
+            let (remote_address, result) = match admitted {
+                Ok(client_address) => {
+                    if client_address != remote_address {
+                        self.registry.set_peer(id, client_address.clone());
+                    }
+                    (self.hooks.attach_counters)(&mut stream, &counters);
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        self.handler
+                            .handle(&mut stream, &client_address, &self.shutdown_signal)
+                    }))
+                    .unwrap_or_else(|payload| {
+                        let message = panic_message(payload.as_ref());
+                        self.metrics.panicked();
+                        log::error!(
+                            connection_id = as_display!(id),
+                            remote_address = as_display!(client_address),
+                            listener = as_display!(listener),
+                            panic = message;
+                            "Handler panicked"
+                        );
+                        Err(format!("Handler panicked: {}", message).into())
+                    });
+                    self.metrics
+                        .completed(accepted_at.elapsed(), result.is_err());
+                    (client_address, result)
+                }
+                Err(e) => {
+                    self.metrics.rejected();
+                    (remote_address, Err(e))
+                }
+            };
+            drop(active);
+            drop(permit);
+            // Not inside the log macros: their arguments aren't evaluated if the level is disabled
+            let other_threads = self.active_threads.load(Ordering::SeqCst);
+            if let Some(err) = result.err() {
+                log::error!(
+                    error = as_display!(err),
+                    connection_id = as_display!(id),
+                    other_threads = as_display!(other_threads),
+                    remote_address = as_display!(remote_address),
+                    listener = as_display!(listener);
+                    "Request complete"
+                );
+            } else {
+                log::info!(
+                    connection_id = as_display!(id),
+                    other_threads = as_display!(other_threads),
+                    remote_address = as_display!(remote_address),
+                    listener = as_display!(listener);
+                    "Request complete"
+                );
+            }
+        }
+    }
+
+    /// Check a connection against our limits, logging and counting it if it's turned away.
+    fn admit(
+        &self,
+        remote_address: &PeerAddress,
+        listener: &PeerAddress,
+    ) -> Result<Option<ConnectionPermit>, LimitExceeded> {
+        self.limiter.admit(remote_address.ip()).inspect_err(|e| {
+            self.metrics.limited(e.reason());
+            log::warn!(
+                remote_address = as_display!(remote_address),
+                listener = as_display!(listener),
+                reason = e.reason();
+                "Connection limit reached, rejecting connection"
+            );
+        })
+    }
+
+    /// Work out who a connection is really from, which is `remote_address` unless we're behind
+    /// a proxy. A connection without a valid PROXY header is an error, and isn't handled.
+    fn read_proxy_header(
+        &self,
+        stream: &mut T,
+        remote_address: &PeerAddress,
+        listener: &PeerAddress,
+    ) -> Result<PeerAddress, Box<dyn Error>> {
+        let Some(read_header) = self.hooks.proxy_header_reader else {
+            return Ok(remote_address.clone());
+        };
+        let client_address = read_header(stream)
+            .map_err(|e| format!("Invalid PROXY header from {}: {}", remote_address, e))?
+            .unwrap_or_else(|| remote_address.clone());
+        log::info!(
+            remote_address = as_display!(client_address),
+            proxy = as_display!(remote_address),
+            listener = as_display!(listener);
+            "Got a connection"
+        );
+        Ok(client_address)
+    }
+}
+
+/// Counts as one of a pool's running workers until dropped, whether its thread exits, panics,
+/// or never starts.
+struct WorkerSlot<T: Send + 'static> {
+    pool: Arc<WorkerPool<T>>,
+}
+
+impl<T: Send + 'static> Drop for WorkerSlot<T> {
+    fn drop(&mut self) {
+        self.pool.workers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counts a connection as active, and keeps it in the registry, until dropped.
+struct ActiveConnection<'a, T: Send + 'static> {
+    pool: &'a WorkerPool<T>,
+    id: u64,
+}
+
+impl<'a, T: Send + 'static> ActiveConnection<'a, T> {
+    fn start(pool: &'a WorkerPool<T>, id: u64) -> Self {
+        pool.active_threads.fetch_add(1, Ordering::SeqCst);
+        Self { pool, id }
+    }
+}
+
+impl<T: Send + 'static> Drop for ActiveConnection<'_, T> {
+    fn drop(&mut self) {
+        self.pool.registry.remove(self.id);
+        self.pool.active_threads.fetch_sub(1, Ordering::SeqCst);
+        self.pool.notify_idle_waiters();
+    }
+}
+
+/// What a panic was called with, if it's a string, as it almost always is.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("<non-string panic payload>")
+}
+
+/// Open a listener on each socket we inherited if there are any, and otherwise on each bind
+/// address. Everything is opened up front, so we either serve every address or none of them.
+fn open_listeners<L>(
+    ctx: &Context,
+    bind: impl Fn(&str) -> io::Result<L>,
+    adopt: impl Fn(OwnedFd) -> io::Result<L>,
+) -> Result<Vec<L>, Box<dyn Error>> {
+    let inherited_sockets = ctx
+        .inherited_sockets
+        .as_deref()
+        .map(|sockets| socket_activation::for_problem(sockets, ctx.problem.as_deref()));
+    match inherited_sockets {
+        Some(sockets) if sockets.is_empty() => {
+            Err(String::from("None of the inherited sockets are for this problem").into())
+        }
+        Some(sockets) => Ok(sockets
+            .into_iter()
+            .map(|socket| {
+                log::debug!(
+                    fd = as_display!(socket.fd()),
+                    name = as_debug!(socket.name);
+                    "Using inherited socket"
+                );
+                adopt(socket.try_clone()?)
+            })
+            .collect::<io::Result<Vec<_>>>()?),
+        None if ctx.bind_addresses.is_empty() => Err(String::from("No bind address given").into()),
+        None => Ok(ctx
+            .bind_addresses
+            .iter()
+            .map(|bind_address| bind(bind_address))
+            .collect::<io::Result<Vec<_>>>()?),
+    }
+}
+
+pub(crate) trait Server {
+    type Listener: Send + 'static;
+    type ConnectionLike: Send + 'static;
+
+    fn serve(
+        &self,
+        ctx: &Context,
+        handler: impl ConnectionHandler<Self::ConnectionLike>,
+    ) -> Result<ShutdownSignal, Box<dyn Error>> {
+        let handler: Arc<dyn ConnectionHandler<Self::ConnectionLike>> = Arc::new(handler);
+        let proxy_header_reader = if ctx.proxy_protocol {
+            Some(
+                Self::proxy_header_reader()
+                    .ok_or("This server doesn't support the PROXY protocol")?,
+            )
+        } else {
+            None
+        };
+        let listeners = open_listeners(ctx, Self::get_listener, Self::adopt_listener)?
+            .into_iter()
+            .map(|listener| {
+                let local_address = Self::get_local_address(&listener)?;
+                Ok((listener, local_address))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let shutdown_signal = ShutdownSignal::new();
+        let mut shutdown_signal_clone = shutdown_signal.clone();
+        let pool = WorkerPool::new(
+            ctx.worker_pool.clone(),
+            handler.clone(),
+            ConnectionHooks {
+                reject: Self::reject,
+                force_closer: Self::force_closer,
+                proxy_header_reader,
+                attach_counters: Self::attach_counters,
+            },
+            ctx.metrics.clone(),
+            Limiter::new(ctx.limits.clone()),
+            ctx.connections.clone(),
+            shutdown_signal.clone(),
+        );
+        // Disconnects once every accept loop has dropped its listener
+        let (listener_closed_sender, listener_closed) = mpsc::channel::<()>();
+
+        for (listener, local_address) in listeners {
+            let handoff_guard = match Self::handoff_socket(&listener) {
+                Some(socket) => Some(
+                    ctx.handoff
+                        .add_listener(socket, ctx.problem.as_deref().unwrap_or_default())?,
+                ),
+                None => {
+                    ctx.handoff.refuse(format!(
+                        "The server on {} can't hand over its listener without dropping anything",
+                        local_address
+                    ));
+                    None
+                }
+            };
+            let handoff = ctx.handoff.clone();
+            let pool = pool.clone();
+            let health = ctx.health.clone();
+            let listener_closed_sender = listener_closed_sender.clone();
+            let wake_listener: fn(&PeerAddress) = Self::wake_listener;
+            let local_address_for_waker = local_address.clone();
+            // Draining has to wait for the accept timeout instead, or it would handle the waker
+            let wake_listener_on_shutdown =
+                shutdown_signal.on_shutdown(move || wake_listener(&local_address_for_waker));
+
+            log::info!(
+                address = as_display!(local_address),
+                pid = as_display!(std::process::id()),
+                workers = as_display!(ctx.worker_pool.workers),
+                queue_capacity = as_display!(ctx.worker_pool.queue_capacity);
+                "Listening"
+            );
+            handler.on_listen(&local_address);
+            Self::watch_shutdown(&listener, &shutdown_signal);
+
+            thread::Builder::new()
+                .name(format!("accept-and-forward {}", local_address))
+                .spawn(move || {
+                    let mut shutdown_signal = pool.shutdown_signal.clone();
+                    let mut backoff = Backoff::default();
+                    loop {
+                        let pumped = Self::pump(&listener);
+                        if !pool.shutdown_signal.is_accepting() {
+                            // A drain still handles anything we just accepted. Otherwise it's
+                            // dropped, and closing the listener refuses the rest.
+                            if let (Ok((stream, remote_address)), true) =
+                                (pumped, pool.shutdown_signal.is_draining())
+                            {
+                                pool.dispatch(stream, remote_address, local_address.clone());
+                            }
+                            log::info!(
+                                listener = as_display!(local_address);
+                                "Shutting down, no longer accepting connections"
+                            );
+                            break;
+                        }
+                        if pumped.is_ok() && backoff.reset() {
+                            health.clear_problem(&local_address);
+                            log::info!(
+                                listener = as_display!(local_address);
+                                "Accepting connections again"
+                            );
+                        }
+                        match pumped {
+                            Ok((stream, remote_address)) if proxy_header_reader.is_some() => {
+                                // The worker logs who it's really from once it has read the header
+                                log::debug!(
+                                    proxy = as_display!(remote_address),
+                                    listener = as_display!(local_address);
+                                    "Got a connection from a proxy"
+                                );
+                                pool.dispatch(stream, remote_address, local_address.clone());
+                            },
+                            Ok((stream, remote_address)) => {
+                                log::info!(
+                                    remote_address = as_display!(remote_address),
+                                    listener = as_display!(local_address);
+                                    "Got a connection"
+                                );
+                                pool.dispatch(stream, remote_address, local_address.clone());
+                            },
+                            // The listener timed out, so we can check we're still accepting
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                                log::debug!(
+                                    location = "accept-and-forward thread -> pump loop -> result of pumping the listener",
+                                    listener = as_display!(local_address),
+                                    error = as_display!(e);
+                                    "std::io::ErrorKind::Interrupted received, continuing"
+                                );
+                            }
+                            Err(e) if is_transient(&e) => {
+                                let delay = backoff.next_delay();
+                                health.set_problem(&local_address, format!("Error accepting connections, retrying: {}", e));
+                                log::warn!(
+                                    listener = as_display!(local_address),
+                                    error = as_display!(e),
+                                    retry_in = as_debug!(delay);
+                                    "Transient error accepting connection, backing off"
+                                );
+                                // Returns early if we start shutting down
+                                shutdown_signal.wait_while(&[ShutdownPhase::Running], Some(delay));
+                            }
+                            Err(e) => {
+                                health.set_problem(&local_address, format!("Unable to accept connections: {}", e));
+                                log::error!(
+                                    location = "accept-and-forward thread -> pump loop -> result of pumping the listener",
+                                    listener = as_display!(local_address),
+                                    error = as_display!(e);
+                                    "Error accepting connection; shutting down"
+                                );
+                                shutdown_signal.fail(format!("Error accepting connections on {}: {}", local_address, e));
+                                break;
+                            }
+                        }
+                    }
+                    // Nothing to wake now, and a drain shuts down as soon as we're gone
+                    drop(wake_listener_on_shutdown);
+                    drop(handoff_guard);
+                    if handoff.is_handed_off() {
+                        Self::hand_over(listener);
+                    } else {
+                        drop(listener);
+                    }
+                    drop(listener_closed_sender);
+                })?;
+        }
+        drop(listener_closed_sender);
+
+        thread::Builder::new()
+            .name("server-controller".into())
+            .spawn(move || {
+                shutdown_signal_clone.wait_while(&[ShutdownPhase::Running], None);
+
+                // shutdown time!
+
+                log::info!(
+                    shutdown_timeout = as_debug!(pool.config.shutdown_timeout),
+                    draining = as_display!(shutdown_signal_clone.is_draining()),
+                    drain_timeout = as_debug!(pool.config.drain_timeout),
+                    active_threads = as_display!(pool.active_threads.load(Ordering::SeqCst)),
+                    pending = as_display!(pool.pending.load(Ordering::SeqCst));
+                    "Shutdown signal received"
+                );
+                // A drain is over once every accept loop has stopped, so nothing more can be
+                // dispatched, and every connection has finished
+                let mut listeners_closed = false;
+                let drain_deadline = pool
+                    .config
+                    .drain_timeout
+                    .map(|drain_timeout| Instant::now() + drain_timeout);
+                let mut open_connections = pool.open_connections();
+                let mut last_progress = Instant::now();
+                while shutdown_signal_clone.is_draining() {
+                    if !listeners_closed {
+                        listeners_closed = matches!(
+                            listener_closed.recv_timeout(SLEEP_DURATION),
+                            Err(mpsc::RecvTimeoutError::Disconnected)
+                        );
+                    } else if pool.wait_until_idle(SLEEP_DURATION) {
+                        if shutdown_signal_clone.stop_draining() {
+                            log::info!("Drained, shutting down");
+                        }
+                        break;
+                    }
+                    if drain_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        if shutdown_signal_clone.stop_draining() {
+                            log::warn!(
+                                drain_timeout = as_debug!(pool.config.drain_timeout),
+                                open_connections = as_display!(pool.open_connections()),
+                                reason = "drain timeout reached";
+                                "Shutting down"
+                            );
+                        }
+                        break;
+                    }
+                    // Logged as connections finish, and now and then if they're slow to
+                    let still_open = pool.open_connections();
+                    if still_open < open_connections
+                        || last_progress.elapsed() >= DRAIN_PROGRESS_INTERVAL
+                    {
+                        log::info!(
+                            open_connections = as_display!(still_open),
+                            active_threads = as_display!(pool.active_threads.load(Ordering::SeqCst)),
+                            pending = as_display!(pool.pending.load(Ordering::SeqCst));
+                            "Draining, waiting for connections to finish"
+                        );
+                        last_progress = Instant::now();
+                    }
+                    open_connections = still_open;
+                }
+                if !pool.wait_until_idle(pool.config.shutdown_timeout) {
+                    let closed = pool.force_close_all();
+                    log::warn!(
+                        closed_connections = as_display!(closed),
+                        shutdown_timeout = as_debug!(pool.config.shutdown_timeout),
+                        reason = "shutdown timeout reached";
+                        "Force-closing open connections"
+                    );
+                    pool.wait_until_idle(FORCE_CLOSE_TIMEOUT);
+                }
+                if !pool.is_idle() {
+                    log::warn!(
+                        active_threads = as_display!(pool.active_threads.load(Ordering::SeqCst)),
+                        pending = as_display!(pool.pending.load(Ordering::SeqCst)),
+                        shutdown_timeout = as_debug!(pool.config.shutdown_timeout),
+                        reason = "shutdown timeout reached";
+                        "Stopping controller despite active threads"
+                    );
+                }
+                // Give the accept loops a moment to let go of their listeners, so anything they clean
+                // up on drop is gone by the time shutdown completes
+                let _ = listener_closed.recv_timeout(WAKE_TIMEOUT);
+                handler.on_shutdown();
+                shutdown_signal_clone.complete_shutdown();
+            })?;
+        Ok(shutdown_signal)
+    }
+
+    /// Turn away a connection we have no room for, optionally telling it why.
+    fn reject(
+        connection: Self::ConnectionLike,
+        remote_address: &PeerAddress,
+        message: Option<&str>,
+    ) -> io::Result<()>;
+
+    /// Something that closes `connection` from another thread, if that's possible.
+    fn force_closer(_connection: &Self::ConnectionLike) -> Option<ForceCloser> {
+        None
+    }
+
+    /// Count the bytes read from and written to `connection` in `counters`, if we can.
+    fn attach_counters(
+        _connection: &mut Self::ConnectionLike,
+        _counters: &Arc<ConnectionCounters>,
+    ) {
+    }
+
+    /// How to read a PROXY protocol header from a connection, for servers which can sit behind
+    /// a load balancer that sends them.
+    fn proxy_header_reader() -> Option<ProxyHeaderReader<Self::ConnectionLike>> {
+        None
+    }
+
+    /// Make a pump blocked on the listener at `local_address` return, so the accept loop
+    /// notices shutdown. Servers whose pump never blocks for long needn't do anything.
+    fn wake_listener(_local_address: &PeerAddress) {}
+
+    /// Tell anything `listener` runs in the background how to find out that the server has
+    /// shut down, so it can stop too.
+    fn watch_shutdown(_listener: &Self::Listener, _shutdown_signal: &ShutdownSignal) {}
+
+    fn get_listener(bind_address: &str) -> io::Result<Self::Listener>;
+
+    /// Listen on a socket inherited from our parent instead of one we bound ourselves.
+    fn adopt_listener(_socket: OwnedFd) -> io::Result<Self::Listener> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "This server can't listen on inherited sockets",
+        ))
+    }
+
+    /// The socket to pass on to a new process when we restart, for servers which can hand
+    /// over to one without dropping anything.
+    fn handoff_socket(_listener: &Self::Listener) -> Option<BorrowedFd<'_>> {
+        None
+    }
+
+    /// Let go of a listener a new process has taken over, leaving anything it still needs.
+    fn hand_over(listener: Self::Listener) {
+        drop(listener);
+    }
+
+    fn pump(listener: &Self::Listener) -> io::Result<(Self::ConnectionLike, PeerAddress)>;
+
+    fn get_local_address(listener: &Self::Listener) -> io::Result<PeerAddress>;
+}
+
+/// Serves stream connections over TCP, or over a Unix domain socket for bind addresses which
+/// start with `unix:`. One server can listen on both.
+pub(crate) struct StreamServer();
+
+impl StreamServer {
+    pub(crate) fn new() -> Self {
+        Self {}
+    }
+}
+
+pub(crate) enum StreamListener {
+    Tcp(TcpListener),
+    Unix(UnixSocketListener),
+}
+
+impl Server for StreamServer {
+    type Listener = StreamListener;
+    type ConnectionLike = Stream;
+
+    fn get_listener(bind_address: &str) -> io::Result<Self::Listener> {
+        if bind_address.starts_with(UNIX_PREFIX) {
+            UnixServer::get_listener(bind_address).map(StreamListener::Unix)
+        } else {
+            TcpServer::get_listener(bind_address).map(StreamListener::Tcp)
+        }
+    }
+
+    fn adopt_listener(socket: OwnedFd) -> io::Result<Self::Listener> {
+        if socket_activation::address_family(&socket)? == libc::AF_UNIX {
+            UnixServer::adopt_listener(socket).map(StreamListener::Unix)
+        } else {
+            TcpServer::adopt_listener(socket).map(StreamListener::Tcp)
+        }
+    }
+
+    fn handoff_socket(listener: &Self::Listener) -> Option<BorrowedFd<'_>> {
+        match listener {
+            StreamListener::Tcp(listener) => TcpServer::handoff_socket(listener),
+            StreamListener::Unix(listener) => UnixServer::handoff_socket(listener),
+        }
+    }
+
+    fn hand_over(listener: Self::Listener) {
+        match listener {
+            StreamListener::Tcp(listener) => TcpServer::hand_over(listener),
+            StreamListener::Unix(listener) => UnixServer::hand_over(listener),
+        }
+    }
+
+    fn pump(listener: &Self::Listener) -> io::Result<(Self::ConnectionLike, PeerAddress)> {
+        match listener {
+            StreamListener::Tcp(listener) => TcpServer::pump(listener),
+            StreamListener::Unix(listener) => UnixServer::pump(listener),
+        }
+    }
+
+    fn get_local_address(listener: &Self::Listener) -> io::Result<PeerAddress> {
+        match listener {
+            StreamListener::Tcp(listener) => TcpServer::get_local_address(listener),
+            StreamListener::Unix(listener) => UnixServer::get_local_address(listener),
+        }
+    }
+
+    fn reject(
+        connection: Self::ConnectionLike,
+        _remote_address: &PeerAddress,
+        message: Option<&str>,
+    ) -> io::Result<()> {
+        reject_stream(connection, message)
+    }
+
+    fn wake_listener(local_address: &PeerAddress) {
+        match local_address {
+            PeerAddress::Inet(_) => TcpServer::wake_listener(local_address),
+            PeerAddress::Unix(_) => UnixServer::wake_listener(local_address),
+        }
+    }
+
+    fn force_closer(connection: &Self::ConnectionLike) -> Option<ForceCloser> {
+        stream_force_closer(connection)
+    }
+
+    fn proxy_header_reader() -> Option<ProxyHeaderReader<Self::ConnectionLike>> {
+        Some(read_stream_proxy_header)
+    }
+
+    fn attach_counters(connection: &mut Self::ConnectionLike, counters: &Arc<ConnectionCounters>) {
+        connection.counters = Some(counters.clone());
+    }
+}
+
+/// Closes a connection, telling it why first if there's a reason.
+fn reject_stream(connection: Stream, message: Option<&str>) -> io::Result<()> {
+    if let Some(message) = message {
+        (&connection).write_all(format!("{}\n", message).as_bytes())?;
+    }
+    connection.shutdown(Shutdown::Both)
+}
+
+fn read_stream_proxy_header(connection: &mut Stream) -> io::Result<Option<PeerAddress>> {
+    proxy_protocol::read_from_stream(connection, proxy_protocol::HEADER_TIMEOUT)
+}
+
+fn stream_force_closer(connection: &Stream) -> Option<ForceCloser> {
+    let connection = connection.try_clone().ok()?;
+    Some(Box::new(move || {
+        let _ = connection.shutdown(Shutdown::Both);
+    }))
+}
+
+/// Make accept on `listener` give up with `WouldBlock` after [`ACCEPT_TIMEOUT`], so the accept
+/// loop notices a drain without being woken by a connection it would then have to handle.
+fn set_accept_timeout(listener: &impl AsRawFd) -> io::Result<()> {
+    let timeout = libc::timeval {
+        tv_sec: ACCEPT_TIMEOUT.as_secs() as libc::time_t,
+        tv_usec: ACCEPT_TIMEOUT.subsec_micros() as libc::suseconds_t,
+    };
+    // SAFETY: timeout outlives the call, and we pass its real size
+    if unsafe {
+        libc::setsockopt(
+            listener.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Serves stream connections over TCP; see [`StreamServer`].
+pub(crate) struct TcpServer();
+
+impl Server for TcpServer {
+    type Listener = TcpListener;
+    type ConnectionLike = Stream;
+
+    fn get_listener(bind_address: &str) -> io::Result<Self::Listener> {
+        let listener = Self::Listener::bind(bind_address)?;
+        set_accept_timeout(&listener)?;
+        Ok(listener)
+    }
+
+    fn adopt_listener(socket: OwnedFd) -> io::Result<Self::Listener> {
+        let listener = socket_activation::tcp_listener(socket)?;
+        set_accept_timeout(&listener)?;
+        Ok(listener)
+    }
+
+    fn handoff_socket(listener: &Self::Listener) -> Option<BorrowedFd<'_>> {
+        Some(listener.as_fd())
+    }
+
+    fn pump(listener: &Self::Listener) -> io::Result<(Self::ConnectionLike, PeerAddress)> {
+        let (stream, remote_address) = listener.accept()?;
+        // Connections inherit the listener's accept timeout as a read timeout
+        stream.set_read_timeout(None)?;
+        Ok((stream.into(), remote_address.into()))
+    }
+
+    fn get_local_address(listener: &Self::Listener) -> io::Result<PeerAddress> {
+        listener.local_addr().map(PeerAddress::from)
+    }
+
+    fn reject(
+        connection: Self::ConnectionLike,
+        _remote_address: &PeerAddress,
+        message: Option<&str>,
+    ) -> io::Result<()> {
+        reject_stream(connection, message)
+    }
+
+    fn wake_listener(local_address: &PeerAddress) {
+        let PeerAddress::Inet(local_address) = local_address else {
+            return;
+        };
+        // Connecting to ourselves makes accept return
+        let address = loopback_if_unspecified(*local_address);
+        if let Err(e) = TcpStream::connect_timeout(&address, WAKE_TIMEOUT) {
+            log::debug!(
+                address = as_display!(address),
+                error = as_display!(e);
+                "Unable to wake listener"
+            );
+        }
+    }
+
+    fn force_closer(connection: &Self::ConnectionLike) -> Option<ForceCloser> {
+        stream_force_closer(connection)
+    }
+
+    fn proxy_header_reader() -> Option<ProxyHeaderReader<Self::ConnectionLike>> {
+        Some(read_stream_proxy_header)
+    }
+
+    fn attach_counters(connection: &mut Self::ConnectionLike, counters: &Arc<ConnectionCounters>) {
+        connection.counters = Some(counters.clone());
+    }
+}
+
+/// A listening Unix domain socket which removes its socket file when dropped, if we created it.
+pub(crate) struct UnixSocketListener {
+    listener: UnixListener,
+    /// None for inherited sockets, whose files belong to whoever bound them
+    path: Option<PathBuf>,
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Err(e) = fs::remove_file(path) {
+            log::warn!(
+                path = as_display!(path.display()),
+                error = as_display!(e);
+                "Unable to remove socket file"
+            );
+        }
+    }
+}
+
+/// Remove the socket file at `path` if a previous server left it behind. Anything that isn't
+/// a socket, or is a socket something is still listening on, is left alone.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another server", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            log::info!(path = as_display!(path.display()); "Removing stale socket file");
+            fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Serves stream connections over a Unix domain socket, bound to `unix:/path/to/socket`;
+/// see [`StreamServer`].
+pub(crate) struct UnixServer();
+
+impl Server for UnixServer {
+    type Listener = UnixSocketListener;
+    type ConnectionLike = Stream;
+
+    fn get_listener(bind_address: &str) -> io::Result<Self::Listener> {
+        let path = PathBuf::from(
+            bind_address
+                .strip_prefix(UNIX_PREFIX)
+                .unwrap_or(bind_address),
+        );
+        remove_stale_socket(&path)?;
+        let listener = UnixSocketListener {
+            listener: UnixListener::bind(&path)?,
+            path: Some(path),
+        };
+        set_accept_timeout(&listener.listener)?;
+        Ok(listener)
+    }
+
+    fn adopt_listener(socket: OwnedFd) -> io::Result<Self::Listener> {
+        let listener = socket_activation::unix_listener(socket)?;
+        set_accept_timeout(&listener)?;
+        Ok(UnixSocketListener {
+            listener,
+            path: None,
+        })
+    }
+
+    fn handoff_socket(listener: &Self::Listener) -> Option<BorrowedFd<'_>> {
+        Some(listener.listener.as_fd())
+    }
+
+    fn hand_over(mut listener: Self::Listener) {
+        // The new process is listening on the socket file now
+        listener.path = None;
+    }
+
+    fn pump(listener: &Self::Listener) -> io::Result<(Self::ConnectionLike, PeerAddress)> {
+        let (stream, remote_address) = listener.listener.accept()?;
+        // Connections inherit the listener's accept timeout as a read timeout
+        stream.set_read_timeout(None)?;
+        Ok((stream.into(), remote_address.into()))
+    }
+
+    fn get_local_address(listener: &Self::Listener) -> io::Result<PeerAddress> {
+        listener.listener.local_addr().map(PeerAddress::from)
+    }
+
+    fn reject(
+        connection: Self::ConnectionLike,
+        _remote_address: &PeerAddress,
+        message: Option<&str>,
+    ) -> io::Result<()> {
+        reject_stream(connection, message)
+    }
+
+    fn wake_listener(local_address: &PeerAddress) {
+        let PeerAddress::Unix(Some(path)) = local_address else {
+            return;
+        };
+        // Connecting to ourselves makes accept return
+        if let Err(e) = UnixStream::connect(path) {
+            log::debug!(
+                address = as_display!(local_address),
+                error = as_display!(e);
+                "Unable to wake listener"
+            );
+        }
+    }
+
+    fn force_closer(connection: &Self::ConnectionLike) -> Option<ForceCloser> {
+        stream_force_closer(connection)
+    }
+
+    fn proxy_header_reader() -> Option<ProxyHeaderReader<Self::ConnectionLike>> {
+        Some(read_stream_proxy_header)
+    }
+
+    fn attach_counters(connection: &mut Self::ConnectionLike, counters: &Arc<ConnectionCounters>) {
+        connection.counters = Some(counters.clone());
+    }
+}
+
+/// Handles each datagram a [`UdpServer`] receives.
+pub(crate) trait DatagramHandler: Send + Sync + 'static {
+    /// Called once for each address the server listens on, once it has started listening.
+    fn on_listen(&self, _local_address: &SocketAddr) {}
+
+    /// Called once for each datagram, in the order they're received. Datagrams arriving on the
+    /// same address are handled one at a time, so this should not block for long.
+    fn handle(
+        &self,
+        payload: &[u8],
+        peer: &SocketAddr,
+        reply: &ReplySink,
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Called once, when the server has finished shutting down.
+    fn on_shutdown(&self) {}
+}
+
+impl<F> DatagramHandler for F
+where
+    F: Fn(&[u8], &SocketAddr, &ReplySink) -> Result<(), Box<dyn Error>> + Send + Sync + 'static,
+{
+    fn handle(
+        &self,
+        payload: &[u8],
+        peer: &SocketAddr,
+        reply: &ReplySink,
+    ) -> Result<(), Box<dyn Error>> {
+        self(payload, peer, reply)
+    }
+}
+
+/// Sends datagrams back to the peer a datagram came from, from the socket it arrived on.
+pub(crate) struct ReplySink<'a> {
+    socket: &'a UdpSocket,
+    peer: SocketAddr,
+    metrics: &'a Metrics,
+}
+
+impl ReplySink<'_> {
+    pub(crate) fn send(&self, payload: &[u8]) -> io::Result<()> {
+        let bytes_written = self.socket.send_to(payload, self.peer)?;
+        self.metrics.written(bytes_written);
+        Ok(())
+    }
+}
+
+/// Receives datagrams on a single thread, handing each one to a [`DatagramHandler`] exactly once.
+pub(crate) struct UdpServer {
+    max_datagram_size: usize,
+}
+
+impl UdpServer {
+    pub(crate) fn new() -> Self {
+        Self {
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+        }
+    }
+
+    /// Datagrams with larger payloads than this are dropped without being handled.
+    pub(crate) fn with_max_datagram_size(self, max_datagram_size: usize) -> Self {
+        Self { max_datagram_size }
+    }
+
+    pub(crate) fn serve(
+        &self,
+        ctx: &Context,
+        handler: impl DatagramHandler,
+    ) -> Result<ShutdownSignal, Box<dyn Error>> {
+        if ctx.proxy_protocol {
+            return Err(String::from(
+                "Datagram problems can't be served behind the PROXY protocol",
+            )
+            .into());
+        }
+        let sockets = open_listeners(
+            ctx,
+            |bind_address| {
+                if bind_address.starts_with(UNIX_PREFIX) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Datagram problems can't be served over Unix domain sockets",
+                    ));
+                }
+                UdpSocket::bind(bind_address)
+            },
+            socket_activation::udp_socket,
+        )?
+        .into_iter()
+        .map(|socket| {
+            let local_address = socket.local_addr()?;
+            Ok((socket, local_address))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+        let handler: Arc<dyn DatagramHandler> = Arc::new(handler);
+        let limiter = Limiter::new(ctx.limits.clone());
+        let shutdown_signal = ShutdownSignal::new();
+        // There are no connections to register, but the admin port can still shut us down
+        ctx.connections.add_server(&shutdown_signal);
+        // The last receiver to stop finishes the shutdown
+        let receivers = Arc::new(AtomicUsize::new(sockets.len()));
+
+        for (socket, local_address) in sockets {
+            // Handlers keep what they've been sent in memory, which a new process wouldn't have
+            ctx.handoff.refuse(format!(
+                "The datagram server on {} can't hand over without losing what it's been sent",
+                local_address
+            ));
+            let handler = handler.clone();
+            let metrics = ctx.metrics.clone();
+            let limiter = limiter.clone();
+            let health = ctx.health.clone();
+            let receivers = receivers.clone();
+            let mut shutdown_signal_clone = shutdown_signal.clone();
+            // One byte more than we allow, so we can tell when a datagram was truncated
+            let mut buffer = vec![0u8; self.max_datagram_size + 1];
+
+            log::info!(
+                address = as_display!(local_address),
+                pid = as_display!(std::process::id()),
+                max_datagram_size = as_display!(self.max_datagram_size);
+                "Listening"
+            );
+            handler.on_listen(&local_address);
+
+            // Sending ourselves an empty datagram makes recv_from return
+            let waker = socket.try_clone()?;
+            let wake_on_stop = shutdown_signal.on_stop_accepting(move || {
+                let address = loopback_if_unspecified(local_address);
+                if let Err(e) = waker.send_to(&[], address) {
+                    log::debug!(
+                        address = as_display!(address),
+                        error = as_display!(e);
+                        "Unable to wake listener"
+                    );
+                }
+            });
+
+            thread::Builder::new()
+                .name(format!("datagram-receiver {}", local_address))
+                .spawn(move || {
+                    let _wake_on_stop = wake_on_stop;
+                    let listener = PeerAddress::from(local_address);
+                    let mut backoff = Backoff::default();
+                    loop {
+                        let received = socket.recv_from(&mut buffer);
+                        // There are no connections to wait for, so a drain stops straight away
+                        if !shutdown_signal_clone.is_accepting() {
+                            log::info!(
+                                listener = as_display!(local_address);
+                                "Shutting down, no longer receiving datagrams"
+                            );
+                            break;
+                        }
+                        if received.is_ok() && backoff.reset() {
+                            health.clear_problem(&listener);
+                            log::info!(
+                                listener = as_display!(local_address);
+                                "Receiving datagrams again"
+                            );
+                        }
+                        let (length, peer) = match received {
+                            Ok(received) => received,
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                            // A previous reply bounced; that's the peer's problem, not ours
+                            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                            Err(e) if is_transient(&e) => {
+                                let delay = backoff.next_delay();
+                                health.set_problem(
+                                    &listener,
+                                    format!("Error receiving datagrams, retrying: {}", e),
+                                );
+                                log::warn!(
+                                    listener = as_display!(local_address),
+                                    error = as_display!(e),
+                                    retry_in = as_debug!(delay);
+                                    "Transient error receiving datagram, backing off"
+                                );
+                                shutdown_signal_clone
+                                    .wait_while(&[ShutdownPhase::Running], Some(delay));
+                                continue;
+                            }
+                            Err(e) => {
+                                health.set_problem(
+                                    &listener,
+                                    format!("Unable to receive datagrams: {}", e),
+                                );
+                                log::error!(
+                                    listener = as_display!(local_address),
+                                    error = as_display!(e);
+                                    "Error receiving datagram; shutting down"
+                                );
+                                shutdown_signal_clone.fail(format!(
+                                    "Error receiving datagrams on {}: {}",
+                                    local_address, e
+                                ));
+                                break;
+                            }
+                        };
+                        if length > buffer.len() - 1 {
+                            log::info!(
+                                peer = as_display!(peer),
+                                listener = as_display!(local_address),
+                                max_datagram_size = as_display!(buffer.len() - 1);
+                                "Dropping oversized datagram"
+                            );
+                            continue;
+                        }
+                        if let Err(e) = limiter.admit_datagram(peer.ip()) {
+                            metrics.limited(e.reason());
+                            log::debug!(
+                                peer = as_display!(peer),
+                                listener = as_display!(local_address),
+                                reason = e.reason();
+                                "Dropping datagram from a limited source"
+                            );
+                            continue;
+                        }
+                        metrics.read(length);
+                        let reply = ReplySink {
+                            socket: &socket,
+                            peer,
+                            metrics: &metrics,
+                        };
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                            handler.handle(&buffer[..length], &peer, &reply)
+                        }))
+                        .unwrap_or_else(|payload| {
+                            let message = panic_message(payload.as_ref());
+                            metrics.panicked();
+                            log::error!(
+                                peer = as_display!(peer),
+                                listener = as_display!(local_address),
+                                panic = message;
+                                "Datagram handler panicked"
+                            );
+                            Err(format!("Handler panicked: {}", message).into())
+                        });
+                        if let Err(e) = result {
+                            log::error!(
+                                error = as_display!(e),
+                                peer = as_display!(peer),
+                                listener = as_display!(local_address);
+                                "Error handling datagram"
+                            );
+                        }
+                    }
+                    if receivers.fetch_sub(1, Ordering::SeqCst) == 1 {
+                        handler.on_shutdown();
+                        shutdown_signal_clone.stop_draining();
+                        shutdown_signal_clone.complete_shutdown();
+                    }
+                })?;
+        }
+        Ok(shutdown_signal)
+    }
+}
+
+fn loopback_if_unspecified(mut address: SocketAddr) -> SocketAddr {
+    if address.ip().is_unspecified() {
+        address.set_ip(match address {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    address
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::LimitsConfig;
+    use crate::scaffolding::{adopting_context, test_context};
+    use std::collections::VecDeque;
+    use std::io::{BufRead, BufReader, Read};
+
+    /// Stands in for a connection: the handler reports that it started, then blocks until released.
+    struct Job {
+        release: mpsc::Receiver<()>,
+        events: mpsc::Sender<&'static str>,
+    }
+
+    fn handle_job(job: &mut Job, _remote_address: &PeerAddress) -> Result<(), Box<dyn Error>> {
+        job.events.send("started")?;
+        job.release.recv()?;
+        Ok(())
+    }
+
+    fn reject_job(
+        job: Job,
+        _remote_address: &PeerAddress,
+        _message: Option<&str>,
+    ) -> io::Result<()> {
+        job.events.send("rejected").map_err(io::Error::other)
+    }
+
+    fn pool(
+        workers: usize,
+        queue_capacity: usize,
+        queue_full_policy: QueueFullPolicy,
+    ) -> Arc<WorkerPool<Job>> {
+        WorkerPool::new(
+            WorkerPoolConfig {
+                workers,
+                queue_capacity,
+                queue_full_policy,
+                ..WorkerPoolConfig::default()
+            },
+            Arc::new(handle_job),
+            ConnectionHooks {
+                reject: reject_job,
+                force_closer: |_| None,
+                proxy_header_reader: None,
+                attach_counters: |_, _| {},
+            },
+            Arc::new(Metrics::new("test")),
+            Limiter::new(LimitsConfig::default()),
+            Arc::new(ConnectionRegistry::new()),
+            ShutdownSignal::new(),
+        )
+    }
+
+    /// Dispatch a job, returning the means to release it and the events it reports.
+    fn dispatch(pool: &Arc<WorkerPool<Job>>) -> (mpsc::Sender<()>, mpsc::Receiver<&'static str>) {
+        let (release_sender, release) = mpsc::channel();
+        let (events, event_receiver) = mpsc::channel();
+        let address = PeerAddress::from(SocketAddr::from(([127, 0, 0, 1], 0)));
+        pool.dispatch(Job { release, events }, address.clone(), address);
+        (release_sender, event_receiver)
+    }
+
+    fn next_event(events: &mpsc::Receiver<&'static str>) -> &'static str {
+        events
+            .recv_timeout(Duration::from_secs(5))
+            .expect("Job should report an event")
+    }
+
+    fn wait_until_idle<T: Send + 'static>(pool: &WorkerPool<T>) {
+        let stop_at = Instant::now() + Duration::from_secs(5);
+        while !pool.is_idle() {
+            assert!(Instant::now() < stop_at, "Pool should become idle");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn parses_queue_full_policies() {
+        assert_eq!("reject".parse(), Ok(QueueFullPolicy::Reject));
+        assert_eq!("hold".parse(), Ok(QueueFullPolicy::Hold));
+        assert_eq!(
+            "close:busy, try later".parse(),
+            Ok(QueueFullPolicy::CloseWithMessage(String::from(
+                "busy, try later"
+            )))
+        );
+        assert!("drop".parse::<QueueFullPolicy>().is_err());
+    }
+
+    #[test]
+    fn bounds_workers_and_queue() {
+        let pool = pool(2, 1, QueueFullPolicy::Reject);
+        let (release_first, first) = dispatch(&pool);
+        assert_eq!(next_event(&first), "started");
+        let (release_second, second) = dispatch(&pool);
+        assert_eq!(next_event(&second), "started");
+
+        // Both workers are busy, so this one waits in the queue...
+        let (release_third, third) = dispatch(&pool);
+        // ...and there's no room for this one
+        let (_, fourth) = dispatch(&pool);
+        assert_eq!(next_event(&fourth), "rejected");
+        assert_eq!(pool.workers.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.active_threads.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.pending.load(Ordering::SeqCst), 1);
+
+        release_first.send(()).unwrap();
+        assert_eq!(next_event(&third), "started");
+        assert_eq!(pool.workers.load(Ordering::SeqCst), 2);
+
+        release_second.send(()).unwrap();
+        release_third.send(()).unwrap();
+        wait_until_idle(&pool);
+    }
+
+    #[test]
+    fn holds_connections_until_there_is_room() {
+        let pool = pool(1, 1, QueueFullPolicy::Hold);
+        let (release_first, first) = dispatch(&pool);
+        assert_eq!(next_event(&first), "started");
+        let (release_second, second) = dispatch(&pool);
+
+        let pool_for_thread = pool.clone();
+        let held = thread::spawn(move || dispatch(&pool_for_thread));
+        thread::sleep(Duration::from_millis(100));
+        assert!(
+            !held.is_finished(),
+            "Dispatch should wait for room in the queue"
+        );
+        assert_eq!(pool.pending.load(Ordering::SeqCst), 2);
+
+        release_first.send(()).unwrap();
+        assert_eq!(next_event(&second), "started");
+        let (release_third, third) = held.join().unwrap();
+        release_second.send(()).unwrap();
+        assert_eq!(next_event(&third), "started");
+        release_third.send(()).unwrap();
+        wait_until_idle(&pool);
+    }
+
+    #[test]
+    fn workers_exit_after_shutdown() {
+        let pool = pool(4, 4, QueueFullPolicy::Reject);
+        let (release, events) = dispatch(&pool);
+        assert_eq!(next_event(&events), "started");
+        pool.shutdown_signal.clone().start_shutdown();
+        release.send(()).unwrap();
+
+        let stop_at = Instant::now() + Duration::from_secs(5);
+        while pool.workers.load(Ordering::SeqCst) > 0 {
+            assert!(Instant::now() < stop_at, "Workers should exit");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(pool.is_idle());
+    }
+
+    #[test]
+    fn runs_shutdown_callbacks_once() {
+        let mut shutdown_signal = ShutdownSignal::new();
+        let (events, received) = mpsc::channel();
+        let events_for_kept = events.clone();
+        let _kept = shutdown_signal.on_shutdown(move || events_for_kept.send("kept").unwrap());
+        let events_for_dropped = events.clone();
+        drop(shutdown_signal.on_shutdown(move || events_for_dropped.send("dropped").unwrap()));
+
+        assert!(shutdown_signal.start_shutdown());
+        assert!(!shutdown_signal.start_shutdown());
+        // Registering after shutdown has started runs the callback straight away
+        let _late = shutdown_signal.on_shutdown(move || events.send("late").unwrap());
+        assert_eq!(
+            received.try_iter().collect::<Vec<_>>(),
+            vec!["kept", "late"]
+        );
+    }
+
+    #[test]
+    fn wakes_waiters_as_soon_as_shutdown_completes() {
+        let shutdown_signal = ShutdownSignal::new();
+        let waiter = {
+            let shutdown_signal = shutdown_signal.clone();
+            thread::spawn(move || {
+                shutdown_signal.sleep_until_shutdown().unwrap();
+                Instant::now()
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        let mut controller = shutdown_signal.clone();
+        assert_eq!(controller.complete_shutdown(), None);
+        assert!(controller.start_shutdown());
+        assert!(!shutdown_signal.is_shutdown_complete());
+        assert_eq!(controller.complete_shutdown(), Some(true));
+        let completed_at = Instant::now();
+        assert_eq!(controller.complete_shutdown(), Some(false));
+
+        let woke_at = waiter.join().unwrap();
+        assert!(woke_at.saturating_duration_since(completed_at) < Duration::from_millis(50));
+    }
+
+    fn never_called(
+        _stream: &mut Stream,
+        _remote_address: &PeerAddress,
+    ) -> Result<(), Box<dyn Error>> {
+        unreachable!("No connections are made")
+    }
+
+    #[test]
+    fn idle_servers_shut_down_promptly_and_stop_listening() {
+        let (ctx, address) = test_context("test");
+        let mut shutdown_signal = StreamServer::new().serve(&ctx, never_called).unwrap();
+
+        let started = Instant::now();
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        // The accept loop was woken, so it drops the listener, leaving only the context's
+        drop(ctx);
+        let stop_at = Instant::now() + Duration::from_secs(1);
+        while TcpStream::connect(address).is_ok() {
+            assert!(Instant::now() < stop_at, "Listener should be closed");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// A socket path unique to this test, with nothing at it yet.
+    fn unix_socket_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("protohackers-{}-{}.sock", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn unix_context(path: &Path) -> Context {
+        Context::new(
+            VecDeque::from([String::from("protohackers"), String::from("test")]),
+            format!("unix:{}", path.display()),
+        )
+    }
+
+    #[test]
+    fn serves_unix_sockets_and_cleans_up_on_shutdown() {
+        let path = unix_socket_path("serve");
+        let (peers, peer_receiver) = mpsc::channel();
+        let peers = Mutex::new(peers);
+        let handler = move |stream: &mut Stream, remote_address: &PeerAddress| {
+            peers.lock().unwrap().send(remote_address.clone())?;
+            echo_once(stream, remote_address)
+        };
+        let mut shutdown_signal = StreamServer::new()
+            .serve(&unix_context(&path), handler)
+            .unwrap();
+
+        let mut stream = Stream::connect(&format!("unix:{}", path.display())).unwrap();
+        stream.write_all(b"01234567").unwrap();
+        let mut response = [0u8; 8];
+        stream.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"01234567");
+        let peer = peer_receiver.recv().unwrap();
+        assert_eq!(peer, PeerAddress::Unix(None));
+        assert_eq!(peer.to_string(), "unix:(unnamed)");
+
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+        assert!(!path.exists(), "Socket file should be removed");
+    }
+
+    #[test]
+    fn replaces_stale_unix_sockets_only() {
+        let path = unix_socket_path("stale");
+        // A server which went away without cleaning up
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let mut shutdown_signal = StreamServer::new()
+            .serve(&unix_context(&path), never_called)
+            .unwrap();
+
+        // But a live one keeps its socket
+        let error = StreamServer::new()
+            .serve(&unix_context(&path), never_called)
+            .err()
+            .expect("Socket should be in use");
+        assert!(error.to_string().contains("in use"), "{}", error);
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+
+        // And other files are never removed
+        fs::write(&path, "not a socket").unwrap();
+        assert!(StreamServer::new()
+            .serve(&unix_context(&path), never_called)
+            .is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn read_forever(
+        stream: &mut Stream,
+        _remote_address: &PeerAddress,
+    ) -> Result<(), Box<dyn Error>> {
+        // Deliberately ignores shutdown, so only force-closing the connection gets us out of here
+        io::copy(stream, &mut io::sink())?;
+        Ok(())
+    }
+
+    #[test]
+    fn force_closes_connections_at_the_shutdown_deadline() {
+        let (ctx, address) = test_context("test");
+        let shutdown_timeout = Duration::from_millis(300);
+        let ctx = ctx.with_worker_pool(WorkerPoolConfig {
+            shutdown_timeout,
+            ..WorkerPoolConfig::default()
+        });
+        let mut shutdown_signal = StreamServer::new().serve(&ctx, read_forever).unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"still here").unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(
+            shutdown_timeout + FORCE_CLOSE_TIMEOUT + Duration::from_secs(1)
+        ));
+        assert!(started.elapsed() >= shutdown_timeout);
+        assert_eq!(client.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    /// Counts how many times each datagram is handled, echoing each one back.
+    #[derive(Clone, Default)]
+    struct DatagramCounter {
+        counts: Arc<Mutex<HashMap<Vec<u8>, usize>>>,
+    }
+
+    impl DatagramHandler for DatagramCounter {
+        fn handle(
+            &self,
+            payload: &[u8],
+            _peer: &SocketAddr,
+            reply: &ReplySink,
+        ) -> Result<(), Box<dyn Error>> {
+            *self
+                .counts
+                .lock()
+                .unwrap()
+                .entry(payload.to_vec())
+                .or_default() += 1;
+            reply.send(payload)?;
+            Ok(())
+        }
+    }
+
+    fn serve_udp(server: UdpServer, handler: impl DatagramHandler) -> (SocketAddr, ShutdownSignal) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let ctx = adopting_context("test", vec![socket.into()]);
+        let shutdown_signal = server.serve(&ctx, handler).unwrap();
+        (address, shutdown_signal)
+    }
+
+    fn udp_client(server_address: SocketAddr) -> UdpSocket {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server_address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+    }
+
+    #[test]
+    fn handles_each_datagram_exactly_once() {
+        const CLIENTS: usize = 4;
+        const BURSTS: usize = 10;
+        const BURST_SIZE: usize = 25;
+
+        let counter = DatagramCounter::default();
+        let (address, mut shutdown_signal) = serve_udp(UdpServer::new(), counter.clone());
+        let clients: Vec<_> = (0..CLIENTS)
+            .map(|client_number| {
+                thread::spawn(move || {
+                    let client = udp_client(address);
+                    for burst in 0..BURSTS {
+                        let mut expected: Vec<Vec<u8>> = (0..BURST_SIZE)
+                            .map(|n| format!("{}-{}-{}", client_number, burst, n).into_bytes())
+                            .collect();
+                        for payload in &expected {
+                            client.send(payload).unwrap();
+                        }
+                        let mut replies = Vec::new();
+                        let mut buffer = [0u8; 64];
+                        for _ in 0..BURST_SIZE {
+                            let length = client
+                                .recv(&mut buffer)
+                                .expect("Every datagram should get a reply");
+                            replies.push(buffer[..length].to_vec());
+                        }
+                        expected.sort();
+                        replies.sort();
+                        assert_eq!(replies, expected);
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+
+        let counts = counter.counts.lock().unwrap();
+        assert_eq!(counts.len(), CLIENTS * BURSTS * BURST_SIZE);
+        assert!(counts.values().all(|count| *count == 1));
+        drop(counts);
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn drops_oversized_datagrams() {
+        let counter = DatagramCounter::default();
+        let (address, _shutdown_signal) =
+            serve_udp(UdpServer::new().with_max_datagram_size(8), counter.clone());
+        let client = udp_client(address);
+        client.send(b"too long!").unwrap();
+        client.send(b"fits now").unwrap();
+
+        let mut buffer = [0u8; 16];
+        let length = client.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"fits now");
+        assert_eq!(counter.counts.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn datagram_servers_shut_down_promptly() {
+        let (_, mut shutdown_signal) = serve_udp(UdpServer::new(), DatagramCounter::default());
+        let started = Instant::now();
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    /// Records which hooks were called, in order.
+    struct HookRecorder {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ConnectionHandler<Stream> for HookRecorder {
+        fn on_listen(&self, _local_address: &PeerAddress) {
+            self.events.lock().unwrap().push(String::from("listen"));
+        }
+
+        fn handle(
+            &self,
+            stream: &mut Stream,
+            _remote_address: &PeerAddress,
+            _shutdown_signal: &ShutdownSignal,
+        ) -> Result<(), Box<dyn Error>> {
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line)?;
+            self.events.lock().unwrap().push(line);
+            Ok(())
+        }
+
+        fn on_shutdown(&self) {
+            self.events.lock().unwrap().push(String::from("shutdown"));
+        }
+    }
+
+    #[test]
+    fn calls_handler_hooks() {
+        let (ctx, address) = test_context("test");
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut shutdown_signal = StreamServer::new()
+            .serve(
+                &ctx,
+                HookRecorder {
+                    events: events.clone(),
+                },
+            )
+            .unwrap();
+
+        TcpStream::connect(address)
+            .unwrap()
+            .write_all(b"connection\n")
+            .unwrap();
+        let stop_at = Instant::now() + Duration::from_secs(5);
+        while events.lock().unwrap().len() < 2 {
+            assert!(Instant::now() < stop_at, "Connection should be handled");
+            thread::sleep(Duration::from_millis(10));
+        }
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["listen", "connection\n", "shutdown"]
+        );
+    }
+
+    fn announce_then_wait(
+        stream: &mut Stream,
+        _remote_address: &PeerAddress,
+    ) -> Result<(), Box<dyn Error>> {
+        stream.write_all(b"started\n")?;
+        io::copy(stream, &mut io::sink())?;
+        Ok(())
+    }
+
+    #[test]
+    fn closes_connections_with_a_message_when_full() {
+        let (ctx, address) = test_context("test");
+        let ctx = ctx.with_worker_pool(WorkerPoolConfig {
+            workers: 1,
+            queue_capacity: 1,
+            queue_full_policy: QueueFullPolicy::CloseWithMessage(String::from("busy")),
+            ..WorkerPoolConfig::default()
+        });
+        let mut shutdown_signal = StreamServer::new().serve(&ctx, announce_then_wait).unwrap();
+
+        let read_line = |stream: &TcpStream| {
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            line
+        };
+        let first = TcpStream::connect(address).unwrap();
+        assert_eq!(read_line(&first), "started\n");
+        let second = TcpStream::connect(address).unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(
+            (&second).read(&mut [0u8; 1]).is_err(),
+            "Second connection should be waiting in the queue"
+        );
+
+        let mut third = TcpStream::connect(address).unwrap();
+        let mut response = String::new();
+        third.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "busy\n");
+        assert!(ctx
+            .metrics
+            .render()
+            .contains("protohackers_connections_rejected_total{problem=\"test\"} 1\n"));
+
+        drop(first);
+        second.set_read_timeout(None).unwrap();
+        assert_eq!(read_line(&second), "started\n");
+        drop(second);
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn shares_one_pool_between_every_bind_address() {
+        let ipv4 = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ipv6 = TcpListener::bind("[::1]:0").unwrap();
+        let path = unix_socket_path("shared");
+        let unix = UnixListener::bind(&path).unwrap();
+        let (ipv4_address, ipv6_address) = (ipv4.local_addr().unwrap(), ipv6.local_addr().unwrap());
+        let ctx = adopting_context("test", vec![ipv4.into(), ipv6.into(), unix.into()])
+            .with_worker_pool(WorkerPoolConfig {
+                workers: 1,
+                queue_capacity: 1,
+                queue_full_policy: QueueFullPolicy::CloseWithMessage(String::from("busy")),
+                ..WorkerPoolConfig::default()
+            });
+        let mut shutdown_signal = StreamServer::new().serve(&ctx, announce_then_wait).unwrap();
+
+        let read_line = |stream: &Stream| {
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            line
+        };
+        // One worker and one queue slot between all three listeners
+        let first = Stream::connect(&ipv4_address.to_string()).unwrap();
+        assert_eq!(read_line(&first), "started\n");
+        let second = Stream::connect(&ipv6_address.to_string()).unwrap();
+        second
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(
+            (&second).read(&mut [0u8; 1]).is_err(),
+            "Second connection should be waiting in the queue"
+        );
+        second.set_read_timeout(None).unwrap();
+        let third = Stream::connect(&format!("unix:{}", path.display())).unwrap();
+        assert_eq!(read_line(&third), "busy\n");
+
+        drop(first);
+        assert_eq!(read_line(&second), "started\n");
+        drop(second);
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reads_proxy_headers_before_handling() {
+        let (ctx, address) = test_context("test");
+        let ctx = ctx.with_proxy_protocol(true).with_limits(LimitsConfig {
+            deny: vec!["203.0.113.0/24".parse().unwrap()],
+            ..LimitsConfig::default()
+        });
+        let handler = |stream: &mut Stream, remote_address: &PeerAddress| {
+            let mut line = String::new();
+            BufReader::new(&*stream).read_line(&mut line)?;
+            stream.write_all(format!("{} {}", remote_address, line).as_bytes())?;
+            Ok(())
+        };
+        let mut shutdown_signal = StreamServer::new().serve(&ctx, handler).unwrap();
+
+        let send = |request: &[u8]| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            stream.write_all(request).unwrap();
+            let mut response = String::new();
+            let result = stream.read_to_string(&mut response);
+            (result.map(|_| response), stream.local_addr().unwrap())
+        };
+        let (response, _) = send(b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\nhello\n");
+        assert_eq!(response.unwrap(), "192.0.2.1:56324 hello\n");
+        let (response, _) = send(b"PROXY TCP6 2001:db8::1 2001:db8::2 9000 443\r\nhello\n");
+        assert_eq!(response.unwrap(), "[2001:db8::1]:9000 hello\n");
+        // UNKNOWN means the connection is what it looks like
+        let (response, local_address) = send(b"PROXY UNKNOWN\r\nhello\n");
+        assert_eq!(response.unwrap(), format!("{} hello\n", local_address));
+        // No header, no handler: the connection is closed, or reset as we never read all of it
+        let (response, _) = send(b"hello, is anybody there?\n");
+        assert!(response.map_or_else(
+            |e| e.kind() == io::ErrorKind::ConnectionReset,
+            |response| response.is_empty()
+        ));
+        // Limits apply to the client, not the proxy
+        let (response, _) = send(b"PROXY TCP4 203.0.113.5 198.51.100.7 56324 443\r\nhello\n");
+        assert!(response.map_or_else(
+            |e| e.kind() == io::ErrorKind::ConnectionReset,
+            |response| response.is_empty()
+        ));
+        assert!(ctx.metrics.render().contains(
+            "protohackers_connections_limited_total{problem=\"test\",reason=\"denied\"} 1\n"
+        ));
+
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn caps_connections_per_ip() {
+        let (ctx, address) = test_context("test");
+        let ctx = ctx.with_limits(LimitsConfig {
+            max_connections_per_ip: Some(1),
+            ..LimitsConfig::default()
+        });
+        let mut shutdown_signal = StreamServer::new().serve(&ctx, announce_then_wait).unwrap();
+        let read_line = |stream: &TcpStream| {
+            let mut line = String::new();
+            BufReader::new(stream).read_line(&mut line).unwrap();
+            line
+        };
+
+        let first = TcpStream::connect(address).unwrap();
+        assert_eq!(read_line(&first), "started\n");
+        let second = TcpStream::connect(address).unwrap();
+        assert_eq!(read_line(&second), "", "Should be closed straight away");
+        let rendered = ctx.metrics.render();
+        assert!(rendered.contains(
+            "protohackers_connections_limited_total{problem=\"test\",reason=\"too_many_connections\"} 1\n"
+        ));
+        assert!(rendered.contains("protohackers_connections_rejected_total{problem=\"test\"} 1\n"));
+
+        drop(first);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let third = loop {
+            let third = TcpStream::connect(address).unwrap();
+            let mut line = String::new();
+            if BufReader::new(&third).read_line(&mut line).unwrap() > 0 {
+                break third;
+            }
+            // The first connection's handler hasn't noticed it closed yet
+            assert!(Instant::now() < deadline, "Never let another connection in");
+            thread::sleep(Duration::from_millis(10));
+        };
+        drop(third);
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn survives_panicking_handlers() {
+        let (ctx, address) = test_context("test");
+        let ctx = ctx.with_worker_pool(WorkerPoolConfig {
+            workers: 1,
+            ..WorkerPoolConfig::default()
+        });
+        let handler = |stream: &mut Stream, _remote_address: &PeerAddress| {
+            let mut line = String::new();
+            BufReader::new(&*stream).read_line(&mut line)?;
+            if line == "panic\n" {
+                panic!("Asked to panic");
+            }
+            stream.write_all(line.as_bytes())?;
+            Ok(())
+        };
+        let mut shutdown_signal = StreamServer::new().serve(&ctx, handler).unwrap();
+
+        let mut panicking = TcpStream::connect(address).unwrap();
+        panicking.write_all(b"panic\n").unwrap();
+        assert_eq!(panicking.read(&mut [0u8; 1]).unwrap(), 0);
+        // The only worker is still there to handle the next connection
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"hello\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "hello\n");
+
+        let rendered = ctx.metrics.render();
+        assert!(rendered.contains("protohackers_handler_panics_total{problem=\"test\"} 1\n"));
+        assert!(rendered.contains("protohackers_handler_errors_total{problem=\"test\"} 1\n"));
+        let started = Instant::now();
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+        assert!(
+            started.elapsed() < SHUTDOWN_TIMEOUT,
+            "Panicking handler should not count as active"
+        );
+        assert!(ctx.connections.list().is_empty());
+    }
+
+    #[test]
+    fn gives_up_worker_slots_however_workers_stop() {
+        let pool = pool(1, 1, QueueFullPolicy::Reject);
+        pool.workers.fetch_add(1, Ordering::SeqCst);
+        drop(WorkerSlot { pool: pool.clone() });
+        assert_eq!(pool.workers.load(Ordering::SeqCst), 0);
+
+        pool.workers.fetch_add(1, Ordering::SeqCst);
+        let slot = WorkerSlot { pool: pool.clone() };
+        thread::spawn(move || {
+            let _slot = slot;
+            panic!("Worker died");
+        })
+        .join()
+        .unwrap_err();
+        assert_eq!(pool.workers.load(Ordering::SeqCst), 0);
+    }
+
+    /// A TCP server whose listener fails to accept with OS error `ERRNO` the first `FAILURES`
+    /// times, as if we'd run out of file descriptors.
+    struct FlakyServer<const ERRNO: i32, const FAILURES: usize>();
+
+    struct FlakyListener {
+        listener: TcpListener,
+        errno: i32,
+        failures: AtomicUsize,
+    }
+
+    impl<const ERRNO: i32, const FAILURES: usize> Server for FlakyServer<ERRNO, FAILURES> {
+        type Listener = FlakyListener;
+        type ConnectionLike = Stream;
+
+        fn get_listener(bind_address: &str) -> io::Result<Self::Listener> {
+            Self::flaky(TcpServer::get_listener(bind_address)?)
+        }
+
+        fn adopt_listener(socket: OwnedFd) -> io::Result<Self::Listener> {
+            Self::flaky(TcpServer::adopt_listener(socket)?)
+        }
+
+        fn pump(listener: &Self::Listener) -> io::Result<(Self::ConnectionLike, PeerAddress)> {
+            if listener
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |failures| {
+                    failures.checked_sub(1)
+                })
+                .is_ok()
+            {
+                return Err(io::Error::from_raw_os_error(listener.errno));
+            }
+            TcpServer::pump(&listener.listener)
+        }
+
+        fn get_local_address(listener: &Self::Listener) -> io::Result<PeerAddress> {
+            TcpServer::get_local_address(&listener.listener)
+        }
+
+        fn reject(
+            connection: Self::ConnectionLike,
+            _remote_address: &PeerAddress,
+            message: Option<&str>,
+        ) -> io::Result<()> {
+            reject_stream(connection, message)
+        }
+
+        fn wake_listener(local_address: &PeerAddress) {
+            TcpServer::wake_listener(local_address)
+        }
+    }
+
+    impl<const ERRNO: i32, const FAILURES: usize> FlakyServer<ERRNO, FAILURES> {
+        fn flaky(listener: TcpListener) -> io::Result<FlakyListener> {
+            Ok(FlakyListener {
+                listener,
+                errno: ERRNO,
+                failures: AtomicUsize::new(FAILURES),
+            })
+        }
+    }
+
+    #[test]
+    fn backs_off_and_retries_when_out_of_file_descriptors() {
+        let (ctx, address) = test_context("test");
+        let mut shutdown_signal = FlakyServer::<{ libc::EMFILE }, 5>()
+            .serve(&ctx, echo_once)
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while ctx.health.is_healthy() {
+            assert!(
+                Instant::now() < deadline,
+                "Should be unhealthy while failing"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        let problems = ctx.health.problems();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].0, address.to_string());
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"01234567").unwrap();
+        let mut buffer = [0u8; 8];
+        stream.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"01234567");
+        assert!(ctx.health.is_healthy());
+        assert!(!shutdown_signal.is_shutdown_initiated());
+
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+        assert!(shutdown_signal.sleep_until_shutdown().is_ok());
+    }
+
+    /// Runs out of file descriptors for real. The limit is per process, so this would break other
+    /// tests running alongside it; run it alone with
+    /// `cargo test -- --ignored --exact server::tests::recovers_from_running_out_of_file_descriptors`.
+    #[test]
+    #[ignore]
+    #[cfg(target_os = "linux")]
+    fn recovers_from_running_out_of_file_descriptors() {
+        use std::os::fd::FromRawFd;
+
+        let (ctx, address) = test_context("test");
+        let mut shutdown_signal = StreamServer::new().serve(&ctx, echo_once).unwrap();
+        let SocketAddr::V4(address) = address else {
+            unreachable!("test_context listens on IPv4")
+        };
+
+        // Made before the limit is lowered, and connected after, so the server can't accept them
+        let clients: Vec<OwnedFd> = (0..3)
+            .map(|_| {
+                // SAFETY: socket returns a new descriptor we own, or -1
+                let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+                assert!(fd >= 0, "{}", io::Error::last_os_error());
+                unsafe { OwnedFd::from_raw_fd(fd) }
+            })
+            .collect();
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: limit is a valid rlimit for getrlimit to fill in
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+            0
+        );
+        // Every descriptor below the lowest free one is in use, so a limit of that leaves none
+        let lowest_free = std::fs::File::open("/dev/null").unwrap().as_raw_fd();
+        let exhausted = libc::rlimit {
+            rlim_cur: lowest_free as libc::rlim_t,
+            ..limit
+        };
+        // SAFETY: exhausted is a valid rlimit
+        assert_eq!(
+            unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &exhausted) },
+            0
+        );
+
+        let server_address = libc::sockaddr_in {
+            sin_family: libc::AF_INET as libc::sa_family_t,
+            sin_port: address.port().to_be(),
+            sin_addr: libc::in_addr {
+                s_addr: u32::from(*address.ip()).to_be(),
+            },
+            sin_zero: [0; 8],
+        };
+        for client in &clients {
+            // SAFETY: server_address is a valid sockaddr_in of the given length
+            let connected = unsafe {
+                libc::connect(
+                    client.as_raw_fd(),
+                    &server_address as *const libc::sockaddr_in as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+            };
+            assert_eq!(connected, 0, "{}", io::Error::last_os_error());
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while ctx.health.is_healthy() {
+            assert!(
+                Instant::now() < deadline,
+                "Should be unhealthy while out of file descriptors"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // SAFETY: limit is the valid rlimit we started with
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+        for client in clients {
+            let mut stream = TcpStream::from(client);
+            stream.write_all(b"01234567").unwrap();
+            let mut buffer = [0u8; 8];
+            stream.read_exact(&mut buffer).unwrap();
+            assert_eq!(&buffer, b"01234567");
+        }
+        assert!(ctx.health.is_healthy());
+        assert!(!shutdown_signal.is_shutdown_initiated());
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn shuts_down_with_an_error_when_accepting_fails_for_good() {
+        let (ctx, address) = test_context("test");
+        let mut shutdown_signal = FlakyServer::<{ libc::EBADF }, 1>()
+            .serve(&ctx, echo_once)
+            .unwrap();
+
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+        let error = shutdown_signal.sleep_until_shutdown().unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with(&format!("Error accepting connections on {}: ", address)),
+            "{}",
+            error
+        );
+        assert!(!ctx.health.is_healthy());
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let mut backoff = Backoff::default();
+        let delays = (0..10).map(|_| backoff.next_delay()).collect::<Vec<_>>();
+        assert_eq!(delays[0], MIN_BACKOFF);
+        assert_eq!(delays[1], MIN_BACKOFF * 2);
+        assert_eq!(delays[9], MAX_BACKOFF);
+        assert!(backoff.reset());
+        assert!(!backoff.reset());
+        assert_eq!(backoff.next_delay(), MIN_BACKOFF);
+    }
+
+    fn echo_once(stream: &mut Stream, _remote_address: &PeerAddress) -> Result<(), Box<dyn Error>> {
+        let mut buffer = [0u8; 8];
+        stream.read_exact(&mut buffer)?;
+        stream.write_all(&buffer)?;
+        Ok(())
+    }
+
+    #[test]
+    fn counts_connections_and_bytes_for_metrics() {
+        let (ctx, address) = test_context("echo");
+        let metrics_address = crate::metrics::serve_http(
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            ctx.metrics.clone(),
+            ctx.health.clone(),
+        )
+        .unwrap();
+        let mut shutdown_signal = StreamServer::new().serve(&ctx, echo_once).unwrap();
+
+        for _ in 0..2 {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(b"01234567").unwrap();
+            stream.read_exact(&mut [0u8; 8]).unwrap();
+        }
+        // Hanging up part way through makes the handler fail
+        TcpStream::connect(address)
+            .unwrap()
+            .write_all(b"0123")
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !ctx
+            .metrics
+            .render()
+            .contains("protohackers_connections_completed_total{problem=\"echo\"} 3\n")
+        {
+            assert!(Instant::now() < deadline, "Handlers didn't finish");
+            thread::sleep(Duration::from_millis(10));
+        }
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+
+        let mut scrape = TcpStream::connect(metrics_address).unwrap();
+        scrape.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        scrape.read_to_string(&mut response).unwrap();
+        for line in [
+            "protohackers_connections_accepted_total{problem=\"echo\"} 3",
+            "protohackers_connections_rejected_total{problem=\"echo\"} 0",
+            "protohackers_connections_completed_total{problem=\"echo\"} 3",
+            "protohackers_handler_errors_total{problem=\"echo\"} 1",
+            "protohackers_bytes_read_total{problem=\"echo\"} 20",
+            "protohackers_bytes_written_total{problem=\"echo\"} 16",
+            "protohackers_connection_duration_seconds_count{problem=\"echo\"} 3",
+        ] {
+            assert!(
+                response.lines().any(|l| l == line),
+                "{} not in\n{}",
+                line,
+                response
+            );
+        }
+    }
+
+    #[test]
+    fn registers_connections_so_they_can_be_kicked() {
+        let (ctx, address) = test_context("test");
+        let mut shutdown_signal = StreamServer::new().serve(&ctx, echo_once).unwrap();
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"0123").unwrap();
+        let connection = wait_for_bytes_read(&ctx, 4);
+        assert_eq!(
+            connection.peer,
+            PeerAddress::from(client.local_addr().unwrap())
+        );
+        assert_eq!(connection.listener, PeerAddress::from(address));
+
+        ctx.connections.kick(connection.id).unwrap();
+        assert_eq!(client.read(&mut [0u8; 1]).unwrap(), 0);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !ctx.connections.list().is_empty() {
+            assert!(Instant::now() < deadline, "Connection wasn't removed");
+            thread::sleep(Duration::from_millis(10));
+        }
+        shutdown_signal.start_shutdown();
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn draining_waits_past_the_shutdown_timeout() {
+        let (ctx, address) = test_context("test");
+        let shutdown_timeout = Duration::from_millis(100);
+        let ctx = ctx.with_worker_pool(WorkerPoolConfig {
+            shutdown_timeout,
+            ..WorkerPoolConfig::default()
+        });
+        let mut shutdown_signal = StreamServer::new().serve(&ctx, echo_once).unwrap();
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"0123").unwrap();
+        wait_for_bytes_read(&ctx, 4);
+        assert!(ctx.connections.drain());
+        assert!(!ctx.connections.drain(), "Already draining");
+        // Leaving only the server's own copy of the listener
+        drop(ctx);
+        thread::sleep(shutdown_timeout + FORCE_CLOSE_TIMEOUT + Duration::from_millis(200));
+        assert!(!shutdown_signal.is_shutdown_complete());
+        assert!(
+            TcpStream::connect(address).is_err(),
+            "Should stop accepting"
+        );
+
+        client.write_all(b"4567").unwrap();
+        let mut buffer = [0u8; 8];
+        client.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"01234567");
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn shutting_down_cuts_a_drain_short() {
+        let (ctx, address) = test_context("test");
+        let mut shutdown_signal = StreamServer::new().serve(&ctx, echo_once).unwrap();
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"0123").unwrap();
+        wait_for_bytes_read(&ctx, 4);
+        assert!(ctx.connections.drain());
+        assert!(ctx.connections.shutdown());
+        assert!(!ctx.connections.shutdown(), "Already shutting down");
+        // Force-closed at the shutdown deadline
+        assert_eq!(client.read(&mut [0u8; 1]).unwrap(), 0);
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn shuts_down_once_the_drain_timeout_passes() {
+        let (ctx, address) = test_context("test");
+        let ctx = ctx.with_worker_pool(WorkerPoolConfig {
+            drain_timeout: Some(Duration::from_millis(500)),
+            ..WorkerPoolConfig::default()
+        });
+        let mut shutdown_signal = StreamServer::new().serve(&ctx, echo_once).unwrap();
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"0123").unwrap();
+        wait_for_bytes_read(&ctx, 4);
+        assert!(ctx.connections.drain());
+        thread::sleep(Duration::from_millis(500) + SLEEP_DURATION * 2);
+        assert!(shutdown_signal.is_shutdown_initiated());
+        // Force-closed at the shutdown deadline
+        assert_eq!(client.read(&mut [0u8; 1]).unwrap(), 0);
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn tells_handlers_to_stop_only_once_a_drain_is_over() {
+        let mut shutdown_signal = ShutdownSignal::new();
+        let (sender, events) = mpsc::channel();
+        let stop_accepting = sender.clone();
+        let _stop_accepting = shutdown_signal.on_stop_accepting(move || {
+            stop_accepting.send("stop accepting").unwrap();
+        });
+        let _shutdown = shutdown_signal.on_shutdown(move || sender.send("shut down").unwrap());
+
+        assert!(shutdown_signal.start_drain());
+        assert_eq!(events.try_recv(), Ok("stop accepting"));
+        assert!(events.try_recv().is_err());
+        assert!(!shutdown_signal.is_accepting());
+        assert!(!shutdown_signal.is_shutdown_initiated());
+        assert!(!shutdown_signal.start_shutdown(), "Draining, not running");
+
+        assert!(shutdown_signal.stop_draining());
+        assert_eq!(events.try_recv(), Ok("shut down"));
+        assert!(shutdown_signal.is_shutdown_initiated());
+        assert_eq!(shutdown_signal.complete_shutdown(), Some(true));
+    }
+
+    /// Answers each connection's first byte with whether it has been told to stop.
+    struct AnswersWhetherToStop;
+
+    impl ConnectionHandler<Stream> for AnswersWhetherToStop {
+        fn handle(
+            &self,
+            connection: &mut Stream,
+            _remote_address: &PeerAddress,
+            shutdown_signal: &ShutdownSignal,
+        ) -> Result<(), Box<dyn Error>> {
+            connection.read_exact(&mut [0u8; 1])?;
+            match shutdown_signal.is_shutdown_initiated() {
+                true => connection.write_all(b"stop")?,
+                false => connection.write_all(b"more")?,
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn handles_the_last_connections_accepted_while_draining() {
+        let (ctx, address) = test_context("test");
+        let mut shutdown_signal = StreamServer::new()
+            .serve(&ctx, AnswersWhetherToStop)
+            .unwrap();
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"?").unwrap();
+        let mut answer = [0u8; 4];
+        client.read_exact(&mut answer).unwrap();
+        assert_eq!(&answer, b"more");
+
+        // Queued behind the listener, then accepted when the drain wakes it
+        let mut late_client = TcpStream::connect(address).unwrap();
+        assert!(ctx.connections.drain());
+        late_client.write_all(b"?").unwrap();
+        late_client.read_exact(&mut answer).unwrap();
+        assert_eq!(
+            &answer, b"more",
+            "Handlers aren't told to stop while draining"
+        );
+        assert!(!shutdown_signal.sleep_until_shutdown_or_timeout(Duration::from_secs(5)));
+    }
+
+    /// Wait until the only registered connection has had `bytes` read from it, and return it.
+    fn wait_for_bytes_read(ctx: &Context, bytes: u64) -> crate::admin::ConnectionInfo {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match ctx.connections.list().pop() {
+                Some(connection) if connection.bytes_read == bytes => return connection,
+                _ => assert!(Instant::now() < deadline, "Connection wasn't registered"),
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Time `connections` short-lived connections spread across `clients` client threads.
+    fn time_connections(address: SocketAddr, clients: usize, connections: usize) -> Duration {
+        let started = Instant::now();
+        let threads: Vec<_> = (0..clients)
+            .map(|_| {
+                thread::spawn(move || {
+                    for _ in 0..connections / clients {
+                        let mut stream = TcpStream::connect(address).unwrap();
+                        stream.write_all(b"01234567").unwrap();
+                        stream.read_exact(&mut [0u8; 8]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        started.elapsed()
+    }
+
+    /// Compares the worker pool against spawning a thread for every connection.
+    /// Run with `cargo test --release -- --ignored --nocapture benchmark`.
+    #[test]
+    #[ignore]
+    fn benchmark_worker_pool_against_thread_per_connection() {
+        const CLIENTS: usize = 32;
+        const CONNECTIONS: usize = 20_000;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for (stream, remote_address) in
+                std::iter::from_fn(|| Some(listener.accept())).map_while(Result::ok)
+            {
+                thread::spawn(move || {
+                    let _ = echo_once(&mut stream.into(), &remote_address.into());
+                });
+            }
+        });
+        let thread_per_connection = time_connections(address, CLIENTS, CONNECTIONS);
+
+        let (ctx, address) = test_context("benchmark");
+        let mut shutdown_signal = StreamServer::new().serve(&ctx, echo_once).unwrap();
+        let worker_pool = time_connections(address, CLIENTS, CONNECTIONS);
+        shutdown_signal.sleep_until_shutdown_or_timeout(Duration::ZERO);
+
+        println!(
+            "{} connections from {} clients: thread per connection {:?}, worker pool {:?}",
+            CONNECTIONS, CLIENTS, thread_per_connection, worker_pool
+        );
     }
 }
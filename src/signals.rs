@@ -0,0 +1,186 @@
+//! Running callbacks when the process receives a signal, outside the signal handler. ctrl-c is
+//! handled by the ctrlc crate instead; see [`crate::server::ShutdownSignal::set_as_ctrl_c_handler`].
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read},
+    mem,
+    os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use log::as_display;
+use once_cell::sync::OnceCell;
+
+type Callback = Arc<dyn Fn() + Send + Sync>;
+
+/// The write end of the pipe signal handlers pass signal numbers through
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+static CALLBACKS: OnceCell<Mutex<HashMap<libc::c_int, Vec<Callback>>>> = OnceCell::new();
+
+// Where this thread's errno lives; every libc names it differently
+#[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+use libc::__errno as errno_location;
+#[cfg(any(target_os = "linux", target_os = "emscripten", target_os = "redox"))]
+use libc::__errno_location as errno_location;
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "dragonfly"
+))]
+use libc::__error as errno_location;
+
+extern "C" fn handle_signal(signal: libc::c_int) {
+    // Only async-signal-safe calls in here, and errno has to survive us
+    let errno = io::Error::last_os_error().raw_os_error().unwrap_or(0);
+    let byte = signal as u8;
+    // SAFETY: byte outlives the call. The pipe doesn't block, so if it's full the signal is
+    // dropped, which is fine because one is already waiting to be handled.
+    unsafe { libc::write(PIPE.load(Ordering::SeqCst), &byte as *const _ as _, 1) };
+    // SAFETY: errno_location always returns a valid pointer for this thread
+    unsafe { *errno_location() = errno };
+}
+
+/// Run `callback` on a background thread each time we receive `signal`.
+pub(crate) fn on_signal(
+    signal: libc::c_int,
+    callback: impl Fn() + Send + Sync + 'static,
+) -> io::Result<()> {
+    let callbacks = CALLBACKS.get_or_try_init(start_dispatcher)?;
+    let mut callbacks = callbacks
+        .lock()
+        .expect("Signal callbacks should not be poisoned");
+    let first_for_signal = !callbacks.contains_key(&signal);
+    callbacks
+        .entry(signal)
+        .or_default()
+        .push(Arc::new(callback));
+    if first_for_signal {
+        // SAFETY: an all-zero sigaction is valid, and handle_signal only does async-signal-safe things
+        let mut action: libc::sigaction = unsafe { mem::zeroed() };
+        action.sa_sigaction = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Create the pipe and start the thread which runs callbacks for whatever comes out of it.
+fn start_dispatcher() -> io::Result<Mutex<HashMap<libc::c_int, Vec<Callback>>>> {
+    let (mut reader, writer) = pipe()?;
+    // A signal handler must never block, even if signals arrive faster than we handle them
+    add_flags(
+        writer.as_raw_fd(),
+        libc::F_GETFL,
+        libc::F_SETFL,
+        libc::O_NONBLOCK,
+    )?;
+    // Signal handlers write to this for as long as the process lives
+    PIPE.store(writer.into_raw_fd(), Ordering::SeqCst);
+    thread::Builder::new()
+        .name("signal-dispatcher".into())
+        .spawn(move || {
+            let mut signal = [0u8; 1];
+            loop {
+                match reader.read(&mut signal) {
+                    Ok(1) => {}
+                    Ok(_) => break,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        log::error!(error = as_display!(e); "Unable to read signals; ignoring them from now on");
+                        break;
+                    }
+                }
+                let signal = libc::c_int::from(signal[0]);
+                // Cloned so a callback can register another without deadlocking
+                let callbacks = CALLBACKS
+                    .get()
+                    .and_then(|callbacks| {
+                        callbacks
+                            .lock()
+                            .expect("Signal callbacks should not be poisoned")
+                            .get(&signal)
+                            .cloned()
+                    })
+                    .unwrap_or_default();
+                for callback in callbacks {
+                    callback();
+                }
+            }
+        })?;
+    Ok(Mutex::new(HashMap::new()))
+}
+
+/// Create a pipe, returning its read and write ends, neither of which is inherited across exec.
+pub(crate) fn pipe() -> io::Result<(File, File)> {
+    let mut fds: [RawFd; 2] = [-1; 2];
+    // SAFETY: fds has room for both ends
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: pipe just gave us these descriptors, and nothing else owns them
+    let ends = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    for fd in fds {
+        add_flags(fd, libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC)?;
+    }
+    Ok(ends)
+}
+
+/// Set `flags` on `fd`, reading the old ones with `get` and writing them with `set`.
+pub(crate) fn add_flags(
+    fd: RawFd,
+    get: libc::c_int,
+    set: libc::c_int,
+    flags: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: fcntl with these commands only reads and writes the descriptor's flags
+    let old_flags = unsafe { libc::fcntl(fd, get) };
+    if old_flags == -1 || unsafe { libc::fcntl(fd, set, old_flags | flags) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn drops_signals_rather_than_blocking_when_they_pile_up() {
+        let (started, started_receiver) = mpsc::channel();
+        let (release, release_receiver) = mpsc::channel::<()>();
+        let (started, release_receiver) = (Mutex::new(started), Mutex::new(release_receiver));
+        on_signal(libc::SIGWINCH, move || {
+            let _ = started.lock().unwrap().send(());
+            let _ = release_receiver
+                .lock()
+                .unwrap()
+                .recv_timeout(Duration::from_secs(5));
+        })
+        .unwrap();
+
+        // SAFETY: SIGWINCH is handled, so raising it only runs our handler
+        unsafe { libc::raise(libc::SIGWINCH) };
+        started_receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        // With the dispatcher stuck in the callback, far more than the pipe holds
+        for _ in 0..100_000 {
+            unsafe { libc::raise(libc::SIGWINCH) };
+        }
+        release.send(()).unwrap();
+        started_receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+    }
+}
@@ -1,17 +1,14 @@
+use crate::check::{Scenario, Step, Transport};
 use crate::{scaffolding::Context, server};
-use server::{Server as _, TcpServer};
+use server::{PeerAddress, Server as _, ShutdownSignal, Stream, StreamServer};
 use std::error::Error;
 use std::io::{ErrorKind, Read, Write};
-use std::net::{SocketAddr, TcpStream};
 
-pub(crate) fn run(ctx: &Context) -> Result<(), Box<dyn Error>> {
-    let shutdown_signal = TcpServer::new().serve(ctx, handle)?;
-    shutdown_signal.set_as_ctrl_c_handler()?;
-    shutdown_signal.sleep_until_shutdown();
-    Ok(())
+pub(crate) fn serve(ctx: &Context) -> Result<ShutdownSignal, Box<dyn Error>> {
+    StreamServer::new().serve(ctx, handle)
 }
 
-fn handle(stream: &mut TcpStream, _remote_address: &SocketAddr) -> Result<(), Box<dyn Error>> {
+fn handle(stream: &mut Stream, _remote_address: &PeerAddress) -> Result<(), Box<dyn Error>> {
     let mut buffer = [0u8; 1024];
     loop {
         match stream.read(&mut buffer) {
@@ -27,3 +24,37 @@ pub(crate) fn help(ctx: &Context) -> Result<(), Box<dyn Error>> {
     println!("Usage: {} smoke_test", ctx.program_name);
     Ok(())
 }
+
+pub(crate) fn scenarios() -> Vec<Scenario> {
+    let all_bytes: Vec<u8> = (0..=u8::MAX).collect();
+    vec![
+        Scenario {
+            name: "echoes a line",
+            transport: Transport::Tcp,
+            steps: vec![Step::send(0, "hello\n"), Step::expect(0, "hello\n")],
+        },
+        Scenario {
+            name: "echoes every byte value",
+            transport: Transport::Tcp,
+            steps: vec![Step::send(0, &all_bytes), Step::expect(0, &all_bytes)],
+        },
+        Scenario {
+            name: "echoes large writes",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, vec![b'x'; 100_000]),
+                Step::expect(0, vec![b'x'; 100_000]),
+            ],
+        },
+        Scenario {
+            name: "echoes each client independently",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, "first"),
+                Step::send(1, "second"),
+                Step::expect(1, "second"),
+                Step::expect(0, "first"),
+            ],
+        },
+    ]
+}
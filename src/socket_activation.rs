@@ -0,0 +1,242 @@
+//! Listening sockets inherited from a supervisor like systemd, which passes them as file
+//! descriptors starting at 3 and describes them in LISTEN_PID, LISTEN_FDS and LISTEN_FDNAMES.
+//! See sd_listen_fds(3).
+//!
+//! Sockets named for the problem are served by its server, and those named `metrics` and `admin`
+//! by the metrics and admin servers. Without names, every socket is served by the problem.
+
+use std::{
+    env,
+    error::Error,
+    io, mem,
+    net::{TcpListener, UdpSocket},
+    os::{
+        fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixListener,
+    },
+};
+
+/// The first file descriptor a supervisor passes
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket passed to us by our parent.
+#[derive(Debug)]
+pub(crate) struct InheritedSocket {
+    fd: OwnedFd,
+    /// From LISTEN_FDNAMES, if it was set
+    pub(crate) name: Option<String>,
+}
+
+impl InheritedSocket {
+    /// A new file descriptor for the socket. The inherited one stays open, so the socket keeps
+    /// listening for as long as we're running, even between servers.
+    pub(crate) fn try_clone(&self) -> io::Result<OwnedFd> {
+        self.fd.as_fd().try_clone_to_owned()
+    }
+
+    pub(crate) fn fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Lets tests hand a server a socket they've already bound, as a supervisor would.
+#[cfg(test)]
+impl From<OwnedFd> for InheritedSocket {
+    fn from(fd: OwnedFd) -> Self {
+        Self { fd, name: None }
+    }
+}
+
+/// Read the sockets our parent passed us, if LISTEN_PID says they're meant for this process,
+/// and remove the variables so they aren't passed on to our children.
+pub(crate) fn from_env() -> Result<Option<Vec<InheritedSocket>>, Box<dyn Error>> {
+    let listen_pid = env::var("LISTEN_PID").ok();
+    let listen_fds = env::var("LISTEN_FDS").ok();
+    let listen_fdnames = env::var("LISTEN_FDNAMES").ok();
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    let (Some(listen_pid), Some(listen_fds)) = (listen_pid, listen_fds) else {
+        return Ok(None);
+    };
+    if listen_pid.parse::<u32>()? != std::process::id() {
+        log::debug!(listen_pid = listen_pid.as_str(); "Ignoring sockets meant for another process");
+        return Ok(None);
+    }
+    let count: RawFd = listen_fds.parse()?;
+    let names = match &listen_fdnames {
+        Some(names) => names
+            .split(':')
+            .map(|name| Some(String::from(name)))
+            .collect(),
+        None => vec![None; count as usize],
+    };
+    if names.len() != count as usize {
+        return Err(format!(
+            "LISTEN_FDNAMES has {} names but LISTEN_FDS is {}",
+            names.len(),
+            count
+        )
+        .into());
+    }
+
+    let mut sockets = Vec::new();
+    for (fd, name) in (LISTEN_FDS_START..LISTEN_FDS_START + count).zip(names) {
+        // Don't leak the sockets to anything we run
+        // SAFETY: fcntl doesn't touch memory, and fails harmlessly if fd isn't open
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(format!(
+                "Inherited file descriptor {} is unusable: {}",
+                fd,
+                io::Error::last_os_error()
+            )
+            .into());
+        }
+        // SAFETY: our parent passed the descriptor to us alone, and we checked it's open
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        sockets.push(InheritedSocket { fd, name });
+    }
+    Ok(Some(sockets))
+}
+
+/// The sockets to serve `problem` on: those named for it, or all of them if they have no names.
+/// One supervisor can pass the same set of sockets to processes serving different problems.
+pub(crate) fn for_problem<'a>(
+    sockets: &'a [InheritedSocket],
+    problem: Option<&str>,
+) -> Vec<&'a InheritedSocket> {
+    sockets
+        .iter()
+        .filter(|socket| socket.name.is_none() || socket.name.as_deref() == problem)
+        .collect()
+}
+
+/// The socket named `name`, if there is one.
+pub(crate) fn named<'a>(sockets: &'a [InheritedSocket], name: &str) -> Option<&'a InheritedSocket> {
+    sockets
+        .iter()
+        .find(|socket| socket.name.as_deref() == Some(name))
+}
+
+/// Take an inherited socket as a listening TCP socket.
+pub(crate) fn tcp_listener(socket: OwnedFd) -> io::Result<TcpListener> {
+    check_socket(
+        &socket,
+        libc::SOCK_STREAM,
+        &[libc::AF_INET, libc::AF_INET6],
+        "TCP",
+    )?;
+    Ok(TcpListener::from(socket))
+}
+
+/// Take an inherited socket as a listening Unix domain stream socket.
+pub(crate) fn unix_listener(socket: OwnedFd) -> io::Result<UnixListener> {
+    check_socket(
+        &socket,
+        libc::SOCK_STREAM,
+        &[libc::AF_UNIX],
+        "Unix domain stream",
+    )?;
+    Ok(UnixListener::from(socket))
+}
+
+/// Take an inherited socket as a UDP socket.
+pub(crate) fn udp_socket(socket: OwnedFd) -> io::Result<UdpSocket> {
+    check_socket(
+        &socket,
+        libc::SOCK_DGRAM,
+        &[libc::AF_INET, libc::AF_INET6],
+        "UDP",
+    )?;
+    Ok(UdpSocket::from(socket))
+}
+
+/// The address family of `socket`, like `libc::AF_INET`.
+pub(crate) fn address_family(socket: &OwnedFd) -> io::Result<libc::c_int> {
+    // SAFETY: an all-zero sockaddr_storage is valid, and getsockname writes no more than length
+    let mut address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut length = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    if unsafe {
+        libc::getsockname(
+            socket.as_raw_fd(),
+            &mut address as *mut _ as *mut libc::sockaddr,
+            &mut length,
+        )
+    } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(address.ss_family as libc::c_int)
+}
+
+/// Check that `socket` is of `socket_type` and one of `families`, and listening if it's a stream
+/// socket, so we don't find out it isn't only once we try to accept on it.
+fn check_socket(
+    socket: &OwnedFd,
+    socket_type: libc::c_int,
+    families: &[libc::c_int],
+    description: &str,
+) -> io::Result<()> {
+    if get_socket_option(socket, libc::SO_TYPE)? != socket_type
+        || !families.contains(&address_family(socket)?)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Inherited socket {} isn't a {} socket",
+                socket.as_raw_fd(),
+                description
+            ),
+        ));
+    }
+    if socket_type == libc::SOCK_STREAM && get_socket_option(socket, libc::SO_ACCEPTCONN)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Inherited socket {} isn't listening", socket.as_raw_fd()),
+        ));
+    }
+    Ok(())
+}
+
+fn get_socket_option(socket: &OwnedFd, option: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut length = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: value is big enough for the integer options we ask for
+    if unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &mut value as *mut _ as *mut libc::c_void,
+            &mut length,
+        )
+    } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    #[test]
+    fn checks_inherited_sockets() {
+        let listener = OwnedFd::from(TcpListener::bind("127.0.0.1:0").unwrap());
+        assert_eq!(address_family(&listener).unwrap(), libc::AF_INET);
+        assert!(udp_socket(listener.try_clone().unwrap()).is_err());
+        assert!(unix_listener(listener.try_clone().unwrap()).is_err());
+        assert!(tcp_listener(listener).is_ok());
+
+        let socket = OwnedFd::from(UdpSocket::bind("127.0.0.1:0").unwrap());
+        assert!(tcp_listener(socket.try_clone().unwrap()).is_err());
+        assert!(udp_socket(socket).is_ok());
+
+        // Connected, not listening
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = OwnedFd::from(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        assert!(tcp_listener(stream).is_err());
+    }
+}
@@ -0,0 +1,816 @@
+use crate::check::{Scenario, Step, Transport};
+use crate::{scaffolding::Context, server};
+use log::{as_debug, as_display};
+use server::{ConnectionHandler, PeerAddress, Server as _, ShutdownSignal, Stream, StreamServer};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+const SECONDS_PER_DAY: u32 = 86400;
+
+// --- Codec ---
+// Every message starts with a u8 type, followed by fields in a fixed order.
+// Integers are big-endian; strings are a u8 length followed by that many ASCII bytes.
+
+trait ReadFields: Read {
+    fn read_u8_field(&mut self) -> io::Result<u8> {
+        let mut buffer = [0u8; 1];
+        self.read_exact(&mut buffer)?;
+        Ok(buffer[0])
+    }
+
+    fn read_u16_field(&mut self) -> io::Result<u16> {
+        let mut buffer = [0u8; 2];
+        self.read_exact(&mut buffer)?;
+        Ok(u16::from_be_bytes(buffer))
+    }
+
+    fn read_u32_field(&mut self) -> io::Result<u32> {
+        let mut buffer = [0u8; 4];
+        self.read_exact(&mut buffer)?;
+        Ok(u32::from_be_bytes(buffer))
+    }
+
+    fn read_str_field(&mut self) -> io::Result<String> {
+        let mut buffer = vec![0u8; self.read_u8_field()? as usize];
+        self.read_exact(&mut buffer)?;
+        // The spec promises ASCII, so lossy conversion never actually loses anything legitimate
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+impl<R: Read + ?Sized> ReadFields for R {}
+
+trait WriteFields {
+    fn write_u8_field(&mut self, value: u8);
+    fn write_u16_field(&mut self, value: u16);
+    fn write_u32_field(&mut self, value: u32);
+    fn write_str_field(&mut self, value: &str);
+}
+
+impl WriteFields for Vec<u8> {
+    fn write_u8_field(&mut self, value: u8) {
+        self.push(value);
+    }
+
+    fn write_u16_field(&mut self, value: u16) {
+        self.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn write_u32_field(&mut self, value: u32) {
+        self.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn write_str_field(&mut self, value: &str) {
+        // Strings longer than 255 bytes can't be represented, so truncate them
+        let bytes = &value.as_bytes()[..value.len().min(u8::MAX as usize)];
+        self.write_u8_field(bytes.len() as u8);
+        self.extend_from_slice(bytes);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ClientMessage {
+    Plate { plate: String, timestamp: u32 },
+    WantHeartbeat { interval: u32 },
+    IAmCamera { road: u16, mile: u16, limit: u16 },
+    IAmDispatcher { roads: Vec<u16> },
+}
+
+#[derive(Debug)]
+enum DecodeError {
+    Io(io::Error),
+    UnknownMessageType(u8),
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(value: io::Error) -> Self {
+        DecodeError::Io(value)
+    }
+}
+
+impl ClientMessage {
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self, DecodeError> {
+        Ok(match reader.read_u8_field()? {
+            0x20 => ClientMessage::Plate {
+                plate: reader.read_str_field()?,
+                timestamp: reader.read_u32_field()?,
+            },
+            0x40 => ClientMessage::WantHeartbeat {
+                interval: reader.read_u32_field()?,
+            },
+            0x80 => ClientMessage::IAmCamera {
+                road: reader.read_u16_field()?,
+                mile: reader.read_u16_field()?,
+                limit: reader.read_u16_field()?,
+            },
+            0x81 => {
+                let count = reader.read_u8_field()?;
+                let mut roads = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    roads.push(reader.read_u16_field()?);
+                }
+                ClientMessage::IAmDispatcher { roads }
+            }
+            message_type => return Err(DecodeError::UnknownMessageType(message_type)),
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Ticket {
+    plate: String,
+    road: u16,
+    mile1: u16,
+    timestamp1: u32,
+    mile2: u16,
+    timestamp2: u32,
+    /// 100x miles per hour
+    speed: u16,
+}
+
+#[derive(Debug, PartialEq)]
+enum ServerMessage {
+    Error { msg: String },
+    Ticket(Ticket),
+    Heartbeat,
+}
+
+impl ServerMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self {
+            ServerMessage::Error { msg } => {
+                bytes.write_u8_field(0x10);
+                bytes.write_str_field(msg);
+            }
+            ServerMessage::Ticket(ticket) => {
+                bytes.write_u8_field(0x21);
+                bytes.write_str_field(&ticket.plate);
+                bytes.write_u16_field(ticket.road);
+                bytes.write_u16_field(ticket.mile1);
+                bytes.write_u32_field(ticket.timestamp1);
+                bytes.write_u16_field(ticket.mile2);
+                bytes.write_u32_field(ticket.timestamp2);
+                bytes.write_u16_field(ticket.speed);
+            }
+            ServerMessage::Heartbeat => bytes.write_u8_field(0x41),
+        }
+        bytes
+    }
+
+    fn error(msg: &str) -> Self {
+        ServerMessage::Error {
+            msg: String::from(msg),
+        }
+    }
+}
+
+// --- Tickets and dispatch ---
+
+#[derive(Clone, Copy)]
+struct Camera {
+    road: u16,
+    mile: u16,
+    /// Miles per hour
+    limit: u16,
+}
+
+struct DispatchCentre {
+    /// (plate, road) -> timestamp -> mile
+    observations: HashMap<(String, u16), BTreeMap<u32, u16>>,
+    /// plate -> days on which that plate has been ticketed
+    ticketed_days: HashMap<String, HashSet<u32>>,
+    /// road -> tickets waiting for a dispatcher for that road to connect
+    pending_tickets: HashMap<u16, VecDeque<Ticket>>,
+    /// road -> (dispatcher id, dispatcher's outbound message queue)
+    dispatchers: HashMap<u16, Vec<(usize, Sender<ServerMessage>)>>,
+}
+
+impl DispatchCentre {
+    fn new() -> Self {
+        Self {
+            observations: HashMap::new(),
+            ticketed_days: HashMap::new(),
+            pending_tickets: HashMap::new(),
+            dispatchers: HashMap::new(),
+        }
+    }
+
+    fn observe(&mut self, camera: Camera, plate: &str, timestamp: u32) {
+        let sightings = self
+            .observations
+            .entry((String::from(plate), camera.road))
+            .or_default();
+        sightings.insert(timestamp, camera.mile);
+
+        // Observations can arrive in any order, so compare against the nearest
+        // sightings either side of this one, in time.
+        let mut candidates = Vec::with_capacity(2);
+        if let Some((&earlier_timestamp, &earlier_mile)) = sightings.range(..timestamp).next_back()
+        {
+            candidates.push((earlier_mile, earlier_timestamp, camera.mile, timestamp));
+        }
+        if let Some((&later_timestamp, &later_mile)) = sightings
+            .range((Bound::Excluded(timestamp), Bound::Unbounded))
+            .next()
+        {
+            candidates.push((camera.mile, timestamp, later_mile, later_timestamp));
+        }
+
+        for (mile1, timestamp1, mile2, timestamp2) in candidates {
+            let distance = f64::from(mile1.abs_diff(mile2));
+            let hours = f64::from(timestamp2 - timestamp1) / 3600.0;
+            let speed = distance / hours;
+            if speed >= f64::from(camera.limit) + 0.5 {
+                self.issue(Ticket {
+                    plate: String::from(plate),
+                    road: camera.road,
+                    mile1,
+                    timestamp1,
+                    mile2,
+                    timestamp2,
+                    speed: (speed * 100.0).round().min(f64::from(u16::MAX)) as u16,
+                });
+            }
+        }
+    }
+
+    /// Issue a ticket, unless the plate has already been ticketed on any of the days it covers.
+    fn issue(&mut self, ticket: Ticket) {
+        let days = (ticket.timestamp1 / SECONDS_PER_DAY)..=(ticket.timestamp2 / SECONDS_PER_DAY);
+        let ticketed_days = self.ticketed_days.entry(ticket.plate.clone()).or_default();
+        if days.clone().any(|day| ticketed_days.contains(&day)) {
+            log::debug!(ticket = as_debug!(ticket); "Plate already ticketed on this day");
+            return;
+        }
+        ticketed_days.extend(days);
+        self.dispatch(ticket);
+    }
+
+    fn dispatch(&mut self, ticket: Ticket) {
+        if let Some(dispatchers) = self.dispatchers.get_mut(&ticket.road) {
+            let mut message = ServerMessage::Ticket(ticket);
+            while let Some((_, dispatcher)) = dispatchers.first() {
+                match dispatcher.send(message) {
+                    Ok(()) => return,
+                    Err(e) => {
+                        // That dispatcher has gone away, try the next one
+                        message = e.0;
+                        dispatchers.remove(0);
+                    }
+                }
+            }
+            if let ServerMessage::Ticket(ticket) = message {
+                self.queue(ticket);
+            }
+        } else {
+            self.queue(ticket);
+        }
+    }
+
+    fn queue(&mut self, ticket: Ticket) {
+        log::debug!(ticket = as_debug!(ticket); "No dispatcher available, queueing ticket");
+        self.pending_tickets
+            .entry(ticket.road)
+            .or_default()
+            .push_back(ticket);
+    }
+
+    fn add_dispatcher(&mut self, id: usize, roads: &[u16], sink: Sender<ServerMessage>) {
+        for road in roads {
+            self.dispatchers
+                .entry(*road)
+                .or_default()
+                .push((id, sink.clone()));
+            if let Some(pending) = self.pending_tickets.remove(road) {
+                for ticket in pending {
+                    self.dispatch(ticket);
+                }
+            }
+        }
+    }
+
+    fn remove_dispatcher(&mut self, id: usize) {
+        for dispatchers in self.dispatchers.values_mut() {
+            dispatchers.retain(|(dispatcher_id, _)| *dispatcher_id != id);
+        }
+        self.dispatchers
+            .retain(|_, dispatchers| !dispatchers.is_empty());
+    }
+}
+
+/// The dispatch centre is shared by every connection to one server.
+struct SharedDispatchCentre {
+    centre: Mutex<DispatchCentre>,
+    next_dispatcher_id: AtomicUsize,
+}
+
+impl SharedDispatchCentre {
+    fn new() -> Self {
+        Self {
+            centre: Mutex::new(DispatchCentre::new()),
+            next_dispatcher_id: AtomicUsize::new(0),
+        }
+    }
+
+    fn centre(&self) -> MutexGuard<'_, DispatchCentre> {
+        self.centre
+            .lock()
+            .expect("Dispatch centre should not be poisoned")
+    }
+}
+
+// --- Connections ---
+
+enum Role {
+    Unidentified,
+    Camera(Camera),
+    Dispatcher(usize),
+}
+
+pub(crate) fn serve(ctx: &Context) -> Result<ShutdownSignal, Box<dyn Error>> {
+    StreamServer::new().serve(ctx, dispatching())
+}
+
+fn dispatching() -> impl ConnectionHandler<Stream> {
+    let dispatch_centre = Arc::new(SharedDispatchCentre::new());
+    move |stream: &mut Stream, remote_address: &PeerAddress| {
+        handle(stream, remote_address, &dispatch_centre)
+    }
+}
+
+/// Write each message to the client until every sender has gone, returning the tickets it didn't
+/// get so another dispatcher can have them. Once `disconnected` is set, tickets aren't even tried.
+fn write_messages(
+    mut writer: Stream,
+    messages: Receiver<ServerMessage>,
+    disconnected: &AtomicBool,
+    remote_address: &PeerAddress,
+) -> Vec<Ticket> {
+    let mut undelivered = Vec::new();
+    // Keep receiving after we stop writing, so no ticket is sent here without being returned
+    let mut closed = false;
+    for message in messages {
+        let message = match message {
+            ServerMessage::Ticket(ticket) if closed || disconnected.load(Ordering::SeqCst) => {
+                undelivered.push(ticket);
+                continue;
+            }
+            _ if closed => continue,
+            message => message,
+        };
+        let is_error = matches!(message, ServerMessage::Error { .. });
+        if let Err(e) = writer.write_all(&message.to_bytes()) {
+            log::debug!(
+                remote_address = as_display!(remote_address),
+                error = as_display!(e);
+                "Error writing message to client"
+            );
+            if let ServerMessage::Ticket(ticket) = message {
+                undelivered.push(ticket);
+            }
+            closed = true;
+        } else if is_error {
+            // Errors always end the connection
+            let _ = writer.shutdown(Shutdown::Both);
+            closed = true;
+        }
+    }
+    undelivered
+}
+
+fn handle(
+    stream: &mut Stream,
+    remote_address: &PeerAddress,
+    dispatch_centre: &Arc<SharedDispatchCentre>,
+) -> Result<(), Box<dyn Error>> {
+    // Tickets, heartbeats and errors can all be sent at any time, so a single
+    // writer thread owns the outbound side of the connection. It stops once every sender has
+    // been dropped: ours when we return, the dispatch centre's when we're removed from it, and
+    // the heartbeat thread's once it sees `_stop_heartbeat` dropped.
+    let (tx, rx) = channel::<ServerMessage>();
+    let writer = stream.try_clone()?;
+    let disconnected = Arc::new(AtomicBool::new(false));
+    let disconnected_for_writer = disconnected.clone();
+    let remote_address_for_writer = remote_address.clone();
+    let dispatch_centre_for_writer = dispatch_centre.clone();
+    thread::spawn(move || {
+        let undelivered = write_messages(
+            writer,
+            rx,
+            &disconnected_for_writer,
+            &remote_address_for_writer,
+        );
+        if !undelivered.is_empty() {
+            let mut dispatch_centre = dispatch_centre_for_writer.centre();
+            for ticket in undelivered {
+                log::debug!(ticket = as_debug!(ticket); "Dispatcher went away, redispatching ticket");
+                dispatch_centre.dispatch(ticket);
+            }
+        }
+    });
+
+    let mut role = Role::Unidentified;
+    let mut heartbeat_requested = false;
+    let mut _stop_heartbeat = None;
+    let result = loop {
+        let message = match ClientMessage::read_from(stream) {
+            Ok(message) => message,
+            Err(DecodeError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => break Ok(()),
+            Err(DecodeError::Io(e)) => break Err(e.into()),
+            Err(DecodeError::UnknownMessageType(message_type)) => {
+                log::debug!(
+                    remote_address = as_display!(remote_address),
+                    message_type = as_display!(message_type);
+                    "Unknown message type"
+                );
+                break tx
+                    .send(ServerMessage::error("illegal msg"))
+                    .map_err(Into::into);
+            }
+        };
+        log::debug!(remote_address = as_display!(remote_address), message = as_debug!(message); "Got message");
+
+        match (message, &role) {
+            (ClientMessage::WantHeartbeat { .. }, _) if heartbeat_requested => {
+                break tx
+                    .send(ServerMessage::error("heartbeat already requested"))
+                    .map_err(Into::into);
+            }
+            (ClientMessage::WantHeartbeat { interval }, _) => {
+                heartbeat_requested = true;
+                if interval > 0 {
+                    let heartbeat_tx = tx.clone();
+                    let interval = Duration::from_millis(u64::from(interval) * 100);
+                    let (stop_heartbeat, heartbeat_stopped) = channel::<()>();
+                    _stop_heartbeat = Some(stop_heartbeat);
+                    thread::spawn(move || {
+                        while let Err(RecvTimeoutError::Timeout) =
+                            heartbeat_stopped.recv_timeout(interval)
+                        {
+                            if heartbeat_tx.send(ServerMessage::Heartbeat).is_err() {
+                                break;
+                            }
+                        }
+                    });
+                }
+            }
+            (ClientMessage::IAmCamera { road, mile, limit }, Role::Unidentified) => {
+                role = Role::Camera(Camera { road, mile, limit });
+            }
+            (ClientMessage::IAmDispatcher { roads }, Role::Unidentified) => {
+                let id = dispatch_centre
+                    .next_dispatcher_id
+                    .fetch_add(1, Ordering::SeqCst);
+                dispatch_centre
+                    .centre()
+                    .add_dispatcher(id, &roads, tx.clone());
+                role = Role::Dispatcher(id);
+            }
+            (ClientMessage::IAmCamera { .. } | ClientMessage::IAmDispatcher { .. }, _) => {
+                break tx
+                    .send(ServerMessage::error("already identified"))
+                    .map_err(Into::into);
+            }
+            (ClientMessage::Plate { plate, timestamp }, Role::Camera(camera)) => {
+                dispatch_centre.centre().observe(*camera, &plate, timestamp);
+            }
+            (ClientMessage::Plate { .. }, _) => {
+                break tx
+                    .send(ServerMessage::error("not a camera"))
+                    .map_err(Into::into);
+            }
+        }
+    };
+
+    // Anything still on its way to us is for a dispatcher which has gone
+    disconnected.store(true, Ordering::SeqCst);
+    if let Role::Dispatcher(id) = role {
+        dispatch_centre.centre().remove_dispatcher(id);
+    }
+    result
+}
+
+pub(crate) fn help(ctx: &Context) -> Result<(), Box<dyn Error>> {
+    println!("Usage: {} speed_daemon", ctx.program_name);
+    Ok(())
+}
+
+pub(crate) fn scenarios() -> Vec<Scenario> {
+    // Tickets are only issued once per plate per day, so use a plate and road that
+    // the server can't have seen before
+    let run_id = crate::check::run_id();
+    let plate = format!("CHK{}", run_id);
+    let road = (run_id % u16::MAX as u32) as u16;
+
+    let camera = |mile: u16| {
+        let mut bytes = Vec::new();
+        bytes.write_u8_field(0x80);
+        bytes.write_u16_field(road);
+        bytes.write_u16_field(mile);
+        bytes.write_u16_field(60);
+        bytes
+    };
+    let observation = |timestamp: u32| {
+        let mut bytes = Vec::new();
+        bytes.write_u8_field(0x20);
+        bytes.write_str_field(&plate);
+        bytes.write_u32_field(timestamp);
+        bytes
+    };
+    let mut dispatcher = Vec::new();
+    dispatcher.write_u8_field(0x81);
+    dispatcher.write_u8_field(1);
+    dispatcher.write_u16_field(road);
+    let ticket = ServerMessage::Ticket(Ticket {
+        plate: plate.clone(),
+        road,
+        mile1: 8,
+        timestamp1: 0,
+        mile2: 9,
+        timestamp2: 45,
+        speed: 8000,
+    });
+
+    vec![
+        Scenario {
+            name: "rejects unknown message types",
+            transport: Transport::Tcp,
+            steps: vec![Step::send(0, [0xff]), Step::expect(0, [0x10])],
+        },
+        Scenario {
+            name: "sends heartbeats",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, [0x40, 0x00, 0x00, 0x00, 0x01]),
+                Step::expect(0, [0x41]),
+                Step::expect(0, [0x41]),
+            ],
+        },
+        Scenario {
+            name: "tickets a speeding car",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::send(0, camera(8)),
+                Step::send(0, observation(0)),
+                Step::send(1, camera(9)),
+                Step::send(1, observation(45)),
+                Step::send(2, dispatcher),
+                Step::expect(2, ticket.to_bytes()),
+            ],
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Receiver;
+
+    fn decode(bytes: &[u8]) -> Result<ClientMessage, DecodeError> {
+        ClientMessage::read_from(&mut &bytes[..])
+    }
+
+    #[test]
+    fn decodes_client_messages() {
+        assert_eq!(
+            decode(&[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x03, 0xe8]).unwrap(),
+            ClientMessage::Plate {
+                plate: String::from("UN1X"),
+                timestamp: 1000
+            }
+        );
+        assert_eq!(
+            decode(&[0x40, 0x00, 0x00, 0x04, 0xdb]).unwrap(),
+            ClientMessage::WantHeartbeat { interval: 1243 }
+        );
+        assert_eq!(
+            decode(&[0x80, 0x00, 0x42, 0x00, 0x64, 0x00, 0x3c]).unwrap(),
+            ClientMessage::IAmCamera {
+                road: 66,
+                mile: 100,
+                limit: 60
+            }
+        );
+        assert_eq!(
+            decode(&[0x81, 0x03, 0x00, 0x42, 0x01, 0x70, 0x13, 0x88]).unwrap(),
+            ClientMessage::IAmDispatcher {
+                roads: vec![66, 368, 5000]
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_and_truncated_messages() {
+        assert!(matches!(
+            decode(&[0x41]),
+            Err(DecodeError::UnknownMessageType(0x41))
+        ));
+        assert!(matches!(
+            decode(&[0x20, 0x04, 0x55, 0x4e]),
+            Err(DecodeError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    fn encodes_server_messages() {
+        assert_eq!(
+            ServerMessage::error("bad").to_bytes(),
+            vec![0x10, 0x03, 0x62, 0x61, 0x64]
+        );
+        assert_eq!(ServerMessage::Heartbeat.to_bytes(), vec![0x41]);
+        assert_eq!(
+            ServerMessage::Ticket(Ticket {
+                plate: String::from("UN1X"),
+                road: 66,
+                mile1: 100,
+                timestamp1: 123456,
+                mile2: 110,
+                timestamp2: 123816,
+                speed: 10000,
+            })
+            .to_bytes(),
+            vec![
+                0x21, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x42, 0x00, 0x64, 0x00, 0x01, 0xe2, 0x40,
+                0x00, 0x6e, 0x00, 0x01, 0xe3, 0xa8, 0x27, 0x10,
+            ]
+        );
+    }
+
+    fn centre_with_dispatcher(road: u16) -> (DispatchCentre, Receiver<ServerMessage>) {
+        let mut centre = DispatchCentre::new();
+        let (tx, rx) = channel();
+        centre.add_dispatcher(0, &[road], tx);
+        (centre, rx)
+    }
+
+    const CAMERA_1: Camera = Camera {
+        road: 123,
+        mile: 8,
+        limit: 60,
+    };
+    const CAMERA_2: Camera = Camera {
+        road: 123,
+        mile: 9,
+        limit: 60,
+    };
+
+    #[test]
+    fn tickets_speeding_cars_in_either_observation_order() {
+        let (mut centre, rx) = centre_with_dispatcher(123);
+        // The later observation arrives first
+        centre.observe(CAMERA_2, "UN1X", 45);
+        centre.observe(CAMERA_1, "UN1X", 0);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            ServerMessage::Ticket(Ticket {
+                plate: String::from("UN1X"),
+                road: 123,
+                mile1: 8,
+                timestamp1: 0,
+                mile2: 9,
+                timestamp2: 45,
+                speed: 8000,
+            })
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn does_not_ticket_cars_within_half_a_mile_per_hour_of_the_limit() {
+        let (mut centre, rx) = centre_with_dispatcher(123);
+        // 1 mile in 60 seconds is exactly the limit
+        centre.observe(CAMERA_1, "SLOW", 0);
+        centre.observe(CAMERA_2, "SLOW", 60);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn tickets_each_plate_at_most_once_per_day() {
+        let (mut centre, rx) = centre_with_dispatcher(123);
+        centre.observe(CAMERA_1, "UN1X", 0);
+        centre.observe(CAMERA_2, "UN1X", 45);
+        centre.observe(CAMERA_1, "UN1X", 90);
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+
+        // The next day is fine
+        centre.observe(CAMERA_1, "UN1X", SECONDS_PER_DAY + 10);
+        centre.observe(CAMERA_2, "UN1X", SECONDS_PER_DAY + 55);
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn queues_tickets_until_a_dispatcher_connects() {
+        let mut centre = DispatchCentre::new();
+        centre.observe(CAMERA_1, "UN1X", 0);
+        centre.observe(CAMERA_2, "UN1X", 45);
+
+        let (tx, rx) = channel();
+        centre.add_dispatcher(0, &[124], tx);
+        assert!(rx.try_recv().is_err());
+
+        let (tx, rx) = channel();
+        centre.add_dispatcher(1, &[123], tx);
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::Ticket(_))));
+    }
+
+    #[test]
+    fn requeues_tickets_for_departed_dispatchers() {
+        let (mut centre, rx) = centre_with_dispatcher(123);
+        drop(rx);
+        centre.observe(CAMERA_1, "UN1X", 0);
+        centre.observe(CAMERA_2, "UN1X", 45);
+
+        let (tx, rx) = channel();
+        centre.add_dispatcher(1, &[123], tx);
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::Ticket(_))));
+    }
+
+    #[test]
+    fn hands_back_tickets_for_disconnected_dispatchers() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, remote_address) = listener.accept().unwrap();
+        let ticket = Ticket {
+            plate: String::from("UN1X"),
+            road: 123,
+            mile1: 8,
+            timestamp1: 0,
+            mile2: 9,
+            timestamp2: 45,
+            speed: 8000,
+        };
+
+        let (tx, rx) = channel();
+        let disconnected = AtomicBool::new(false);
+        tx.send(ServerMessage::Heartbeat).unwrap();
+        // Sent while the dispatcher was leaving, so it can't have been delivered
+        tx.send(ServerMessage::Ticket(ticket.clone())).unwrap();
+        disconnected.store(true, Ordering::SeqCst);
+        drop(tx);
+        let undelivered = write_messages(
+            server.into(),
+            rx,
+            &disconnected,
+            &PeerAddress::from(remote_address),
+        );
+        assert_eq!(undelivered, vec![ticket]);
+        let mut heartbeat = [0u8; 1];
+        client.read_exact(&mut heartbeat).unwrap();
+        assert_eq!(heartbeat, [0x41]);
+    }
+
+    #[test]
+    fn servers_have_separate_dispatch_centres() {
+        let serve = || {
+            let (ctx, address) = crate::scaffolding::test_context("speed_daemon");
+            StreamServer::new()
+                .serve(&ctx, dispatching())
+                .expect("Server should start");
+            address
+        };
+        let (first, second) = (serve(), serve());
+        let connect = |address, message: &[u8]| {
+            let mut client = std::net::TcpStream::connect(address).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            client.write_all(message).unwrap();
+            client
+        };
+        let dispatcher = [0x81, 0x01, 0x00, 0x7b];
+        let mut elsewhere = connect(second, &dispatcher);
+        let mut camera = connect(first, &[0x80, 0x00, 0x7b, 0x00, 0x08, 0x00, 0x3c]);
+        camera
+            .write_all(&[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x00, 0x00])
+            .unwrap();
+        let mut camera = connect(first, &[0x80, 0x00, 0x7b, 0x00, 0x09, 0x00, 0x3c]);
+        camera
+            .write_all(&[0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x00, 0x2d])
+            .unwrap();
+
+        let mut message_type = [0u8; 1];
+        connect(first, &dispatcher)
+            .read_exact(&mut message_type)
+            .unwrap();
+        assert_eq!(message_type, [0x21]);
+        elsewhere
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let error = elsewhere.read(&mut message_type).unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut
+        ));
+    }
+}
@@ -0,0 +1,257 @@
+use crate::check::{Scenario, Step, Transport};
+use crate::{scaffolding::Context, server};
+use log::as_display;
+use server::{ReplySink, ShutdownSignal, UdpServer};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::{Mutex, PoisonError};
+
+// All requests and responses must be shorter than 1000 bytes. The server drops
+// anything larger than this, and we ignore anything exactly this size.
+const MAX_DATAGRAM_SIZE: usize = 1000;
+const VERSION_KEY: &[u8] = b"version";
+const VERSION_VALUE: &[u8] = b"mjec's Key-Value Store 1.0";
+
+#[derive(Debug, PartialEq)]
+enum Request<'a> {
+    Insert { key: &'a [u8], value: &'a [u8] },
+    Retrieve { key: &'a [u8] },
+}
+
+impl<'a> Request<'a> {
+    fn parse(datagram: &'a [u8]) -> Self {
+        // The key is everything up to the *first* equals sign; any further
+        // equals signs are part of the value.
+        match datagram.iter().position(|b| *b == b'=') {
+            Some(index) => Request::Insert {
+                key: &datagram[..index],
+                value: &datagram[index + 1..],
+            },
+            None => Request::Retrieve { key: datagram },
+        }
+    }
+}
+
+struct Database {
+    data: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Database {
+    fn new() -> Self {
+        Self {
+            data: HashMap::new(),
+        }
+    }
+
+    /// Apply a request to the database, returning the response to send (if any).
+    fn apply(&mut self, request: Request) -> Option<Vec<u8>> {
+        match request {
+            // The version is read-only; attempts to set it are silently ignored
+            Request::Insert { key, .. } if key == VERSION_KEY => None,
+            Request::Insert { key, value } => {
+                self.data.insert(key.to_vec(), value.to_vec());
+                None
+            }
+            Request::Retrieve { key } => {
+                let value = if key == VERSION_KEY {
+                    VERSION_VALUE
+                } else {
+                    // We are allowed to either not respond or respond with an
+                    // empty value for missing keys; an empty value is friendlier.
+                    self.data.get(key).map(Vec::as_slice).unwrap_or_default()
+                };
+                let mut response = Vec::with_capacity(key.len() + 1 + value.len());
+                response.extend_from_slice(key);
+                response.push(b'=');
+                response.extend_from_slice(value);
+                Some(response)
+            }
+        }
+    }
+
+    fn handle_datagram(&mut self, datagram: &[u8]) -> Option<Vec<u8>> {
+        if datagram.len() >= MAX_DATAGRAM_SIZE {
+            return None;
+        }
+        self.apply(Request::parse(datagram))
+            .filter(|response| response.len() < MAX_DATAGRAM_SIZE)
+    }
+}
+
+pub(crate) fn serve(ctx: &Context) -> Result<ShutdownSignal, Box<dyn Error>> {
+    let database = Mutex::new(Database::new());
+    UdpServer::new()
+        .with_max_datagram_size(MAX_DATAGRAM_SIZE)
+        .serve(
+            ctx,
+            move |datagram: &[u8], peer: &SocketAddr, reply: &ReplySink| {
+                handle(&database, datagram, peer, reply)
+            },
+        )
+}
+
+fn handle(
+    database: &Mutex<Database>,
+    datagram: &[u8],
+    peer: &SocketAddr,
+    reply: &ReplySink,
+) -> Result<(), Box<dyn Error>> {
+    // Each change to the database is a single insert, so it's safe to carry on if one panicked
+    let response = database
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .handle_datagram(datagram);
+
+    if let Some(response) = response {
+        log::debug!(
+            peer_address = as_display!(peer),
+            response = as_display!(String::from_utf8_lossy(&response));
+            "Sending response"
+        );
+        reply.send(&response)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn help(ctx: &Context) -> Result<(), Box<dyn Error>> {
+    println!("Usage: {} unusual_database_program", ctx.program_name);
+    Ok(())
+}
+
+pub(crate) fn scenarios() -> Vec<Scenario> {
+    // The database outlives the checker, so use keys it can't have seen before
+    let key = format!("check{}", crate::check::run_id());
+    vec![
+        Scenario {
+            name: "retrieves inserted values",
+            transport: Transport::Udp,
+            steps: vec![
+                Step::send(0, format!("{}=first", key)),
+                Step::send(0, &key),
+                Step::expect(0, format!("{}=first", key)),
+                Step::send(1, format!("{}=second=with=equals", key)),
+                Step::send(0, &key),
+                Step::expect(0, format!("{}=second=with=equals", key)),
+            ],
+        },
+        Scenario {
+            name: "retrieves missing and empty keys",
+            transport: Transport::Udp,
+            steps: vec![
+                Step::send(0, format!("{}missing", key)),
+                Step::expect(0, format!("{}missing=", key)),
+                Step::send(0, format!("={}", key)),
+                Step::send(0, ""),
+                Step::expect(0, format!("={}", key)),
+            ],
+        },
+        Scenario {
+            name: "reports a read-only version",
+            transport: Transport::Udp,
+            steps: vec![
+                Step::send(0, "version=hacked"),
+                Step::send(0, "version"),
+                Step::expect_matching(0, "version=", ""),
+            ],
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_then_retrieve() {
+        let mut db = Database::new();
+        assert_eq!(db.handle_datagram(b"foo=bar"), None);
+        assert_eq!(db.handle_datagram(b"foo"), Some(b"foo=bar".to_vec()));
+    }
+
+    #[test]
+    fn later_insert_overwrites_earlier() {
+        let mut db = Database::new();
+        db.handle_datagram(b"foo=bar");
+        db.handle_datagram(b"foo=baz");
+        assert_eq!(db.handle_datagram(b"foo"), Some(b"foo=baz".to_vec()));
+    }
+
+    #[test]
+    fn equals_signs_after_the_first_belong_to_the_value() {
+        assert_eq!(
+            Request::parse(b"foo=bar=baz"),
+            Request::Insert {
+                key: b"foo",
+                value: b"bar=baz"
+            }
+        );
+        assert_eq!(
+            Request::parse(b"foo==="),
+            Request::Insert {
+                key: b"foo",
+                value: b"=="
+            }
+        );
+
+        let mut db = Database::new();
+        db.handle_datagram(b"foo=bar=baz");
+        assert_eq!(db.handle_datagram(b"foo"), Some(b"foo=bar=baz".to_vec()));
+    }
+
+    #[test]
+    fn empty_keys_and_values_are_allowed() {
+        let mut db = Database::new();
+        db.handle_datagram(b"=value");
+        assert_eq!(db.handle_datagram(b""), Some(b"=value".to_vec()));
+
+        db.handle_datagram(b"key=");
+        assert_eq!(db.handle_datagram(b"key"), Some(b"key=".to_vec()));
+
+        db.handle_datagram(b"=");
+        assert_eq!(db.handle_datagram(b""), Some(b"=".to_vec()));
+    }
+
+    #[test]
+    fn missing_keys_retrieve_an_empty_value() {
+        let mut db = Database::new();
+        assert_eq!(db.handle_datagram(b"nope"), Some(b"nope=".to_vec()));
+    }
+
+    #[test]
+    fn version_is_read_only() {
+        let mut db = Database::new();
+        let expected = [b"version=".as_slice(), VERSION_VALUE].concat();
+        assert_eq!(db.handle_datagram(b"version"), Some(expected.clone()));
+        assert_eq!(db.handle_datagram(b"version=hacked"), None);
+        assert_eq!(db.handle_datagram(b"version"), Some(expected));
+    }
+
+    #[test]
+    fn oversized_datagrams_are_ignored() {
+        let mut db = Database::new();
+
+        let mut just_fits = b"k=".to_vec();
+        just_fits.resize(MAX_DATAGRAM_SIZE - 1, b'v');
+        assert_eq!(db.handle_datagram(&just_fits), None);
+        assert_eq!(db.handle_datagram(b"k").map(|r| r.len()), Some(999));
+
+        let mut too_big = b"big=".to_vec();
+        too_big.resize(MAX_DATAGRAM_SIZE, b'v');
+        assert_eq!(db.handle_datagram(&too_big), None);
+        assert_eq!(db.handle_datagram(b"big"), Some(b"big=".to_vec()));
+
+        let mut way_too_big = b"huge=".to_vec();
+        way_too_big.resize(MAX_DATAGRAM_SIZE * 2, b'v');
+        assert_eq!(db.handle_datagram(&way_too_big), None);
+        assert_eq!(db.handle_datagram(b"huge"), Some(b"huge=".to_vec()));
+    }
+
+    #[test]
+    fn responses_of_1000_bytes_or_more_are_not_sent() {
+        let mut db = Database::new();
+        // A 999 byte key fits in a request, but "key=" does not fit in a response
+        let long_key = vec![b'k'; MAX_DATAGRAM_SIZE - 1];
+        assert_eq!(db.handle_datagram(&long_key), None);
+    }
+}
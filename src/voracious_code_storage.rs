@@ -0,0 +1,406 @@
+use crate::check::{Scenario, Step, Transport};
+use crate::{scaffolding::Context, server};
+use log::as_display;
+use server::{ConnectionHandler, PeerAddress, Server as _, ShutdownSignal, Stream, StreamServer};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// These must match the reference server byte for byte
+const READY: &[u8] = b"READY\n";
+const USAGE_HELP: &str = "OK usage: HELP|GET|PUT|LIST";
+const USAGE_GET: &str = "ERR usage: GET file [revision]";
+const USAGE_PUT: &str = "ERR usage: PUT file length newline data";
+const USAGE_LIST: &str = "ERR usage: LIST dir";
+const ERR_ILLEGAL_FILE_NAME: &str = "ERR illegal file name";
+const ERR_ILLEGAL_DIR_NAME: &str = "ERR illegal dir name";
+const ERR_NO_SUCH_FILE: &str = "ERR no such file";
+const ERR_NO_SUCH_REVISION: &str = "ERR no such revision";
+const ERR_TEXT_FILES_ONLY: &str = "ERR text files only";
+/// Far longer than any sensible command, including its newline. Only a name can make a line
+/// this long, so longer ones are answered as illegal names, and end the session.
+const MAX_LINE: u64 = 1024;
+
+struct Store {
+    /// file name -> every revision of that file, oldest first
+    files: BTreeMap<String, Vec<Vec<u8>>>,
+}
+
+impl Store {
+    fn new() -> Self {
+        Self {
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Store `data` as the newest revision of `name`, returning its revision number.
+    /// Putting the same content as the newest revision does not create a new one.
+    fn put(&mut self, name: &str, data: Vec<u8>) -> usize {
+        let revisions = self.files.entry(String::from(name)).or_default();
+        if revisions.last() != Some(&data) {
+            revisions.push(data);
+        }
+        revisions.len()
+    }
+
+    /// Get revision `revision` of `name` (counting from 1), or the newest if `revision` is `None`.
+    fn get(&self, name: &str, revision: Option<usize>) -> Result<&[u8], &'static str> {
+        let revisions = self.files.get(name).ok_or(ERR_NO_SUCH_FILE)?;
+        match revision {
+            None => revisions.last(),
+            Some(revision) => revision
+                .checked_sub(1)
+                .and_then(|index| revisions.get(index)),
+        }
+        .map(Vec::as_slice)
+        .ok_or(ERR_NO_SUCH_REVISION)
+    }
+
+    /// List the immediate children of `dir`, as (name, description) pairs sorted by name.
+    fn list(&self, dir: &str) -> Vec<(String, String)> {
+        let prefix = if dir.ends_with('/') {
+            String::from(dir)
+        } else {
+            format!("{}/", dir)
+        };
+        let mut entries = BTreeMap::new();
+        for (name, revisions) in self.files.range(prefix.clone()..) {
+            let Some(rest) = name.strip_prefix(&prefix) else {
+                break;
+            };
+            match rest.split_once('/') {
+                Some((subdirectory, _)) => {
+                    entries.insert(format!("{}/", subdirectory), String::from("DIR"));
+                }
+                None => {
+                    entries.insert(String::from(rest), format!("r{}", revisions.len()));
+                }
+            }
+        }
+        entries.into_iter().collect()
+    }
+}
+
+fn is_legal_path(path: &str) -> bool {
+    path.starts_with('/')
+        && !path.contains("//")
+        && path
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '.' | '-' | '_'))
+}
+
+fn is_legal_file_name(name: &str) -> bool {
+    is_legal_path(name) && !name.ends_with('/')
+}
+
+/// The files, shared by every connection to one server
+struct SharedStore {
+    store: RwLock<Store>,
+}
+
+impl SharedStore {
+    fn new() -> Self {
+        Self {
+            store: RwLock::new(Store::new()),
+        }
+    }
+
+    // Each change to the store is a single insert or push, so a handler panicking can't leave it
+    // half-done, and a poisoned lock is safe to carry on with
+    fn store(&self) -> RwLockReadGuard<'_, Store> {
+        self.store.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn store_mut(&self) -> RwLockWriteGuard<'_, Store> {
+        self.store.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl ConnectionHandler<Stream> for SharedStore {
+    fn handle(
+        &self,
+        stream: &mut Stream,
+        remote_address: &PeerAddress,
+        _shutdown_signal: &ShutdownSignal,
+    ) -> Result<(), Box<dyn Error>> {
+        handle(stream, remote_address, self)
+    }
+}
+
+fn is_text(data: &[u8]) -> bool {
+    data.iter()
+        .all(|b| b.is_ascii_graphic() || matches!(b, b' ' | b'\n' | b'\t'))
+}
+
+fn parse_revision(revision: &str) -> Option<usize> {
+    revision
+        .strip_prefix('r')
+        .unwrap_or(revision)
+        .parse::<usize>()
+        .ok()
+}
+
+pub(crate) fn serve(ctx: &Context) -> Result<ShutdownSignal, Box<dyn Error>> {
+    StreamServer::new().serve(ctx, SharedStore::new())
+}
+
+fn handle(
+    stream: &mut Stream,
+    remote_address: &PeerAddress,
+    files: &SharedStore,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = Vec::new();
+    loop {
+        stream.write_all(READY)?;
+        line.clear();
+        // Nothing past the limit is read, so a client can't make us buffer an endless line
+        let read = (&mut reader).take(MAX_LINE).read_until(b'\n', &mut line)?;
+        if read == 0 {
+            return Ok(());
+        }
+        // Anything that isn't UTF-8 makes for an illegal method or name, which we answer as such
+        let command = String::from_utf8_lossy(&line);
+        let arguments: Vec<&str> = command.split_whitespace().collect();
+        let method = arguments.first().copied().unwrap_or_default();
+        log::debug!(
+            remote_address = as_display!(remote_address),
+            command = as_display!(command.trim_end());
+            "Got command"
+        );
+        if read as u64 == MAX_LINE && !line.ends_with(b"\n") {
+            let response = match method.to_ascii_uppercase().as_str() {
+                "GET" | "PUT" => String::from(ERR_ILLEGAL_FILE_NAME),
+                "LIST" => String::from(ERR_ILLEGAL_DIR_NAME),
+                _ => format!("ERR illegal method: {}", method),
+            };
+            // We can't tell where the rest of the line ends, or what follows it
+            stream.write_all(format!("{}\n", response).as_bytes())?;
+            return Ok(());
+        }
+
+        let response: Vec<u8> = match method.to_ascii_uppercase().as_str() {
+            "HELP" => format!("{}\n", USAGE_HELP).into_bytes(),
+            "PUT" => match arguments[1..] {
+                [name, length] => 'put: {
+                    let Ok(length) = length.parse::<u64>() else {
+                        break 'put format!("{}\n", USAGE_PUT).into_bytes();
+                    };
+                    // The data always follows, even if we're going to reject it
+                    let mut data = Vec::new();
+                    (&mut reader).take(length).read_to_end(&mut data)?;
+                    if (data.len() as u64) < length {
+                        return Ok(());
+                    }
+                    if !is_legal_file_name(name) {
+                        format!("{}\n", ERR_ILLEGAL_FILE_NAME).into_bytes()
+                    } else if !is_text(&data) {
+                        format!("{}\n", ERR_TEXT_FILES_ONLY).into_bytes()
+                    } else {
+                        let revision = files.store_mut().put(name, data);
+                        format!("OK r{}\n", revision).into_bytes()
+                    }
+                }
+                _ => format!("{}\n", USAGE_PUT).into_bytes(),
+            },
+            "GET" => match arguments[1..] {
+                [name] | [name, _] if !is_legal_file_name(name) => {
+                    format!("{}\n", ERR_ILLEGAL_FILE_NAME).into_bytes()
+                }
+                [name] | [name, _] => {
+                    let revision = match arguments.get(2) {
+                        None => Ok(None),
+                        Some(revision) => parse_revision(revision)
+                            .map(Some)
+                            .ok_or(ERR_NO_SUCH_REVISION),
+                    };
+                    let store = files.store();
+                    match revision.and_then(|revision| store.get(name, revision)) {
+                        Ok(data) => {
+                            let mut response = format!("OK {}\n", data.len()).into_bytes();
+                            response.extend_from_slice(data);
+                            response
+                        }
+                        Err(e) => format!("{}\n", e).into_bytes(),
+                    }
+                }
+                _ => format!("{}\n", USAGE_GET).into_bytes(),
+            },
+            "LIST" => match arguments[1..] {
+                [dir] if !is_legal_path(dir) => format!("{}\n", ERR_ILLEGAL_DIR_NAME).into_bytes(),
+                [dir] => {
+                    let entries = files.store().list(dir);
+                    let mut response = format!("OK {}\n", entries.len());
+                    for (name, description) in entries {
+                        response.push_str(&format!("{} {}\n", name, description));
+                    }
+                    response.into_bytes()
+                }
+                _ => format!("{}\n", USAGE_LIST).into_bytes(),
+            },
+            _ => {
+                // The reference server hangs up on unknown methods
+                stream.write_all(format!("ERR illegal method: {}\n", method).as_bytes())?;
+                return Ok(());
+            }
+        };
+        stream.write_all(&response)?;
+    }
+}
+
+pub(crate) fn help(ctx: &Context) -> Result<(), Box<dyn Error>> {
+    println!("Usage: {} voracious_code_storage", ctx.program_name);
+    Ok(())
+}
+
+pub(crate) fn scenarios() -> Vec<Scenario> {
+    // Files outlive the checker's connections, so use a directory the server can't have seen before
+    let dir = format!("/check{}", crate::check::run_id());
+    vec![
+        Scenario {
+            name: "explains its usage",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::expect(0, READY),
+                Step::send(0, "help\n"),
+                Step::expect(0, format!("{}\n", USAGE_HELP)),
+                Step::expect(0, READY),
+            ],
+        },
+        Scenario {
+            name: "keeps every revision",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::expect(0, READY),
+                Step::send(0, format!("PUT {}/a 4\none\n", dir)),
+                Step::expect(0, "OK r1\n"),
+                Step::expect(0, READY),
+                Step::send(0, format!("PUT {}/a 4\ntwo\n", dir)),
+                Step::expect(0, "OK r2\n"),
+                Step::expect(0, READY),
+                Step::send(0, format!("GET {}/a r1\n", dir)),
+                Step::expect(0, "OK 4\none\n"),
+                Step::expect(0, READY),
+                Step::send(0, format!("GET {}/a\n", dir)),
+                Step::expect(0, "OK 4\ntwo\n"),
+                Step::expect(0, READY),
+                Step::send(0, format!("LIST {}\n", dir)),
+                Step::expect(0, "OK 1\na r2\n"),
+                Step::expect(0, READY),
+            ],
+        },
+        Scenario {
+            name: "rejects bad requests",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::expect(0, READY),
+                Step::send(0, format!("PUT {}/binary 1\n\0", dir)),
+                Step::expect(0, format!("{}\n", ERR_TEXT_FILES_ONLY)),
+                Step::expect(0, READY),
+                Step::send(0, format!("PUT {}/a four\n", dir)),
+                Step::expect(0, format!("{}\n", USAGE_PUT)),
+                Step::expect(0, READY),
+                Step::send(0, format!("GET {}/missing\n", dir)),
+                Step::expect(0, format!("{}\n", ERR_NO_SUCH_FILE)),
+                Step::expect(0, READY),
+                Step::send(0, "GET bad\n"),
+                Step::expect(0, format!("{}\n", ERR_ILLEGAL_FILE_NAME)),
+                Step::expect(0, READY),
+                Step::send(0, [format!("GET {}/", dir).as_bytes(), b"\xff\n"].concat()),
+                Step::expect(0, format!("{}\n", ERR_ILLEGAL_FILE_NAME)),
+                Step::expect(0, READY),
+                Step::send(0, "DELETE /a\n"),
+                Step::expect(0, "ERR illegal method: DELETE\n"),
+                Step::ExpectClosed(0),
+            ],
+        },
+        Scenario {
+            name: "hangs up on endless commands",
+            transport: Transport::Tcp,
+            steps: vec![
+                Step::expect(0, READY),
+                // Exactly as much as we read, so nothing is left unread when the server hangs up
+                Step::send(0, format!("LIST /{}", "a".repeat(MAX_LINE as usize - 6))),
+                Step::expect(0, format!("{}\n", ERR_ILLEGAL_DIR_NAME)),
+                Step::ExpectClosed(0),
+            ],
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_file_names() {
+        for name in ["/a", "/a/b.txt", "/A-Z_0.9", "/x/y/z"] {
+            assert!(is_legal_file_name(name), "{}", name);
+        }
+        for name in ["a", "/", "/a/", "//a", "/a//b", "/a b", "/a*", "/ä"] {
+            assert!(!is_legal_file_name(name), "{}", name);
+        }
+        assert!(is_legal_path("/"));
+        assert!(is_legal_path("/a/"));
+    }
+
+    #[test]
+    fn rejects_non_text() {
+        assert!(is_text(b"hello\n\tworld "));
+        assert!(!is_text(b"nul\0"));
+        assert!(!is_text(b"\x7f"));
+        assert!(!is_text("é".as_bytes()));
+    }
+
+    #[test]
+    fn keeps_every_revision() {
+        let mut store = Store::new();
+        assert_eq!(store.put("/a", b"one".to_vec()), 1);
+        assert_eq!(store.put("/a", b"two".to_vec()), 2);
+        // Same as the newest revision, so no new revision
+        assert_eq!(store.put("/a", b"two".to_vec()), 2);
+        assert_eq!(store.put("/a", b"one".to_vec()), 3);
+
+        assert_eq!(store.get("/a", None), Ok(b"one".as_slice()));
+        assert_eq!(store.get("/a", Some(2)), Ok(b"two".as_slice()));
+        assert_eq!(store.get("/a", Some(0)), Err(ERR_NO_SUCH_REVISION));
+        assert_eq!(store.get("/a", Some(4)), Err(ERR_NO_SUCH_REVISION));
+        assert_eq!(store.get("/b", None), Err(ERR_NO_SUCH_FILE));
+    }
+
+    #[test]
+    fn parses_revisions() {
+        assert_eq!(parse_revision("r3"), Some(3));
+        assert_eq!(parse_revision("3"), Some(3));
+        assert_eq!(parse_revision("rr3"), None);
+        assert_eq!(parse_revision("r-1"), None);
+    }
+
+    #[test]
+    fn lists_files_and_directories() {
+        let mut store = Store::new();
+        store.put("/a", b"".to_vec());
+        store.put("/a", b"x".to_vec());
+        store.put("/dir/b", b"".to_vec());
+        store.put("/dir/sub/c", b"".to_vec());
+        store.put("/dir/sub/d", b"".to_vec());
+        store.put("/directory", b"".to_vec());
+
+        let entry = |name: &str, description: &str| (String::from(name), String::from(description));
+        assert_eq!(
+            store.list("/"),
+            vec![
+                entry("a", "r2"),
+                entry("dir/", "DIR"),
+                entry("directory", "r1")
+            ]
+        );
+        assert_eq!(store.list("/dir"), store.list("/dir/"));
+        assert_eq!(
+            store.list("/dir/"),
+            vec![entry("b", "r1"), entry("sub/", "DIR")]
+        );
+        assert_eq!(store.list("/nothing"), vec![]);
+    }
+}
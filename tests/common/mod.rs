@@ -0,0 +1,70 @@
+//! Helpers for tests which run the binary.
+
+// Not every test uses every helper
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Whether `pid` has a handler for `signal`, going by the mask of caught signals in /proc.
+fn catches(pid: u32, signal: libc::c_int) -> bool {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("SigCgt:"))
+        .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+        .is_some_and(|mask| mask & (1 << (signal - 1)) != 0)
+}
+
+/// Send `signal` to `pid` once it's handling it. Servers are started before their handlers
+/// are set, so we can be served before it's safe to signal.
+pub fn send_signal(pid: u32, signal: libc::c_int) {
+    let deadline = Instant::now() + TIMEOUT;
+    while !catches(pid, signal) {
+        assert!(
+            Instant::now() < deadline,
+            "The server never started handling signal {}",
+            signal
+        );
+        thread::sleep(POLL_INTERVAL);
+    }
+    // SAFETY: kill doesn't touch memory
+    unsafe { libc::kill(pid as libc::pid_t, signal) };
+}
+
+/// Poll `check` until it gives us something, panicking if that takes too long.
+pub fn wait_for<T>(description: &str, mut check: impl FnMut() -> Option<T>) -> T {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some(result) = check() {
+            return result;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "Timed out waiting for {}",
+            description
+        );
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Join the chat at `address` as `name`, returning the connection and the room list we're sent.
+pub fn join(address: SocketAddr, name: &str) -> (BufReader<TcpStream>, String) {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut greeting = String::new();
+    reader.read_line(&mut greeting).unwrap();
+    stream.write_all(format!("{}\n", name).as_bytes()).unwrap();
+    let mut room_list = String::new();
+    reader.read_line(&mut room_list).unwrap();
+    (reader, room_list)
+}
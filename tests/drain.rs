@@ -0,0 +1,72 @@
+//! Drains a running server from the command line, checking it turns new connections away but
+//! leaves open ones be for as long as they last, and exits once they've finished.
+
+mod common;
+
+use std::{
+    io::{BufRead, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+#[test]
+fn drains_without_cutting_off_open_sessions() {
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_protohackers"))
+        .arg("budget_chat")
+        .env("BIND_ADDRESS", address.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // Leaving without joining, so this doesn't hold up the drain
+    common::wait_for("the server to start", || TcpStream::connect(address).ok());
+    let (mut alice, _) = common::join(address, "alice");
+    let (mut bob, room_list) = common::join(address, "bob");
+    assert_eq!(room_list, "* The room contains: alice\n");
+
+    let status = Command::new(env!("CARGO_BIN_EXE_protohackers"))
+        .arg("drain")
+        .arg(server.id().to_string())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    common::wait_for("the server to stop accepting", || {
+        TcpStream::connect(address).err()
+    });
+
+    // Long past the shutdown timeout, and the time a client gets to give its name, Alice and Bob
+    // can still talk
+    std::thread::sleep(Duration::from_secs(11));
+    let mut joined = String::new();
+    alice.read_line(&mut joined).unwrap();
+    assert_eq!(joined, "* bob joined\n");
+    bob.get_mut().write_all(b"still here\n").unwrap();
+    let mut message = String::new();
+    alice.read_line(&mut message).unwrap();
+    assert_eq!(message, "[bob] still here\n");
+    assert!(server.try_wait().unwrap().is_none());
+
+    drop(bob);
+    let mut left = String::new();
+    alice.read_line(&mut left).unwrap();
+    assert_eq!(left, "* bob left\n");
+    alice
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let error = alice.read(&mut [0u8; 1]).unwrap_err();
+    assert!(matches!(
+        error.kind(),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut
+    ));
+    drop(alice);
+    let status = common::wait_for("the server to exit", || server.try_wait().unwrap());
+    assert!(status.success());
+}
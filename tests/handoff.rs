@@ -0,0 +1,137 @@
+//! Restarts a running server with SIGUSR2, checking the new process takes over the port while
+//! the old one finishes with the connections it already has.
+
+mod common;
+
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read},
+    net::{TcpListener, UdpSocket},
+    process::{ChildStderr, Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
+use common::TIMEOUT;
+
+/// Send each line the server logs down a channel.
+fn collect_logs(stderr: ChildStderr) -> Receiver<String> {
+    let (sender, logs) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines() {
+            let Ok(line) = line else { break };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    logs
+}
+
+/// Wait for a log line containing `needle`, and return it.
+fn wait_for_log(logs: &Receiver<String>, needle: &str) -> String {
+    loop {
+        let line = logs
+            .recv_timeout(TIMEOUT)
+            .unwrap_or_else(|_| panic!("Never logged {:?}", needle));
+        if line.contains(needle) {
+            return line;
+        }
+    }
+}
+
+#[test]
+fn hands_over_to_a_new_process_without_dropping_connections() {
+    let address = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut old = Command::new(env!("CARGO_BIN_EXE_protohackers"))
+        .arg("budget_chat")
+        .env("BIND_ADDRESS", address.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The new process logs to the same place
+    let logs = collect_logs(old.stderr.take().unwrap());
+    wait_for_log(&logs, "Listening");
+
+    let (mut alice, room_list) = common::join(address, "alice");
+    assert_eq!(room_list, "* The room contains: \n");
+
+    common::send_signal(old.id(), libc::SIGUSR2);
+    let took_over = wait_for_log(&logs, "New process has taken over");
+    let new_pid: u32 = took_over
+        .split_once("new_pid=")
+        .and_then(|(_, pid)| pid.split_whitespace().next())
+        .and_then(|pid| pid.parse().ok())
+        .expect("Should log the new process's pid");
+    assert_ne!(new_pid, old.id());
+    // Until then, the old process could still pick up the next connection
+    wait_for_log(&logs, "no longer accepting connections");
+
+    // Alice is still in the old process's room, so Bob finds the new one empty
+    let (mut bob, room_list) = common::join(address, "bob");
+    assert_eq!(room_list, "* The room contains: \n");
+
+    // The old process is draining, which leaves Alice be until she leaves
+    alice
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let error = alice.read(&mut [0u8; 1]).unwrap_err();
+    assert!(matches!(
+        error.kind(),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut
+    ));
+    drop(alice);
+    let status = common::wait_for("the old process to exit", || old.try_wait().unwrap());
+    assert!(status.success());
+
+    let (_carol, room_list) = common::join(address, "carol");
+    assert_eq!(room_list, "* The room contains: bob\n");
+
+    common::send_signal(new_pid, libc::SIGINT);
+    let mut rest = String::new();
+    bob.read_to_string(&mut rest).unwrap();
+    assert!(rest.ends_with("* server shutting down\n"));
+}
+
+#[test]
+fn refuses_to_hand_over_datagram_servers() {
+    let address = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_protohackers"))
+        .arg("unusual_database_program")
+        .env("BIND_ADDRESS", address.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let logs = collect_logs(server.stderr.take().unwrap());
+    wait_for_log(&logs, "Listening");
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(TIMEOUT)).unwrap();
+    client.connect(address).unwrap();
+    client.send(b"answer=42").unwrap();
+
+    common::send_signal(server.id(), libc::SIGUSR2);
+    let refused = wait_for_log(&logs, "Unable to restart");
+    assert!(refused.contains("can't hand over without losing what it's been sent"));
+
+    // Still serving, and still remembering
+    client.send(b"answer").unwrap();
+    let mut buffer = [0u8; 1000];
+    let length = client.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..length], b"answer=42");
+
+    common::send_signal(server.id(), libc::SIGINT);
+    let status = common::wait_for("the server to exit", || server.try_wait().unwrap());
+    assert!(status.success());
+}
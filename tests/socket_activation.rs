@@ -0,0 +1,96 @@
+//! Runs the binary the way systemd would for socket activation: with listening sockets already
+//! open on file descriptors 3 onwards, described by LISTEN_PID, LISTEN_FDS and LISTEN_FDNAMES.
+
+mod common;
+#[path = "../src/pass_fds.rs"]
+mod pass_fds;
+
+use std::{
+    io::{Read, Write},
+    net::{Shutdown, TcpListener, TcpStream, UdpSocket},
+    os::fd::{AsRawFd, RawFd},
+    process::{Child, Command, ExitStatus, Stdio},
+};
+
+use common::TIMEOUT;
+use pass_fds::pass_fds;
+
+/// Binding this fails, so the servers only work if they use the sockets we pass them
+const UNUSABLE_BIND_ADDRESS: &str = "unix:/nonexistent/protohackers.sock";
+
+/// Start the binary serving `problem`, passing it `sockets` as file descriptors 3 onwards.
+fn spawn_with_sockets(problem: &str, sockets: &[RawFd], names: Option<&str>) -> Child {
+    let sockets = sockets.to_vec();
+    let mut command = Command::new("sh");
+    // LISTEN_PID has to be the pid of the binary itself, which exec keeps the same as the shell's
+    command
+        .args(["-c", r#"LISTEN_PID=$$ exec "$0" "$@""#])
+        .arg(env!("CARGO_BIN_EXE_protohackers"))
+        .arg(problem)
+        .env("BIND_ADDRESS", UNUSABLE_BIND_ADDRESS)
+        .env("LISTEN_FDS", sockets.len().to_string())
+        .env_remove("LISTEN_FDNAMES")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    if let Some(names) = names {
+        command.env("LISTEN_FDNAMES", names);
+    }
+    pass_fds(&mut command, sockets);
+    command.spawn().expect("Unable to start the server")
+}
+
+/// Interrupt `child`, as ctrl-c would, and wait for it to exit.
+fn interrupt(mut child: Child) -> ExitStatus {
+    common::send_signal(child.id(), libc::SIGINT);
+    common::wait_for("the server to shut down", || child.try_wait().unwrap())
+}
+
+#[test]
+fn serves_streams_on_the_inherited_socket_named_for_the_problem() {
+    let other = TcpListener::bind("127.0.0.1:0").unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let child = spawn_with_sockets(
+        "smoke_test",
+        &[other.as_raw_fd(), listener.as_raw_fd()],
+        Some("prime_time:smoke_test"),
+    );
+
+    // The socket is already listening, so this works even if the server hasn't started yet
+    let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream.write_all(b"hello").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut echoed = Vec::new();
+    stream.read_to_end(&mut echoed).unwrap();
+    assert_eq!(echoed, b"hello");
+
+    assert!(interrupt(child).success());
+}
+
+#[test]
+fn serves_datagrams_on_an_inherited_socket() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let child = spawn_with_sockets("unusual_database_program", &[socket.as_raw_fd()], None);
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(TIMEOUT)).unwrap();
+    client.connect(socket.local_addr().unwrap()).unwrap();
+    client.send(b"answer=42").unwrap();
+    client.send(b"answer").unwrap();
+    let mut buffer = [0u8; 1000];
+    let length = client.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..length], b"answer=42");
+
+    assert!(interrupt(child).success());
+}
+
+#[test]
+fn refuses_to_start_when_no_inherited_socket_is_for_the_problem() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let child = spawn_with_sockets("smoke_test", &[listener.as_raw_fd()], Some("prime_time"));
+    let output = child.wait_with_output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("None of the inherited sockets are for this problem"));
+}